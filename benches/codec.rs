@@ -0,0 +1,178 @@
+//! Encoding and decoding of common messages by the codec.
+//!
+//! Run with `cargo bench --bench codec`. Messages are framed into and
+//! read from buffers which are reused, as they are by a connection.
+//! The allocations made for each message are printed first, as they do
+//! not vary between runs like the timings do.
+//!
+//! Fewer allocations are not necessarily faster: removing one from
+//! encoding and one from decoding an identities answer with 8 keys left
+//! its timings within the noise between runs, about 1.2 µs to encode and
+//! 1.4 to 1.8 µs to decode both before and after.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ssh_agent_lib::{
+    codec::Codec,
+    proto::{Identity, ProtoError, Request, Response, SignRequest},
+};
+use ssh_encoding::{Decode, Encode};
+use ssh_key::{private::Ed25519Keypair, public::KeyData, Signature};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// System allocator counting allocations, including reallocations.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Number of allocations made by `f`.
+fn allocations<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    criterion::black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn key(seed: u8) -> KeyData {
+    KeyData::Ed25519(Ed25519Keypair::from_seed(&[seed; 32]).public)
+}
+
+fn requests() -> Vec<(&'static str, Request)> {
+    vec![
+        ("request identities", Request::RequestIdentities),
+        ("remove all identities", Request::RemoveAllIdentities),
+        (
+            "sign request (256 B)",
+            Request::SignRequest(SignRequest::new(key(1), vec![0; 256])),
+        ),
+        (
+            "sign request (64 KiB)",
+            Request::SignRequest(SignRequest::new(key(1), vec![0; 64 * 1024])),
+        ),
+    ]
+}
+
+fn responses() -> Vec<(&'static str, Response)> {
+    let identities = (0..8)
+        .map(|seed| Identity {
+            pubkey: key(seed),
+            comment: format!("key{seed}@example.com").into(),
+        })
+        .collect();
+    let signature = Signature::decode(&mut &hex_literal::hex!(
+        "0000000b 7373682d65643235353139"
+        "00000040 00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000"
+    )[..])
+    .expect("valid signature");
+    vec![
+        ("success", Response::Success),
+        ("failure", Response::Failure),
+        (
+            "identities answer (8 keys)",
+            Response::IdentitiesAnswer(identities),
+        ),
+        ("sign response", Response::SignResponse(signature)),
+    ]
+}
+
+/// Benchmark framing `messages` with `encoder`, and reading them back in
+/// with `decoder`, the codec of the other side.
+fn bench<M, Other>(
+    c: &mut Criterion,
+    group: &str,
+    mut encoder: Codec<Other, M>,
+    mut decoder: Codec<M, Other>,
+    messages: Vec<(&'static str, M)>,
+) where
+    M: Decode<Error = ProtoError> + Encode + Clone,
+    Other: Decode<Error = ProtoError> + Encode,
+{
+    let mut bench_group = c.benchmark_group(group);
+    let mut buf = BytesMut::new();
+    for (name, message) in messages {
+        // the buffer is large enough after the first message
+        buf.clear();
+        encoder
+            .encode(message.clone(), &mut buf)
+            .expect("encodable");
+        let frame = Bytes::copy_from_slice(&buf);
+        let copy = message.clone();
+        let encoding = allocations(|| {
+            buf.clear();
+            encoder.encode(copy, &mut buf)
+        });
+        let decoding = allocations(|| {
+            buf.clear();
+            buf.extend_from_slice(&frame);
+            decoder.decode(&mut buf)
+        });
+        println!("{group}/{name}: {encoding} allocations to encode, {decoding} to decode");
+
+        bench_group.bench_with_input(BenchmarkId::new("encode", name), &message, |b, message| {
+            b.iter_batched(
+                || message.clone(),
+                |message| {
+                    buf.clear();
+                    encoder.encode(message, &mut buf).expect("encodable")
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        bench_group.bench_with_input(BenchmarkId::new("decode", name), &frame, |b, frame| {
+            b.iter(|| {
+                buf.clear();
+                buf.extend_from_slice(frame);
+                decoder
+                    .decode(&mut buf)
+                    .expect("decodable")
+                    .expect("complete")
+            })
+        });
+    }
+    bench_group.finish();
+}
+
+fn requests_bench(c: &mut Criterion) {
+    bench(
+        c,
+        "request",
+        Codec::<Response, Request>::default(),
+        Codec::<Request, Response>::default(),
+        requests(),
+    );
+}
+
+fn responses_bench(c: &mut Criterion) {
+    bench(
+        c,
+        "response",
+        Codec::<Request, Response>::default(),
+        Codec::<Response, Request>::default(),
+        responses(),
+    );
+}
+
+criterion_group!(benches, requests_bench, responses_bench);
+criterion_main!(benches);
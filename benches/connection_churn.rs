@@ -0,0 +1,77 @@
+//! Throughput of an agent whose clients open a new connection for every
+//! request, as `ssh` and `ssh-add` do.
+//!
+//! Run with `cargo bench --bench connection_churn`.
+
+use std::time::{Duration, Instant};
+
+use ssh_agent_lib::{
+    agent::{Server, Session},
+    client::Client,
+    error::AgentError,
+    keyring::KeyRing,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Clients connecting at the same time.
+const CLIENTS: usize = 32;
+
+/// Connections opened by every client.
+const CONNECTIONS: usize = 200;
+
+/// Open `CONNECTIONS` connections from each of `CLIENTS` clients to a
+/// server configured as `server`, asking for identities on each.
+async fn churn(server: Server) -> Result<Duration, AgentError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let agent = tokio::spawn(async move { server.listen(KeyRing::new(), listener).await });
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            tokio::spawn(async move {
+                for _ in 0..CONNECTIONS {
+                    let mut client = Client::new(TcpStream::connect(address).await?);
+                    client.request_identities().await?;
+                }
+                Ok::<_, AgentError>(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.map_err(AgentError::other)??;
+    }
+    let elapsed = start.elapsed();
+
+    agent.abort();
+    Ok(elapsed)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), AgentError> {
+    let servers = [
+        ("default", Server::new()),
+        ("reusing buffers", Server::new().reuse_buffers(true)),
+        (
+            "at most 8 connections",
+            Server::new().max_concurrent_connections(8),
+        ),
+        (
+            "at most 8 connections, reusing buffers",
+            Server::new()
+                .max_concurrent_connections(8)
+                .reuse_buffers(true),
+        ),
+    ];
+
+    for (name, server) in servers {
+        let elapsed = churn(server).await?;
+        let connections = (CLIENTS * CONNECTIONS) as f64;
+        println!(
+            "{name:>40}: {:>8.0} connections/s",
+            connections / elapsed.as_secs_f64()
+        );
+    }
+
+    Ok(())
+}
@@ -14,7 +14,7 @@ use ssh_agent_lib::error::AgentError;
 use ssh_agent_lib::proto::extension::{QueryResponse, RestrictDestination, SessionBind};
 use ssh_agent_lib::proto::{
     message, signature, AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained,
-    Credential, Extension, KeyConstraint, RemoveIdentity, SignRequest, SmartcardKey,
+    Credential, Extension, KeyConstraint, RemoveIdentity, SignRequest, SmartcardKey, Zeroizing,
 };
 use ssh_agent_lib::Agent;
 use ssh_key::{
@@ -120,7 +120,7 @@ impl Session for KeyStorage {
         for identity in self.identities.lock().unwrap().iter() {
             identities.push(message::Identity {
                 pubkey: identity.pubkey.key_data().clone(),
-                comment: identity.comment.clone(),
+                comment: identity.comment.clone().into(),
             })
         }
         Ok(identities)
@@ -190,13 +190,13 @@ impl Session for KeyStorage {
         info!("Adding smartcard key with constraints: {key:?}");
         Ok(())
     }
-    async fn lock(&mut self, pwd: String) -> Result<(), AgentError> {
-        info!("Locked with password: {pwd:?}");
+    async fn lock(&mut self, _pwd: Zeroizing<String>) -> Result<(), AgentError> {
+        info!("Locked");
         Ok(())
     }
 
-    async fn unlock(&mut self, pwd: String) -> Result<(), AgentError> {
-        info!("Unlocked with password: {pwd:?}");
+    async fn unlock(&mut self, _pwd: Zeroizing<String>) -> Result<(), AgentError> {
+        info!("Unlocked");
         Ok(())
     }
 
@@ -81,7 +81,7 @@ impl CardSession {
                             let pin = self.pwds.get(&ident).await;
                             return if let Some(pin) = pin {
                                 tx.verify_pw1_user(pin.expose_secret().as_bytes())?;
-                                let signature = tx.internal_authenticate(request.data.clone())?;
+                                let signature = tx.internal_authenticate(request.data.to_vec())?;
 
                                 Ok(Signature::new(Algorithm::Ed25519, signature)?)
                             } else {
@@ -117,7 +117,9 @@ impl CardSession {
                     })
                     .any(|x| x);
                 if card_pin_matches {
-                    self.pwds.insert(key.id, key.pin.into(), expiration).await;
+                    self.pwds
+                        .insert(key.id, key.pin.to_string().into(), expiration)
+                        .await;
                     Ok(())
                 } else {
                     Err(AgentError::IO(std::io::Error::other(
@@ -146,7 +148,7 @@ impl Session for CardSession {
                                     pubkey: KeyData::Ed25519(Ed25519PublicKey(
                                         e.data().try_into()?,
                                     )),
-                                    comment: ident,
+                                    comment: ident.into(),
                                 }));
                             }
                         }
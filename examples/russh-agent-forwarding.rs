@@ -0,0 +1,130 @@
+//! SSH client with agent forwarding
+//!
+//! Connects to an SSH server using [russh](https://crates.io/crates/russh),
+//! requests agent forwarding and executes a command. Each agent channel
+//! opened by the server is served by a client of the local agent pointed to
+//! by `SSH_AUTH_SOCK`, with all requests logged, so the command can use the
+//! forwarded agent:
+//!
+//! ```sh
+//! RUST_LOG=debug cargo run --example russh-agent-forwarding -- \
+//!     --host example.com --user me --password secret 'ssh-add -L'
+//! ```
+//!
+//! The server's host key must be present in `~/.ssh/known_hosts`.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use clap::Parser;
+use russh::{
+    client::{self, Msg},
+    keys::PublicKey,
+    Channel, ChannelMsg,
+};
+use ssh_agent_lib::{
+    agent::{serve, LoggingSession},
+    client::Client,
+};
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// SSH server host name.
+    #[clap(long)]
+    host: String,
+
+    /// SSH server port.
+    #[clap(long, default_value_t = 22)]
+    port: u16,
+
+    /// User name.
+    #[clap(long)]
+    user: String,
+
+    /// Password of the user.
+    #[clap(long)]
+    password: String,
+
+    /// Command to execute on the server.
+    command: String,
+}
+
+struct Forwarder {
+    host: String,
+    port: u16,
+    agent: String,
+}
+
+impl client::Handler for Forwarder {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool, Self::Error> {
+        russh::keys::check_known_hosts(&self.host, self.port, key).map_err(Into::into)
+    }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        let agent = self.agent.clone();
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let stream = tokio::net::UnixStream::connect(agent).await;
+            #[cfg(windows)]
+            let stream = ssh_agent_lib::client::open_named_pipe(agent).await;
+
+            let result = match stream {
+                Ok(stream) => {
+                    // the forwarded channel carries requests for the local agent
+                    let session = LoggingSession::new(Client::new(stream));
+                    serve(session, channel.into_stream()).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                log::error!("Forwarding agent failed: {e}");
+            }
+        });
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let forwarder = Forwarder {
+        host: args.host.clone(),
+        port: args.port,
+        agent: std::env::var("SSH_AUTH_SOCK")?,
+    };
+    let config = Arc::new(client::Config::default());
+    let mut ssh = client::connect(config, (args.host.as_str(), args.port), forwarder).await?;
+
+    if !ssh
+        .authenticate_password(args.user, args.password)
+        .await?
+        .success()
+    {
+        return Err("Authentication failed".into());
+    }
+
+    let mut channel = ssh.channel_open_session().await?;
+    channel.agent_forward(true).await?;
+    channel.exec(true, args.command).await?;
+
+    let mut status = 0;
+    let mut stdout = std::io::stdout();
+    while let Some(message) = channel.wait().await {
+        match message {
+            ChannelMsg::Data { data } => stdout.write_all(&data)?,
+            ChannelMsg::ExitStatus { exit_status } => status = exit_status,
+            _ => {}
+        }
+    }
+    stdout.flush()?;
+
+    std::process::exit(status as i32);
+}
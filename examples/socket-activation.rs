@@ -0,0 +1,62 @@
+//! Agent started by systemd socket activation
+//!
+//! systemd listens on the socket and starts the agent when the first
+//! client connects, passing the listening socket as descriptor 3. The
+//! agent holds keys in memory only, as `ssh-agent` does. Build the
+//! example with `cargo build --release --example socket-activation` and
+//! install the following user units, adjusting the path of the binary:
+//!
+//! ```ini
+//! # ~/.config/systemd/user/ssh-agent-lib.socket
+//! [Socket]
+//! ListenStream=%t/ssh-agent-lib.sock
+//! SocketMode=0600
+//!
+//! [Install]
+//! WantedBy=sockets.target
+//! ```
+//!
+//! ```ini
+//! # ~/.config/systemd/user/ssh-agent-lib.service
+//! [Service]
+//! ExecStart=%h/ssh-agent-lib/target/release/examples/socket-activation
+//! ```
+//!
+//! Then enable the socket with `systemctl --user enable --now ssh-agent-lib.socket`
+//! and use the agent with `SSH_AUTH_SOCK=$XDG_RUNTIME_DIR/ssh-agent-lib.sock ssh-add -L`.
+//! To try it out without installing units, run
+//! `systemd-socket-activate -l agent.sock target/release/examples/socket-activation`.
+
+#[cfg(target_os = "linux")]
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    use ssh_agent_lib::agent::Agent;
+    use ssh_agent_lib::keyring::KeyRing;
+
+    /// Descriptor of the first socket passed by systemd.
+    const SD_LISTEN_FDS_START: i32 = 3;
+
+    env_logger::init();
+
+    // the variables are inherited by children, which must not take the socket
+    let pid: u32 = std::env::var("LISTEN_PID")?.parse()?;
+    let fds: u32 = std::env::var("LISTEN_FDS")?.parse()?;
+    if pid != std::process::id() || fds != 1 {
+        return Err("expected a single socket passed by systemd".into());
+    }
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+
+    // SAFETY: systemd passed the descriptor to this process, and nothing
+    // else owns it
+    let fd = unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START) };
+    KeyRing::new().listen_fd(fd).await?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("systemd socket activation is only available on Linux");
+}
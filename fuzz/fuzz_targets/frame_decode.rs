@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ssh_agent_lib::proto::{decode_frame, Frame, Request, Response};
+
+fuzz_target!(|data: &[u8]| {
+    let max_length = 1024;
+    if let Ok(Frame::Complete { length, .. }) = decode_frame::<Request>(data, max_length) {
+        assert!(length <= data.len());
+    }
+    if let Ok(Frame::Incomplete { needed }) = decode_frame::<Response>(data, max_length) {
+        assert!(needed > 0);
+    }
+});
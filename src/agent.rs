@@ -1,11 +1,16 @@
 //! Traits for implementing custom SSH agents
 
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use futures::{SinkExt, TryStreamExt};
-use ssh_key::Signature;
+use ssh_key::{
+    public::KeyData, Algorithm, Certificate, EcdsaCurve, HashAlg, PrivateKey, PublicKey, Signature,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 #[cfg(windows)]
 use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
@@ -15,17 +20,62 @@ use tokio::net::{UnixListener, UnixStream};
 use tokio_util::codec::Framed;
 
 use super::error::AgentError;
-use super::proto::message::{Request, Response};
+use super::proto::message::{Request, Response, SSH_AGENT_EXTENSION_FAILURE};
 use crate::codec::Codec;
+use crate::proto::extension::{
+    ExtensionRequest, MessageExtension, Query, QueryResponse, SessionBind,
+};
 use crate::proto::AddIdentity;
 use crate::proto::AddIdentityConstrained;
 use crate::proto::AddSmartcardKeyConstrained;
+use crate::proto::Credential;
 use crate::proto::Extension;
 use crate::proto::Identity;
 use crate::proto::ProtoError;
 use crate::proto::RemoveIdentity;
 use crate::proto::SignRequest;
 use crate::proto::SmartcardKey;
+use crate::proto::Zeroizing;
+use crate::proto::{RSA_SHA2_256, RSA_SHA2_512};
+
+mod logging;
+mod proxy;
+mod server;
+
+pub use self::logging::LoggingSession;
+pub use self::proxy::FilteringProxy;
+pub use self::server::Server;
+
+/// Credentials of the process on the other end of a connection.
+///
+/// Fields are `None` when the platform or the kind of socket does not
+/// provide them, e.g. for TCP connections.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeerCredentials {
+    /// User ID of the peer process.
+    pub uid: Option<u32>,
+
+    /// Group ID of the peer process.
+    pub gid: Option<u32>,
+
+    /// Process ID of the peer process.
+    pub pid: Option<u32>,
+}
+
+/// Signature flags an agent honored for one of its keys.
+///
+/// Returned by [`Session::probe_sign_capabilities`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct SignCapabilities {
+    /// The probed key.
+    pub identity: Identity,
+
+    /// Each probed combination of [signature flags](crate::proto::signature),
+    /// and whether the agent signed with the algorithm it requests.
+    pub flags: Vec<(u32, bool)>,
+}
 
 /// Type representing a socket that asynchronously returns a list of streams.
 #[async_trait]
@@ -35,6 +85,13 @@ pub trait ListeningSocket {
 
     /// Waits until a client connects and returns connected stream.
     async fn accept(&mut self) -> io::Result<Self::Stream>;
+
+    /// Credentials of the client connected to an accepted stream.
+    ///
+    /// The default implementation does not know any credentials.
+    fn peer_credentials(_stream: &Self::Stream) -> PeerCredentials {
+        PeerCredentials::default()
+    }
 }
 
 #[cfg(unix)]
@@ -44,6 +101,22 @@ impl ListeningSocket for UnixListener {
     async fn accept(&mut self) -> io::Result<Self::Stream> {
         UnixListener::accept(self).await.map(|(s, _addr)| s)
     }
+
+    /// Uses `SO_PEERCRED` on Linux and `getpeereid` or `LOCAL_PEERCRED`
+    /// on macOS and the BSDs, where the process ID may be missing.
+    fn peer_credentials(stream: &Self::Stream) -> PeerCredentials {
+        match stream.peer_cred() {
+            Ok(cred) => PeerCredentials {
+                uid: Some(cred.uid()),
+                gid: Some(cred.gid()),
+                pid: cred.pid().and_then(|pid| u32::try_from(pid).ok()),
+            },
+            Err(e) => {
+                log::warn!("Failed to get peer credentials: {e}");
+                PeerCredentials::default()
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -84,6 +157,29 @@ impl ListeningSocket for NamedPipeListener {
             ServerOptions::new().create(&self.1)?,
         ))
     }
+
+    /// Only the process ID of the client is known, Windows does not have
+    /// numeric user IDs.
+    #[allow(unsafe_code)]
+    fn peer_credentials(stream: &Self::Stream) -> PeerCredentials {
+        use std::os::windows::io::AsRawHandle;
+
+        use windows_sys::Win32::System::Pipes::GetNamedPipeClientProcessId;
+
+        let mut pid = 0;
+        // SAFETY: the handle stays open while `stream` is borrowed
+        if unsafe { GetNamedPipeClientProcessId(stream.as_raw_handle() as _, &mut pid) } == 0 {
+            log::warn!(
+                "Failed to get pipe client process: {}",
+                io::Error::last_os_error()
+            );
+            return PeerCredentials::default();
+        }
+        PeerCredentials {
+            pid: Some(pid),
+            ..Default::default()
+        }
+    }
 }
 
 /// Represents one active SSH connection.
@@ -99,6 +195,44 @@ pub trait Session: 'static + Sync + Send + Unpin {
         }))
     }
 
+    /// Request the keys managed by this session, with their comments.
+    ///
+    /// Comments which are not valid UTF-8 are converted lossily, see
+    /// [`Identity::comment_lossy`].
+    ///
+    /// Keys of algorithms which `ssh-key` does not implement do not fail
+    /// the request. They are returned as [`KeyData::Other`] with their
+    /// encoding preserved, and can be skipped by checking
+    /// [`KeyData::is_other`].
+    async fn request_public_keys(&mut self) -> Result<Vec<PublicKey>, AgentError> {
+        Ok(self
+            .request_identities()
+            .await?
+            .into_iter()
+            .map(|identity| {
+                let comment = identity.comment_lossy().into_owned();
+                PublicKey::new(identity.pubkey, comment)
+            })
+            .collect())
+    }
+
+    /// Request the identities whose keys use `algorithm`.
+    ///
+    /// RSA keys match any [`Algorithm::Rsa`], whatever its hash. Keys
+    /// of algorithms which `ssh-key` does not implement match
+    /// [`Algorithm::Other`] with the same name.
+    async fn request_identities_by_algorithm(
+        &mut self,
+        algorithm: Algorithm,
+    ) -> Result<Vec<Identity>, AgentError> {
+        let mut identities = self.request_identities().await?;
+        identities.retain(|identity| match (&algorithm, identity.pubkey.algorithm()) {
+            (Algorithm::Rsa { .. }, Algorithm::Rsa { .. }) => true,
+            (algorithm, key_algorithm) => *algorithm == key_algorithm,
+        });
+        Ok(identities)
+    }
+
     /// Perform a private key signature operation.
     async fn sign(&mut self, _request: SignRequest) -> Result<Signature, AgentError> {
         Err(AgentError::from(ProtoError::UnsupportedCommand {
@@ -106,6 +240,199 @@ pub trait Session: 'static + Sync + Send + Unpin {
         }))
     }
 
+    /// Sign `data` with the given public key, picking default signature flags.
+    ///
+    /// See [`SignRequest::new`] for the flags that are used. If the agent
+    /// rejects them, an error is returned and the caller can retry using
+    /// [`Session::sign`] with explicitly set flags.
+    async fn sign_ssh_data(
+        &mut self,
+        key: &PublicKey,
+        data: &[u8],
+    ) -> Result<Signature, AgentError> {
+        self.sign(SignRequest::new(key.key_data().clone(), data.to_vec()))
+            .await
+    }
+
+    /// Sign `data` with the key a `certificate` was issued for.
+    ///
+    /// The request names the certificate, as `ssh` does when
+    /// authenticating with it, so only the certificate needs to be at
+    /// hand. Signature flags are picked as by [`Session::sign_ssh_data`].
+    async fn sign_with_certificate(
+        &mut self,
+        certificate: &Certificate,
+        data: &[u8],
+    ) -> Result<Signature, AgentError> {
+        self.sign(SignRequest {
+            certificate: Some(certificate.clone()),
+            ..SignRequest::new(certificate.public_key().clone(), data.to_vec())
+        })
+        .await
+    }
+
+    /// Sign `data` with the key in `key_blob` using explicit signature flags.
+    ///
+    /// The `key_blob` holds the standard public key encoding, as sent in
+    /// [`Identity::pubkey`], or the encoding of a certificate issued for
+    /// the key. The `flags` are a combination of the
+    /// [signature flags](crate::proto::signature), e.g.
+    /// [`RSA_SHA2_256`].
+    async fn sign_with_flags(
+        &mut self,
+        key_blob: &[u8],
+        data: &[u8],
+        flags: u32,
+    ) -> Result<Signature, AgentError> {
+        let (pubkey, certificate) = crate::proto::message::key_from_blob(key_blob)?;
+        self.sign(SignRequest {
+            pubkey,
+            certificate,
+            data: data.to_vec().into(),
+            flags,
+        })
+        .await
+    }
+
+    /// Sign `data` with the given public key, hashing it with `hash`.
+    ///
+    /// Agents always hash the data they sign themselves, so the agent
+    /// protocol cannot sign a digest computed beforehand. What a client
+    /// can choose is the hash the agent uses, and only for some keys:
+    ///
+    /// - RSA keys sign with SHA-256 or SHA-512, selected by the
+    ///   [signature flags](crate::proto::signature),
+    /// - ECDSA keys always use the hash of their curve, SHA-256 for
+    ///   NIST P-256 and SHA-512 for NIST P-521,
+    /// - other keys, e.g. Ed25519 keys, do not hash the data separately.
+    ///
+    /// Any other combination fails with [`AgentError::HashUnsupported`]
+    /// without sending a request. This includes every hash for NIST P-384
+    /// keys, which sign with SHA-384, as [`HashAlg`] has no such variant:
+    /// use [`Session::sign_ssh_data`] for them instead.
+    async fn sign_with_hash(
+        &mut self,
+        key: &PublicKey,
+        data: &[u8],
+        hash: HashAlg,
+    ) -> Result<Signature, AgentError> {
+        let algorithm = key.algorithm();
+        let flags = match (&algorithm, hash) {
+            (Algorithm::Rsa { .. }, HashAlg::Sha256) => RSA_SHA2_256,
+            (Algorithm::Rsa { .. }, HashAlg::Sha512) => RSA_SHA2_512,
+            (
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP256,
+                },
+                HashAlg::Sha256,
+            )
+            | (
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP521,
+                },
+                HashAlg::Sha512,
+            ) => 0,
+            _ => return Err(AgentError::HashUnsupported { algorithm, hash }),
+        };
+        self.sign(SignRequest {
+            pubkey: key.key_data().clone(),
+            certificate: None,
+            data: data.to_vec().into(),
+            flags,
+        })
+        .await
+    }
+
+    /// Sign each of `datas` with the given public key.
+    ///
+    /// Signatures are returned in the same order as the input data.
+    /// The default implementation signs the data one by one using
+    /// [`Session::sign_ssh_data`], while the [client](crate::client::Client)
+    /// sends several requests at a time before waiting for the responses.
+    async fn sign_batch(
+        &mut self,
+        key: &PublicKey,
+        datas: &[Vec<u8>],
+    ) -> Result<Vec<Signature>, AgentError> {
+        let mut signatures = Vec::with_capacity(datas.len());
+        for data in datas {
+            signatures.push(self.sign_ssh_data(key, data).await?);
+        }
+        Ok(signatures)
+    }
+
+    /// Check that the agent holds the private part of `key`, as `ssh-add -T`
+    /// does.
+    ///
+    /// A random challenge is signed with the key and the signature is
+    /// verified locally, so a forwarded or otherwise untrusted agent cannot
+    /// claim a key it does not control. Returns `false` if the signature
+    /// does not verify, including when `ssh-key` cannot verify signatures
+    /// of the key's algorithm, and an error if the agent refuses to sign.
+    async fn verify_key_control(&mut self, key: &PublicKey) -> Result<bool, AgentError> {
+        use signature::Verifier;
+        use ssh_key::rand_core::{OsRng, RngCore};
+
+        let mut challenge = [0; 32];
+        OsRng.fill_bytes(&mut challenge);
+        let signature = self.sign_ssh_data(key, &challenge).await?;
+        Ok(key.key_data().verify(&challenge, &signature).is_ok())
+    }
+
+    /// Find out which signature flags the agent honors for each of its keys.
+    ///
+    /// This is a diagnostic for agents which silently ignore flags, e.g.
+    /// answer an `rsa-sha2-256` request with an `rsa-sha2-512` signature.
+    /// **Every key is asked for a signature with every probed flag**: RSA
+    /// keys with [`RSA_SHA2_256`] and [`RSA_SHA2_512`], other keys without
+    /// flags. That is two sign requests for each RSA key and one for every
+    /// other key, each of which may prompt the user to confirm the use of
+    /// the key and counts against keys limited to a number of signatures.
+    /// Legacy `ssh-rsa` signatures are not probed, as they cannot be
+    /// represented by a [`Signature`].
+    ///
+    /// A flag counts as honored if the agent signs a fixed payload with
+    /// the algorithm selected by the flag. Requests the agent refuses,
+    /// also because it is locked, are recorded as not honored, while any
+    /// other error, e.g. of the connection, is returned.
+    async fn probe_sign_capabilities(&mut self) -> Result<Vec<SignCapabilities>, AgentError> {
+        const PAYLOAD: &[u8] = b"ssh-agent-lib signature flag probe";
+
+        let mut capabilities = vec![];
+        for identity in self.request_identities().await? {
+            let probed: &[u32] = match identity.pubkey {
+                KeyData::Rsa(_) => &[RSA_SHA2_256, RSA_SHA2_512],
+                _ => &[0],
+            };
+            let mut flags = Vec::with_capacity(probed.len());
+            for &flag in probed {
+                let request = SignRequest {
+                    pubkey: identity.pubkey.clone(),
+                    certificate: None,
+                    data: PAYLOAD.into(),
+                    flags: flag,
+                };
+                let honored = match self.sign(request).await {
+                    Ok(signature) => {
+                        crate::client::check_algorithm(&identity.pubkey, flag, signature).is_ok()
+                    }
+                    Err(
+                        AgentError::Failure
+                        | AgentError::AgentFailure { .. }
+                        | AgentError::AlgorithmMismatch { .. }
+                        | AgentError::HashUnsupported { .. }
+                        | AgentError::Locked
+                        | AgentError::Proto(ProtoError::UnsupportedSignatureFlags { .. }),
+                    ) => false,
+                    Err(e) => return Err(e),
+                };
+                flags.push((flag, honored));
+            }
+            capabilities.push(SignCapabilities { identity, flags });
+        }
+        Ok(capabilities)
+    }
+
     /// Add a private key to the agent.
     async fn add_identity(&mut self, _identity: AddIdentity) -> Result<(), AgentError> {
         Err(AgentError::from(ProtoError::UnsupportedCommand {
@@ -113,6 +440,65 @@ pub trait Session: 'static + Sync + Send + Unpin {
         }))
     }
 
+    /// Add `key` to the agent, labelled with `comment`.
+    ///
+    /// Without a `comment` the key's own comment is used. An empty comment
+    /// is sent as an empty string.
+    async fn add_identity_with_comment(
+        &mut self,
+        key: &PrivateKey,
+        comment: Option<&str>,
+    ) -> Result<(), AgentError> {
+        self.add_identity(AddIdentity {
+            credential: Credential::Key {
+                privkey: key.key_data().clone(),
+                comment: comment.unwrap_or(key.comment()).to_owned(),
+            },
+        })
+        .await
+    }
+
+    /// Add `key` together with a `certificate` issued for it.
+    ///
+    /// The agent then offers the certificate instead of the bare public
+    /// key. Without a `comment` the key's own comment is used. See
+    /// [`Credential::certificate`] for how the pair is sent.
+    async fn add_identity_with_certificate(
+        &mut self,
+        key: &PrivateKey,
+        certificate: &Certificate,
+        comment: Option<&str>,
+    ) -> Result<(), AgentError> {
+        let credential = Credential::certificate(
+            key.key_data(),
+            certificate.clone(),
+            comment.unwrap_or(key.comment()),
+        )?;
+        self.add_identity(AddIdentity { credential }).await
+    }
+
+    /// Add a key read from an OpenSSH private key file to the agent.
+    ///
+    /// The `pem` contents of a file such as `~/.ssh/id_ed25519` are
+    /// parsed and, if the key is encrypted, decrypted with `passphrase`.
+    /// Encrypted keys without a passphrase fail with
+    /// [`AgentError::KeyEncrypted`]. Without a `comment` the comment stored
+    /// in the file is used.
+    async fn add_identity_from_openssh(
+        &mut self,
+        pem: &str,
+        comment: Option<String>,
+        passphrase: Option<&str>,
+    ) -> Result<(), AgentError> {
+        let mut key = PrivateKey::from_openssh(pem).map_err(ProtoError::from)?;
+        if key.is_encrypted() {
+            let passphrase = passphrase.ok_or(AgentError::KeyEncrypted)?;
+            key = key.decrypt(passphrase).map_err(ProtoError::from)?;
+        }
+        self.add_identity_with_comment(&key, comment.as_deref())
+            .await
+    }
+
     /// Add a private key to the agent with a set of constraints.
     async fn add_identity_constrained(
         &mut self,
@@ -130,6 +516,17 @@ pub trait Session: 'static + Sync + Send + Unpin {
         }))
     }
 
+    /// Remove the key `key` from the agent, as `ssh-add -d` does.
+    ///
+    /// Only the key data is sent, so keys returned by
+    /// [`Session::request_public_keys`] match regardless of their comments.
+    async fn remove_identity_for(&mut self, key: &PublicKey) -> Result<(), AgentError> {
+        self.remove_identity(RemoveIdentity {
+            pubkey: key.key_data().clone(),
+        })
+        .await
+    }
+
     /// Remove all keys from an agent.
     async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
         Err(AgentError::from(ProtoError::UnsupportedCommand {
@@ -137,6 +534,61 @@ pub trait Session: 'static + Sync + Send + Unpin {
         }))
     }
 
+    /// Make `keys` the only identities of the agent.
+    ///
+    /// The agent protocol has no transactions, so this is done in steps
+    /// which other clients of the agent may see, and which cannot all be
+    /// undone:
+    ///
+    /// 1. The identities held so far are listed.
+    /// 2. Each of `keys` is added. Adding a key the agent already holds
+    ///    replaces its comment and constraints.
+    /// 3. The identities held before that are not among `keys` are removed.
+    ///
+    /// If adding a key fails, the keys this call added and which were not
+    /// held before are removed again, and the error is returned. The agent
+    /// then holds its previous identities, though keys of `keys` which it
+    /// already held keep their new comments and constraints. Failures to
+    /// remove the added keys are only logged. If removing a previous
+    /// identity fails, this is not undone: the agent holds all of `keys`
+    /// and some of its previous identities, and the error is returned.
+    async fn replace_identities(&mut self, keys: Vec<AddIdentity>) -> Result<(), AgentError> {
+        let previous: Vec<_> = self
+            .request_identities()
+            .await?
+            .into_iter()
+            .map(|identity| identity.pubkey)
+            .collect();
+        let pubkeys = keys
+            .iter()
+            .map(|key| key.credential.public_key())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (added, key) in keys.into_iter().enumerate() {
+            if let Err(e) = self.add_identity(key).await {
+                for pubkey in &pubkeys[..added] {
+                    if previous.contains(pubkey) {
+                        continue;
+                    }
+                    let identity = RemoveIdentity {
+                        pubkey: pubkey.clone(),
+                    };
+                    if let Err(e) = self.remove_identity(identity).await {
+                        log::warn!("Removing a key while replacing identities failed: {e}");
+                    }
+                }
+                return Err(e);
+            }
+        }
+
+        for pubkey in previous {
+            if !pubkeys.contains(&pubkey) {
+                self.remove_identity(RemoveIdentity { pubkey }).await?;
+            }
+        }
+        Ok(())
+    }
+
     /// Add a key stored on a smartcard.
     async fn add_smartcard_key(&mut self, _key: SmartcardKey) -> Result<(), AgentError> {
         Err(AgentError::from(ProtoError::UnsupportedCommand {
@@ -162,14 +614,14 @@ pub trait Session: 'static + Sync + Send + Unpin {
     }
 
     /// Temporarily lock the agent with a password.
-    async fn lock(&mut self, _key: String) -> Result<(), AgentError> {
+    async fn lock(&mut self, _key: Zeroizing<String>) -> Result<(), AgentError> {
         Err(AgentError::from(ProtoError::UnsupportedCommand {
             command: 22,
         }))
     }
 
     /// Unlock the agent with a password.
-    async fn unlock(&mut self, _key: String) -> Result<(), AgentError> {
+    async fn unlock(&mut self, _key: Zeroizing<String>) -> Result<(), AgentError> {
         Err(AgentError::from(ProtoError::UnsupportedCommand {
             command: 23,
         }))
@@ -182,6 +634,95 @@ pub trait Session: 'static + Sync + Send + Unpin {
         }))
     }
 
+    /// Send a typed extension request and parse the response.
+    ///
+    /// Returns `None` if the agent answered with `SSH_AGENT_SUCCESS`
+    /// instead of an extension response. Failure responses are returned
+    /// as [`AgentError::AgentFailure`].
+    async fn extension_typed<E>(&mut self, request: E) -> Result<Option<E::Response>, AgentError>
+    where
+        E: ExtensionRequest + Send,
+        Self: Sized,
+    {
+        typed_extension(self, request).await
+    }
+
+    /// Send an extension request given by its name and contents.
+    ///
+    /// Returns the contents of the extension response, or `None` if the
+    /// agent answered with `SSH_AGENT_SUCCESS`. This is handy for vendor
+    /// extensions which have no [`ExtensionRequest`] type.
+    async fn extension_raw(
+        &mut self,
+        name: &str,
+        contents: &[u8],
+    ) -> Result<Option<Vec<u8>>, AgentError> {
+        let extension = Extension {
+            name: name.into(),
+            details: contents.to_vec().into(),
+        };
+        Ok(self
+            .extension(extension)
+            .await?
+            .map(|response| response.details.0))
+    }
+
+    /// Query the agent for the list of supported extensions.
+    ///
+    /// This sends the [`Query`] extension request and parses
+    /// the returned [`QueryResponse`], also in the form sent by Pageant.
+    /// Agents that do not support the `query` extension at all and reply
+    /// with a plain failure are reported as supporting no extensions.
+    async fn query_extensions(&mut self) -> Result<Vec<String>, AgentError> {
+        let request = Request::Extension(Extension::new_message(Query)?);
+        match self.handle(request).await {
+            Ok(response) => Ok(QueryResponse::from_answer(&response)?
+                .ok_or_else(|| crate::client::failure(response, "ExtensionResponse"))?
+                .extensions),
+            Err(AgentError::AgentFailure { .. }) => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Count the identities held by the agent.
+    ///
+    /// The protocol has no request for this, so the default lists the
+    /// identities and counts them. Sessions which know the number without
+    /// listing the keys, e.g. through an extension of their agent, can
+    /// override it.
+    ///
+    /// Like an empty listing, a count of zero does not tell an agent
+    /// without keys apart from a locked one, which pretends to hold none.
+    /// See [`Client::is_locked`](crate::client::Client::is_locked).
+    async fn count_identities(&mut self) -> Result<usize, AgentError> {
+        Ok(self.request_identities().await?.len())
+    }
+
+    /// Check that the agent is still answering requests.
+    ///
+    /// This sends the read-only [`Query`] extension request, which unlike
+    /// listing the identities does not make the agent send all of its
+    /// keys. Any answer counts, including a failure from agents which do
+    /// not support the extension, so only broken connections, timeouts
+    /// and unparseable answers are errors.
+    async fn ping(&mut self) -> Result<(), AgentError> {
+        let request = Request::Extension(Extension::new_message(Query)?);
+        match self.handle(request).await {
+            Ok(_) | Err(AgentError::AgentFailure { .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Bind this agent connection to an SSH session.
+    ///
+    /// This sends the [`SessionBind`] (`session-bind@openssh.com`)
+    /// extension request, which OpenSSH clients use before forwarding
+    /// the agent connection or using it for user authentication.
+    async fn session_bind(&mut self, bind: SessionBind) -> Result<(), AgentError> {
+        typed_extension(self, bind).await?;
+        Ok(())
+    }
+
     /// Handle a raw SSH agent request and return agent response.
     ///
     /// Note that it is preferable to use high-level functions instead of
@@ -217,26 +758,99 @@ pub trait Session: 'static + Sync + Send + Unpin {
     }
 }
 
-async fn handle_socket<S>(
+/// Send a typed extension request through [`Session::handle`].
+///
+/// Unlike [`Session::extension_typed`] this also works for unsized
+/// sessions, so the default methods of [`Session`] can use it.
+async fn typed_extension<S, E>(
+    session: &mut S,
+    request: E,
+) -> Result<Option<E::Response>, AgentError>
+where
+    S: Session + ?Sized,
+    E: ExtensionRequest,
+{
+    let request = Request::Extension(Extension::new_message(request)?);
+    match session.handle(request).await? {
+        Response::Success => Ok(None),
+        Response::ExtensionResponse(response) if response.name == E::NAME => {
+            Ok(Some(response.details.parse()?))
+        }
+        response => Err(crate::client::failure(response, "ExtensionResponse")),
+    }
+}
+
+/// Serve a single agent connection over an already established stream.
+///
+/// Use this when the connection does not come from a [`ListeningSocket`],
+/// e.g. on the client side of an SSH connection, where the server opens an
+/// `auth-agent@openssh.com` channel for each use of the forwarded agent.
+/// Returns when the other side closes the stream.
+///
+/// See the `russh-agent-forwarding` example for an SSH client which serves
+/// forwarded agent channels.
+pub async fn serve<Stream>(session: impl Session, stream: Stream) -> Result<(), AgentError>
+where
+    Stream: AsyncRead + AsyncWrite + Unpin,
+{
+    handle_socket(session, &mut connection(stream)).await
+}
+
+/// Server side of an agent connection.
+///
+/// This is a [`Stream`](futures::Stream) of incoming requests and a
+/// [`Sink`](futures::Sink) for their responses, which must be sent in
+/// the order the requests were received.
+pub type Connection<Stream> = Framed<Stream, Codec<Request, Response>>;
+
+/// Wrap an agent connection for handling requests without a [`Session`].
+///
+/// This allows writing the request loop by hand:
+///
+/// ```
+/// use futures::{SinkExt, TryStreamExt};
+/// use ssh_agent_lib::{
+///     agent::connection,
+///     proto::{Request, Response},
+/// };
+///
+/// # async fn example(
+/// #     stream: impl tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+/// # ) -> Result<(), ssh_agent_lib::error::AgentError> {
+/// let mut connection = connection(stream);
+/// while let Some(request) = connection.try_next().await? {
+///     let response = match request {
+///         Request::RequestIdentities => Response::IdentitiesAnswer(vec![]),
+///         _ => Response::Failure,
+///     };
+///     connection.send(response).await?;
+/// }
+/// # Ok(()) }
+/// ```
+///
+/// Use [`futures::StreamExt::split`] to obtain separate halves for
+/// reading requests and writing responses.
+pub fn connection<Stream>(stream: Stream) -> Connection<Stream>
+where
+    Stream: AsyncRead + AsyncWrite,
+{
+    Framed::new(stream, Codec::default())
+}
+
+async fn handle_socket<Stream>(
     mut session: impl Session,
-    mut adapter: Framed<S::Stream, Codec<Request, Response>>,
+    adapter: &mut Connection<Stream>,
 ) -> Result<(), AgentError>
 where
-    S: ListeningSocket + fmt::Debug + Send,
+    Stream: AsyncRead + AsyncWrite + Unpin,
 {
     loop {
         if let Some(incoming_message) = adapter.try_next().await? {
             log::debug!("Request: {incoming_message:?}");
+            let extension = matches!(incoming_message, Request::Extension(_));
             let response = match session.handle(incoming_message).await {
                 Ok(message) => message,
-                Err(AgentError::ExtensionFailure) => {
-                    log::error!("Extension failure handling message");
-                    Response::ExtensionFailure
-                }
-                Err(e) => {
-                    log::error!("Error handling message: {:?}", e);
-                    Response::Failure
-                }
+                Err(e) => failure_response(extension, e),
             };
             log::debug!("Response: {response:?}");
 
@@ -249,35 +863,150 @@ where
     }
 }
 
+/// Convert an error returned by the session handler into a response.
+///
+/// Failed extension requests, including ones for unknown extensions, are
+/// answered with [`Response::ExtensionFailure`] as OpenSSH expects, and
+/// all other failed requests with [`Response::Failure`].
+fn failure_response(extension: bool, error: AgentError) -> Response {
+    match error {
+        AgentError::ExtensionFailure
+        | AgentError::AgentFailure {
+            code: SSH_AGENT_EXTENSION_FAILURE,
+        } => {
+            log::error!("Extension failure handling message");
+            Response::ExtensionFailure
+        }
+        e if extension => {
+            log::error!("Error handling extension: {:?}", e);
+            Response::ExtensionFailure
+        }
+        e => {
+            log::error!("Error handling message: {:?}", e);
+            Response::Failure
+        }
+    }
+}
+
 /// Type representing an agent listening for incoming connections.
 #[async_trait]
 pub trait Agent: 'static + Sync + Send + Sized {
     /// Create new session object when a new socket is accepted.
     fn new_session(&mut self) -> impl Session;
 
+    /// Create new session object for a client with the given credentials.
+    ///
+    /// [`Agent::listen`] calls this for every accepted socket, so agents can
+    /// base authorization decisions on the connecting process. The default
+    /// implementation ignores the credentials and calls
+    /// [`Agent::new_session`].
+    fn new_session_for(&mut self, _peer: &PeerCredentials) -> impl Session {
+        self.new_session()
+    }
+
+    /// Accept only requests with the given message identifiers.
+    ///
+    /// Other requests are answered with a failure before they reach
+    /// the session handler. See [`Request::message_id`] for the list
+    /// of identifiers.
+    fn allow_requests(self, message_ids: impl IntoIterator<Item = u8>) -> RestrictedAgent<Self> {
+        RestrictedAgent {
+            agent: self,
+            allowed: Arc::new(message_ids.into_iter().collect()),
+        }
+    }
+
+    /// Reject requests with the given message identifiers.
+    ///
+    /// Rejected requests are answered with a failure before they reach
+    /// the session handler. See [`Request::message_id`] for the list
+    /// of identifiers.
+    fn deny_requests(self, message_ids: impl IntoIterator<Item = u8>) -> RestrictedAgent<Self> {
+        let denied: HashSet<u8> = message_ids.into_iter().collect();
+        self.allow_requests((0..=u8::MAX).filter(|id| !denied.contains(id)))
+    }
+
+    /// Answer the [`Query`] extension with the given extension names.
+    ///
+    /// `query` requests are answered before they reach the session
+    /// handler, with `query` itself followed by `extensions`. Clients such
+    /// as OpenSSH decide which extensions to use, e.g. whether to send
+    /// `session-bind@openssh.com`, based on this list, so it should name
+    /// exactly the extensions handled by the sessions.
+    fn with_extensions(
+        self,
+        extensions: impl IntoIterator<Item = impl Into<String>>,
+    ) -> ExtensionAgent<Self> {
+        let mut names = vec![Query::NAME.to_owned()];
+        for extension in extensions {
+            let extension = extension.into();
+            if !names.contains(&extension) {
+                names.push(extension);
+            }
+        }
+        ExtensionAgent {
+            agent: self,
+            extensions: Arc::new(names),
+        }
+    }
+
+    /// Allow at most `limit` signatures per key within any `window`.
+    ///
+    /// Sign requests for a key which already made `limit` signatures
+    /// during the last `window` are answered with a failure before they
+    /// reach the session handler. Keys are told apart by the public key
+    /// of the request and counted across all connections, so a client
+    /// cannot escape the limit by reconnecting. Requests in progress
+    /// count towards the limit, so concurrent requests cannot exceed it,
+    /// but only those answered with a signature are still counted
+    /// afterwards.
+    fn limit_signatures(self, limit: usize, window: Duration) -> RateLimitedAgent<Self> {
+        RateLimitedAgent {
+            agent: self,
+            limiter: Arc::new(SignLimiter {
+                limit,
+                window,
+                signatures: Mutex::default(),
+            }),
+        }
+    }
+
     /// Listen on a socket waiting for client connections.
-    async fn listen<S>(mut self, mut socket: S) -> Result<(), AgentError>
+    async fn listen<S>(mut self, socket: S) -> Result<(), AgentError>
     where
         S: ListeningSocket + fmt::Debug + Send,
     {
-        log::info!("Listening; socket = {:?}", socket);
-        loop {
-            match socket.accept().await {
-                Ok(socket) => {
-                    let session = self.new_session();
-                    tokio::spawn(async move {
-                        let adapter = Framed::new(socket, Codec::<Request, Response>::default());
-                        if let Err(e) = handle_socket::<S>(session, adapter).await {
-                            log::error!("Agent protocol error: {:?}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    log::error!("Failed to accept socket: {:?}", e);
-                    return Err(AgentError::IO(e));
-                }
-            }
-        }
+        accept_connections(&mut self, socket).await
+    }
+
+    /// Listen on an already listening Unix socket passed as a file
+    /// descriptor.
+    ///
+    /// Service managers such as systemd bind the socket before starting
+    /// the agent, which saves it from removing a stale socket file and
+    /// lets clients connect while it is starting. With systemd socket
+    /// activation the first socket is descriptor 3, see
+    /// [`sd_listen_fds`](https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html):
+    ///
+    /// ```no_run
+    /// # async fn example(agent: impl ssh_agent_lib::agent::Agent) -> Result<(), ssh_agent_lib::error::AgentError> {
+    /// use std::os::fd::{FromRawFd, OwnedFd};
+    ///
+    /// // SAFETY: systemd passes the socket as descriptor 3, which is not
+    /// // used by anything else
+    /// let fd = unsafe { OwnedFd::from_raw_fd(3) };
+    /// agent.listen_fd(fd).await
+    /// # }
+    /// ```
+    ///
+    /// The `socket-activation` example checks the environment set by
+    /// systemd first. Binding to `fd://` with [`Agent::bind`] does the same
+    /// through the `service-binding` crate.
+    #[cfg(unix)]
+    async fn listen_fd(self, fd: std::os::fd::OwnedFd) -> Result<(), AgentError> {
+        let listener = std::os::unix::net::UnixListener::from(fd);
+        listener.set_nonblocking(true)?;
+        self.listen(UnixListener::from_std(listener)?).await
     }
 
     /// Bind to a service binding listener.
@@ -302,6 +1031,15 @@ pub trait Agent: 'static + Sync + Send + Sized {
     }
 }
 
+/// Accept connections on `socket` and serve each with a new session of `agent`.
+pub(crate) async fn accept_connections<A, S>(agent: &mut A, socket: S) -> Result<(), AgentError>
+where
+    A: Agent,
+    S: ListeningSocket + fmt::Debug + Send,
+{
+    Server::new().accept(agent, socket).await
+}
+
 impl<T> Agent for T
 where
     T: Default + Session,
@@ -310,3 +1048,1113 @@ where
         Self::default()
     }
 }
+
+/// Agent which accepts only a restricted set of request types.
+///
+/// Created using [`Agent::allow_requests`] or [`Agent::deny_requests`].
+/// Requests which are not permitted are answered with
+/// [`Response::Failure`] and the connection stays open.
+#[derive(Debug)]
+pub struct RestrictedAgent<A> {
+    agent: A,
+    allowed: Arc<HashSet<u8>>,
+}
+
+impl<A> Agent for RestrictedAgent<A>
+where
+    A: Agent,
+{
+    fn new_session(&mut self) -> impl Session {
+        RestrictedSession {
+            session: self.agent.new_session(),
+            allowed: Arc::clone(&self.allowed),
+        }
+    }
+
+    fn new_session_for(&mut self, peer: &PeerCredentials) -> impl Session {
+        RestrictedSession {
+            session: self.agent.new_session_for(peer),
+            allowed: Arc::clone(&self.allowed),
+        }
+    }
+}
+
+struct RestrictedSession<S> {
+    session: S,
+    allowed: Arc<HashSet<u8>>,
+}
+
+#[async_trait]
+impl<S> Session for RestrictedSession<S>
+where
+    S: Session,
+{
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        if self.allowed.contains(&message.message_id()) {
+            self.session.handle(message).await
+        } else {
+            log::debug!("Request denied: {}", message.message_id());
+            Ok(Response::Failure)
+        }
+    }
+}
+
+/// Agent which answers the [`Query`] extension itself.
+///
+/// Created using [`Agent::with_extensions`]. All other requests are
+/// passed to the wrapped agent's sessions.
+#[derive(Debug)]
+pub struct ExtensionAgent<A> {
+    agent: A,
+    extensions: Arc<Vec<String>>,
+}
+
+impl<A> Agent for ExtensionAgent<A>
+where
+    A: Agent,
+{
+    fn new_session(&mut self) -> impl Session {
+        ExtensionSession {
+            session: self.agent.new_session(),
+            extensions: Arc::clone(&self.extensions),
+        }
+    }
+
+    fn new_session_for(&mut self, peer: &PeerCredentials) -> impl Session {
+        ExtensionSession {
+            session: self.agent.new_session_for(peer),
+            extensions: Arc::clone(&self.extensions),
+        }
+    }
+}
+
+struct ExtensionSession<S> {
+    session: S,
+    extensions: Arc<Vec<String>>,
+}
+
+#[async_trait]
+impl<S> Session for ExtensionSession<S>
+where
+    S: Session,
+{
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        match message {
+            Request::Extension(extension) if extension.name == Query::NAME => {
+                let response = QueryResponse {
+                    extensions: self.extensions.to_vec(),
+                };
+                Ok(Response::ExtensionResponse(Extension::new_message(
+                    response,
+                )?))
+            }
+            message => self.session.handle(message).await,
+        }
+    }
+}
+
+/// Agent which limits the rate of signatures made with each key.
+///
+/// Created using [`Agent::limit_signatures`]. Requests over the limit
+/// are answered with [`Response::Failure`] and the connection stays open.
+#[derive(Debug)]
+pub struct RateLimitedAgent<A> {
+    agent: A,
+    limiter: Arc<SignLimiter>,
+}
+
+impl<A> Agent for RateLimitedAgent<A>
+where
+    A: Agent,
+{
+    fn new_session(&mut self) -> impl Session {
+        RateLimitedSession {
+            session: self.agent.new_session(),
+            limiter: Arc::clone(&self.limiter),
+        }
+    }
+
+    fn new_session_for(&mut self, peer: &PeerCredentials) -> impl Session {
+        RateLimitedSession {
+            session: self.agent.new_session_for(peer),
+            limiter: Arc::clone(&self.limiter),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SignLimiter {
+    limit: usize,
+    window: Duration,
+    /// Times of the signatures made within the window, oldest first.
+    signatures: Mutex<HashMap<KeyData, VecDeque<Instant>>>,
+}
+
+impl SignLimiter {
+    fn times(&self) -> MutexGuard<'_, HashMap<KeyData, VecDeque<Instant>>> {
+        self.signatures.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reserve a signature for `key` now, unless it is over the limit.
+    ///
+    /// The reservation is checked and taken under one lock, so concurrent
+    /// requests cannot all pass the check before any of them is counted.
+    fn reserve(&self, key: &KeyData) -> Option<Reservation<'_>> {
+        let mut signatures = self.times();
+        let now = Instant::now();
+        if let Some(times) = signatures.get_mut(key) {
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) >= self.window)
+            {
+                times.pop_front();
+            }
+            if times.len() >= self.limit {
+                return None;
+            }
+            times.push_back(now);
+        } else if self.limit > 0 {
+            signatures.insert(key.clone(), VecDeque::from([now]));
+        } else {
+            return None;
+        }
+        Some(Reservation {
+            limiter: self,
+            key: key.clone(),
+            time: now,
+            kept: false,
+        })
+    }
+
+    fn release(&self, key: &KeyData, time: Instant) {
+        let mut signatures = self.times();
+        let Some(times) = signatures.get_mut(key) else {
+            return;
+        };
+        if let Some(index) = times.iter().position(|reserved| *reserved == time) {
+            times.remove(index);
+        }
+        if times.is_empty() {
+            // keys which are no longer used must not fill the map
+            signatures.remove(key);
+        }
+    }
+}
+
+/// Signature counted towards the limit, released on drop unless kept.
+struct Reservation<'a> {
+    limiter: &'a SignLimiter,
+    key: KeyData,
+    time: Instant,
+    kept: bool,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.kept {
+            self.limiter.release(&self.key, self.time);
+        }
+    }
+}
+
+struct RateLimitedSession<S> {
+    session: S,
+    limiter: Arc<SignLimiter>,
+}
+
+#[async_trait]
+impl<S> Session for RateLimitedSession<S>
+where
+    S: Session,
+{
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        let Request::SignRequest(request) = message else {
+            return self.session.handle(message).await;
+        };
+        let Some(mut reservation) = self.limiter.reserve(&request.pubkey) else {
+            log::debug!("Sign request over the rate limit");
+            return Ok(Response::Failure);
+        };
+        // failed and cancelled requests release their reservation
+        let response = self.session.handle(Request::SignRequest(request)).await?;
+        reservation.kept = matches!(response, Response::SignResponse(_));
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::proto::{Unparsed, SSH_AGENT_FAILURE};
+
+    /// Session recording the sign requests it receives.
+    #[derive(Default)]
+    struct RecordingSession(Vec<SignRequest>);
+
+    #[async_trait]
+    impl Session for RecordingSession {
+        async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+            self.0.push(request);
+            Ok(Signature::new(Algorithm::Ed25519, vec![0; 64]).map_err(ProtoError::from)?)
+        }
+    }
+
+    /// Certificate for `key`, valid forever for all principals, signed by `ca`.
+    fn certificate_for(key: &PublicKey, ca: &PrivateKey) -> Result<Certificate, ProtoError> {
+        let mut builder =
+            ssh_key::certificate::Builder::new([0; 16], key.clone(), 0, i64::MAX as u64)?;
+        builder.all_principals_valid()?;
+        Ok(builder.sign(ca)?)
+    }
+
+    #[derive(Default)]
+    struct LockingSession;
+
+    #[async_trait]
+    impl Session for LockingSession {
+        async fn lock(&mut self, _key: Zeroizing<String>) -> Result<(), AgentError> {
+            Ok(())
+        }
+
+        async fn extension(&mut self, _: Extension) -> Result<Option<Extension>, AgentError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn denied_requests_fail() -> TestResult {
+        let mut agent = LockingSession.deny_requests([22, 27]);
+        let mut session = agent.new_session();
+
+        let response = session
+            .handle(Request::Lock("pass".to_owned().into()))
+            .await?;
+        assert_eq!(response, Response::Failure);
+
+        let extension = Extension {
+            name: "test@example.com".into(),
+            details: Unparsed(vec![]),
+        };
+        let response = session.handle(Request::Extension(extension)).await?;
+        assert_eq!(response, Response::Failure);
+
+        let mut agent = LockingSession.allow_requests([22]);
+        let mut session = agent.new_session();
+
+        let response = session
+            .handle(Request::Lock("pass".to_owned().into()))
+            .await?;
+        assert_eq!(response, Response::Success);
+
+        let response = session.handle(Request::RemoveAllIdentities).await?;
+        assert_eq!(response, Response::Failure);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_over_stream() -> TestResult {
+        let (client, agent) = tokio::io::duplex(1024);
+        let server = tokio::spawn(serve(LockingSession, agent));
+
+        let mut client = crate::client::Client::new(client);
+        client.lock("pass".to_owned()).await?;
+        assert!(client.remove_all_identities().await.is_err());
+
+        // closing the stream ends serving the connection
+        drop(client);
+        server.await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_with_hash_picks_flags() -> TestResult {
+        use ssh_key::public::{EcdsaPublicKey, Ed25519PublicKey, RsaPublicKey};
+        use ssh_key::Mpint;
+
+        let rsa = PublicKey::new(
+            KeyData::Rsa(RsaPublicKey {
+                e: Mpint::from_positive_bytes(&[1, 0, 1]).map_err(ProtoError::from)?,
+                n: Mpint::from_positive_bytes(&[0xab; 256]).map_err(ProtoError::from)?,
+            }),
+            "",
+        );
+        let ed25519 = PublicKey::new(KeyData::Ed25519(Ed25519PublicKey([1; 32])), "");
+
+        let mut session = RecordingSession::default();
+        session
+            .sign_with_hash(&rsa, b"data", HashAlg::Sha256)
+            .await?;
+        session
+            .sign_with_hash(&rsa, b"data", HashAlg::Sha512)
+            .await?;
+        let flags = |session: &RecordingSession| -> Vec<u32> {
+            session.0.iter().map(|request| request.flags).collect()
+        };
+        assert_eq!(flags(&session), [RSA_SHA2_256, RSA_SHA2_512]);
+
+        let error = session
+            .sign_with_hash(&ed25519, b"data", HashAlg::Sha256)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::HashUnsupported {
+                algorithm: Algorithm::Ed25519,
+                hash: HashAlg::Sha256
+            }
+        ));
+
+        // P-384 keys sign with SHA-384, which cannot be asked for
+        let mut point = vec![4];
+        point.extend_from_slice(&[1; 96]);
+        let p384 = PublicKey::new(
+            KeyData::Ecdsa(EcdsaPublicKey::from_sec1_bytes(&point).map_err(ProtoError::from)?),
+            "",
+        );
+        for hash in [HashAlg::Sha256, HashAlg::Sha512] {
+            let error = session
+                .sign_with_hash(&p384, b"data", hash)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                AgentError::HashUnsupported {
+                    algorithm: Algorithm::Ecdsa {
+                        curve: EcdsaCurve::NistP384
+                    },
+                    ..
+                }
+            ));
+        }
+        assert_eq!(session.0.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_with_flags_passes_flags() -> TestResult {
+        use ssh_encoding::Encode;
+        use ssh_key::public::Ed25519PublicKey;
+
+        let key = KeyData::Ed25519(Ed25519PublicKey([1; 32]));
+        let mut blob = vec![];
+        key.encode(&mut blob)?;
+
+        let mut session = RecordingSession::default();
+        session
+            .sign_with_flags(&blob, b"data", crate::proto::RSA_SHA2_256)
+            .await?;
+        let request = session.0.pop().expect("sign called");
+        assert_eq!(request.pubkey, key);
+        assert_eq!(request.data, &b"data"[..]);
+        assert_eq!(request.flags, 0x02);
+
+        // trailing data after the key is rejected
+        blob.push(0);
+        assert!(session.sign_with_flags(&blob, b"data", 0).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signatures_are_rate_limited_per_key() -> TestResult {
+        use ssh_key::public::Ed25519PublicKey;
+
+        let mut agent = RecordingSession::default().limit_signatures(2, Duration::from_secs(3600));
+        let sign = |key| {
+            Request::SignRequest(SignRequest::new(
+                KeyData::Ed25519(Ed25519PublicKey([key; 32])),
+                b"data".to_vec(),
+            ))
+        };
+
+        let mut session = agent.new_session();
+        for _ in 0..2 {
+            let response = session.handle(sign(1)).await?;
+            assert!(matches!(response, Response::SignResponse(_)));
+        }
+        assert_eq!(session.handle(sign(1)).await?, Response::Failure);
+
+        // the limit applies to all connections, but only to the same key
+        let mut session = agent.new_session();
+        assert_eq!(session.handle(sign(1)).await?, Response::Failure);
+        let response = session.handle(sign(2)).await?;
+        assert!(matches!(response, Response::SignResponse(_)));
+
+        // signatures older than the window no longer count
+        let mut agent = RecordingSession::default().limit_signatures(1, Duration::ZERO);
+        let mut session = agent.new_session();
+        for _ in 0..2 {
+            let response = session.handle(sign(1)).await?;
+            assert!(matches!(response, Response::SignResponse(_)));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn key_control_is_verified() -> TestResult {
+        let (client, agent) = tokio::io::duplex(4096);
+        tokio::spawn(serve(crate::keyring::KeyRing::new(), agent));
+        let mut client = crate::client::Client::new(client);
+
+        let key = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        client.add_identity_with_comment(&key, None).await?;
+        assert!(client.verify_key_control(key.public_key()).await?);
+
+        /// Session answering with signatures made by another key.
+        struct ImpostorSession(PrivateKey);
+
+        #[async_trait]
+        impl Session for ImpostorSession {
+            async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+                Ok(
+                    signature::Signer::try_sign(&self.0, &request.data)
+                        .map_err(ProtoError::from)?,
+                )
+            }
+        }
+
+        let other = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        let mut session = ImpostorSession(other);
+        assert!(!session.verify_key_control(key.public_key()).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_signatures_are_rate_limited() -> TestResult {
+        use ssh_key::public::Ed25519PublicKey;
+
+        /// Session taking some time to sign, failing for the data `fail`.
+        #[derive(Default)]
+        struct SlowSession;
+
+        #[async_trait]
+        impl Session for SlowSession {
+            async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                if request.data == b"fail"[..] {
+                    return Err(AgentError::Failure);
+                }
+                Ok(Signature::new(Algorithm::Ed25519, vec![0; 64]).map_err(ProtoError::from)?)
+            }
+        }
+
+        let sign = |data: &[u8]| {
+            Request::SignRequest(SignRequest::new(
+                KeyData::Ed25519(Ed25519PublicKey([1; 32])),
+                data.to_vec(),
+            ))
+        };
+
+        // sessions borrow their agent, so they are built by hand here
+        let agent = SlowSession.limit_signatures(3, Duration::from_secs(3600));
+        let mut sessions: Vec<_> = (0..8)
+            .map(|_| RateLimitedSession {
+                session: SlowSession,
+                limiter: Arc::clone(&agent.limiter),
+            })
+            .collect();
+        let responses = futures::future::join_all(
+            sessions
+                .iter_mut()
+                .map(|session| session.handle(sign(b"data"))),
+        )
+        .await;
+        let signatures = responses
+            .into_iter()
+            .filter(|response| matches!(response, Ok(Response::SignResponse(_))))
+            .count();
+        assert_eq!(signatures, 3);
+
+        // failed signatures do not count
+        let mut agent = SlowSession.limit_signatures(1, Duration::from_secs(3600));
+        let mut session = agent.new_session();
+        assert!(session.handle(sign(b"fail")).await.is_err());
+        assert!(agent.limiter.times().is_empty());
+        let response = session.handle(sign(b"data")).await?;
+        assert!(matches!(response, Response::SignResponse(_)));
+        assert_eq!(session.handle(sign(b"data")).await?, Response::Failure);
+
+        Ok(())
+    }
+
+    /// Session recording the identities added to it.
+    #[derive(Default)]
+    struct AddSession(Vec<AddIdentity>);
+
+    #[async_trait]
+    impl Session for AddSession {
+        async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+            self.0.push(identity);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn add_identity_with_comment() -> TestResult {
+        use ssh_encoding::Encode;
+
+        let mut key = PrivateKey::random(&mut rand::thread_rng(), ssh_key::Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        key.set_comment("own comment");
+
+        let mut session = AddSession::default();
+        session.add_identity_with_comment(&key, None).await?;
+        session.add_identity_with_comment(&key, Some("")).await?;
+
+        let comments: Vec<_> = session
+            .0
+            .iter()
+            .map(|identity| match &identity.credential {
+                Credential::Key { comment, .. } => comment.as_str(),
+                Credential::Cert { .. } => panic!("expected a key"),
+            })
+            .collect();
+        assert_eq!(comments, ["own comment", ""]);
+
+        // the empty comment is still present on the wire
+        let mut encoded = vec![];
+        session.0[1].encode(&mut encoded)?;
+        assert!(encoded.ends_with(&[0, 0, 0, 0]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn identities_are_replaced() -> TestResult {
+        use ssh_key::private::KeypairData;
+
+        let key = |seed| {
+            let privkey =
+                KeypairData::Ed25519(ssh_key::private::Ed25519Keypair::from_seed(&[seed; 32]));
+            let pubkey = KeyData::try_from(&privkey).map_err(ProtoError::from)?;
+            let identity = AddIdentity {
+                credential: Credential::Key {
+                    privkey,
+                    comment: format!("key {seed}"),
+                },
+            };
+            Ok::<_, ProtoError>((identity, pubkey))
+        };
+        let pubkeys = |identities: Vec<Identity>| -> Vec<KeyData> {
+            identities
+                .into_iter()
+                .map(|identity| identity.pubkey)
+                .collect()
+        };
+        let (a, _) = key(1)?;
+        let (b, b_pub) = key(2)?;
+        let (c, c_pub) = key(3)?;
+        let (d, _) = key(4)?;
+
+        let mut keyring = crate::keyring::KeyRing::new();
+        keyring.add_identity(a).await?;
+        keyring.add_identity(b.clone()).await?;
+        keyring.replace_identities(vec![b.clone(), c]).await?;
+        assert_eq!(
+            pubkeys(keyring.request_identities().await?),
+            [b_pub.clone(), c_pub.clone()]
+        );
+
+        // the keyring refuses certificates, the key added before is removed
+        let ca = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        let Credential::Key { privkey, .. } = &b.credential else {
+            unreachable!()
+        };
+        let certificate = certificate_for(&PublicKey::new(b_pub.clone(), ""), &ca)?;
+        let cert = AddIdentity {
+            credential: Credential::certificate(privkey, certificate, "cert")?,
+        };
+        assert!(keyring.replace_identities(vec![d, cert]).await.is_err());
+        assert_eq!(pubkeys(keyring.request_identities().await?), [b_pub, c_pub]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_identity_with_certificate() -> TestResult {
+        use ssh_encoding::{Decode, Encode};
+
+        let ca = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        let mut session = AddSession::default();
+        for algorithm in [
+            Algorithm::Ed25519,
+            Algorithm::Ecdsa {
+                curve: ssh_key::EcdsaCurve::NistP256,
+            },
+        ] {
+            let key =
+                PrivateKey::random(&mut rand::thread_rng(), algorithm).map_err(ProtoError::from)?;
+            let certificate = certificate_for(key.public_key(), &ca)?;
+
+            session
+                .add_identity_with_certificate(&key, &certificate, Some("cert"))
+                .await?;
+
+            // a certificate issued for another key is refused
+            assert!(session
+                .add_identity_with_certificate(&ca, &certificate, None)
+                .await
+                .is_err());
+        }
+
+        for identity in session.0 {
+            let request = Request::AddIdentity(identity);
+            let mut encoded = vec![];
+            request.encode(&mut encoded)?;
+            assert_eq!(Request::decode(&mut &encoded[..])?, request);
+
+            let Request::AddIdentity(AddIdentity {
+                credential: Credential::Cert { algorithm, .. },
+            }) = request
+            else {
+                panic!("expected a certificate");
+            };
+            // message type and the length of the key type precede it
+            let cert_type = algorithm.to_certificate_type();
+            assert_eq!(&encoded[5..][..cert_type.len()], cert_type.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_with_certificate() -> TestResult {
+        use ssh_encoding::{Decode, Encode};
+
+        let ca = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        let key = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        let certificate = certificate_for(key.public_key(), &ca)?;
+
+        let mut keyring = crate::keyring::KeyRing::new();
+        keyring
+            .add_identity(AddIdentity {
+                credential: Credential::Key {
+                    privkey: key.key_data().clone(),
+                    comment: "key".into(),
+                },
+            })
+            .await?;
+        keyring.sign_with_certificate(&certificate, b"data").await?;
+
+        // the certificate blob, as sent by `ssh`, names the same key
+        let mut blob = vec![];
+        certificate.encode(&mut blob)?;
+        keyring.sign_with_flags(&blob, b"data", 0).await?;
+
+        let mut message = vec![13];
+        blob.encode(&mut message)?;
+        b"data".to_vec().encode(&mut message)?;
+        0u32.encode(&mut message)?;
+        let request = Request::decode(&mut &message[..])?;
+        let mut encoded = vec![];
+        request.encode(&mut encoded)?;
+        assert_eq!(encoded, message);
+        let Request::SignRequest(request) = request else {
+            panic!("expected a sign request");
+        };
+        assert_eq!(request.pubkey, *key.public_key().key_data());
+        assert_eq!(request.certificate.as_ref(), Some(&certificate));
+
+        // the client sends the certificate blob as well
+        let mut session = RecordingSession::default();
+        session.sign_with_certificate(&certificate, b"data").await?;
+        let request = session.0.pop().expect("sign called");
+        assert_eq!(request.certificate, Some(certificate));
+
+        Ok(())
+    }
+
+    /// Session signing with `rsa-sha2-512` whatever the flags.
+    struct Sha512Only(Vec<Identity>);
+
+    #[async_trait]
+    impl Session for Sha512Only {
+        async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+            Ok(self.0.clone())
+        }
+
+        async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+            let signature = match request.pubkey {
+                KeyData::Rsa(_) => Signature::new(
+                    Algorithm::Rsa {
+                        hash: Some(HashAlg::Sha512),
+                    },
+                    vec![0; 256],
+                ),
+                _ => Signature::new(Algorithm::Ed25519, vec![0; 64]),
+            };
+            Ok(signature.map_err(ProtoError::from)?)
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_capabilities_are_probed() -> TestResult {
+        use ssh_key::public::{Ed25519PublicKey, RsaPublicKey};
+        use ssh_key::Mpint;
+
+        let rsa = Identity {
+            pubkey: KeyData::Rsa(RsaPublicKey {
+                e: Mpint::from_bytes(&[1, 0, 1]).map_err(ProtoError::from)?,
+                n: Mpint::from_bytes(&[0, 0xc5, 0xa3, 0xb2, 0xf1]).map_err(ProtoError::from)?,
+            }),
+            comment: "rsa".into(),
+        };
+        let ed25519 = Identity {
+            pubkey: KeyData::Ed25519(Ed25519PublicKey([0; 32])),
+            comment: "ed25519".into(),
+        };
+        let mut session = Sha512Only(vec![rsa.clone(), ed25519.clone()]);
+
+        // a broken connection is not a refusal
+        struct Broken(Vec<Identity>);
+
+        #[async_trait]
+        impl Session for Broken {
+            async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+                Ok(self.0.clone())
+            }
+
+            async fn sign(&mut self, _request: SignRequest) -> Result<Signature, AgentError> {
+                Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into())
+            }
+        }
+        let mut broken = Broken(vec![ed25519.clone()]);
+        assert!(matches!(
+            broken.probe_sign_capabilities().await,
+            Err(AgentError::IO(_))
+        ));
+
+        let capabilities = session.probe_sign_capabilities().await?;
+        assert_eq!(
+            capabilities,
+            [
+                SignCapabilities {
+                    identity: rsa,
+                    flags: vec![(RSA_SHA2_256, false), (RSA_SHA2_512, true)],
+                },
+                SignCapabilities {
+                    identity: ed25519,
+                    flags: vec![(0, true)],
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn listen_on_passed_descriptor() -> TestResult {
+        use std::os::fd::OwnedFd;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("agent.sock");
+        // bound and listening like a socket passed by systemd, and blocking
+        let fd = OwnedFd::from(std::os::unix::net::UnixListener::bind(&path)?);
+        tokio::spawn(crate::keyring::KeyRing::new().listen_fd(fd));
+
+        let mut client = crate::client::Client::new(UnixStream::connect(&path).await?);
+        assert!(client.request_identities().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_is_answered() -> TestResult {
+        let mut agent = LockingSession.with_extensions(["session-bind@openssh.com", "query"]);
+        let mut session = agent.new_session();
+
+        assert_eq!(
+            session.query_extensions().await?,
+            ["query", "session-bind@openssh.com"]
+        );
+
+        // other extensions still reach the session
+        let extension = Extension {
+            name: "session-bind@openssh.com".into(),
+            details: Unparsed(vec![]),
+        };
+        let response = session.handle(Request::Extension(extension)).await?;
+        assert_eq!(response, Response::Success);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_extensions_are_extension_failures() -> TestResult {
+        #[derive(Default)]
+        struct EmptySession;
+
+        #[async_trait]
+        impl Session for EmptySession {}
+
+        let (client, agent) = tokio::io::duplex(1024);
+        tokio::spawn(serve(EmptySession, agent));
+        let mut client = crate::client::Client::new(client);
+
+        let extension = Extension {
+            name: "unknown@example.com".into(),
+            details: Unparsed(vec![]),
+        };
+        let error = client.extension(extension).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_EXTENSION_FAILURE
+            }
+        ));
+
+        let error = client.lock("pass".to_owned()).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_FAILURE
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn peer_credentials_are_passed_to_sessions() -> TestResult {
+        use std::sync::Mutex;
+
+        struct PeerAgent(Arc<Mutex<Option<PeerCredentials>>>);
+
+        impl Agent for PeerAgent {
+            fn new_session(&mut self) -> impl Session {
+                LockingSession
+            }
+
+            fn new_session_for(&mut self, peer: &PeerCredentials) -> impl Session {
+                *self.0.lock().unwrap() = Some(peer.clone());
+                LockingSession
+            }
+        }
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("agent.sock");
+        let listener = UnixListener::bind(&path)?;
+        let peer = Arc::new(Mutex::new(None));
+        let agent = PeerAgent(Arc::clone(&peer)).deny_requests([]);
+        tokio::spawn(agent.listen(listener));
+
+        let mut client = crate::client::Client::new(UnixStream::connect(&path).await?);
+        client.lock("pass".to_owned()).await?;
+
+        let peer = peer.lock().unwrap().clone().expect("session created");
+        assert_eq!(peer.pid, Some(std::process::id()));
+        assert!(peer.uid.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn public_keys_include_unknown_algorithms() -> TestResult {
+        use ssh_encoding::Decode;
+
+        #[derive(Default)]
+        struct IdentitiesSession;
+
+        #[async_trait]
+        impl Session for IdentitiesSession {
+            async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+                // identities answer with an ed25519 key and one of an
+                // unknown algorithm
+                let answer = hex_literal::hex!(
+                    "0c 00000002"
+                    "00000033 0000000b 7373682d65643235353139"
+                    "00000020 0101010101010101010101010101010101010101010101010101010101010101"
+                    "00000003 6f6e65"
+                    "0000001b 0000000f 6b6579406578616d706c652e636f6d 00000004 deadbeef"
+                    "00000003 74776f"
+                );
+                match Response::decode(&mut &answer[..])? {
+                    Response::IdentitiesAnswer(identities) => Ok(identities),
+                    response => Err(crate::client::failure(response, "IdentitiesAnswer")),
+                }
+            }
+        }
+
+        let keys = IdentitiesSession.request_public_keys().await?;
+        let comments: Vec<_> = keys.iter().map(PublicKey::comment).collect();
+        assert_eq!(comments, ["one", "two"]);
+        assert_eq!(keys[0].algorithm(), ssh_key::Algorithm::Ed25519);
+        assert!(keys[1].key_data().is_other());
+
+        let identities = IdentitiesSession
+            .request_identities_by_algorithm(Algorithm::Ed25519)
+            .await?;
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].comment, b"one");
+        let other = Algorithm::new("key@example.com").map_err(ProtoError::from)?;
+        let identities = IdentitiesSession
+            .request_identities_by_algorithm(other)
+            .await?;
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].comment, b"two");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn typed_extensions() -> TestResult {
+        use ssh_encoding::{Decode, Encode, Reader, Writer};
+
+        #[derive(Debug, PartialEq)]
+        struct Echo(String);
+
+        impl MessageExtension for Echo {
+            const NAME: &'static str = "echo@example.com";
+        }
+
+        impl ExtensionRequest for Echo {
+            type Response = Echo;
+        }
+
+        impl Encode for Echo {
+            fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+                self.0.encoded_len()
+            }
+
+            fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+                self.0.encode(writer)
+            }
+        }
+
+        impl Decode for Echo {
+            type Error = ProtoError;
+
+            fn decode(reader: &mut impl Reader) -> Result<Self, ProtoError> {
+                Ok(Self(String::decode(reader)?))
+            }
+        }
+
+        #[derive(Default)]
+        struct EchoSession;
+
+        #[async_trait]
+        impl Session for EchoSession {
+            async fn extension(
+                &mut self,
+                extension: Extension,
+            ) -> Result<Option<Extension>, AgentError> {
+                if extension.name == Echo::NAME {
+                    Ok(Some(extension))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+
+        let (client, agent) = tokio::io::duplex(1024);
+        tokio::spawn(serve(EchoSession, agent));
+        let mut client = crate::client::Client::new(client);
+
+        let response = client.extension_typed(Echo("hello".into())).await?;
+        assert_eq!(response, Some(Echo("hello".into())));
+
+        // answered with a plain success
+        assert_eq!(client.extension_typed(Query).await?, None);
+
+        let response = client.extension_raw(Echo::NAME, b"raw").await?;
+        assert_eq!(response.as_deref(), Some(&b"raw"[..]));
+        assert_eq!(client.extension_raw("other@example.com", b"").await?, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remove_listed_identity() -> TestResult {
+        let (client, agent) = tokio::io::duplex(4096);
+        tokio::spawn(serve(crate::keyring::KeyRing::new(), agent));
+        let mut client = crate::client::Client::new(client);
+
+        for algorithm in [
+            Algorithm::Ed25519,
+            Algorithm::Ecdsa {
+                curve: ssh_key::EcdsaCurve::NistP256,
+            },
+        ] {
+            let key =
+                PrivateKey::random(&mut rand::thread_rng(), algorithm).map_err(ProtoError::from)?;
+            client.add_identity_with_comment(&key, Some("key")).await?;
+        }
+
+        let keys = client.request_public_keys().await?;
+        client.remove_identity_for(&keys[0]).await?;
+        assert_eq!(client.request_public_keys().await?, keys[1..]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_can_be_handled_without_a_session() -> TestResult {
+        let (client, agent) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut connection = connection(agent);
+            while let Some(request) = connection.try_next().await? {
+                let response = match request {
+                    Request::Lock(_) => Response::Success,
+                    _ => Response::Failure,
+                };
+                connection.send(response).await?;
+            }
+            Ok::<_, AgentError>(())
+        });
+        let mut client = crate::client::Client::new(client);
+
+        client.lock("passphrase".to_owned()).await?;
+        assert!(client.unlock("passphrase".to_owned()).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn add_identity_from_openssh() -> TestResult {
+        let mut key = PrivateKey::random(&mut rand::thread_rng(), ssh_key::Algorithm::Ed25519)
+            .map_err(ProtoError::from)?;
+        key.set_comment("from file");
+        let encrypted = key
+            .encrypt(&mut rand::thread_rng(), "secret")
+            .map_err(ProtoError::from)?
+            .to_openssh(ssh_key::LineEnding::LF)
+            .map_err(ProtoError::from)?;
+
+        let mut session = AddSession::default();
+        let error = session
+            .add_identity_from_openssh(&encrypted, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AgentError::KeyEncrypted));
+        assert!(session
+            .add_identity_from_openssh(&encrypted, None, Some("wrong"))
+            .await
+            .is_err());
+
+        session
+            .add_identity_from_openssh(&encrypted, None, Some("secret"))
+            .await?;
+        let Credential::Key { privkey, comment } = &session.0[0].credential else {
+            panic!("expected a key");
+        };
+        assert_eq!(privkey, key.key_data());
+        assert_eq!(comment, "from file");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+//! Session wrapper logging requests and responses.
+
+use std::fmt;
+
+use ssh_key::{public::KeyData, HashAlg, PublicKey, Signature};
+
+use super::Session;
+use crate::error::AgentError;
+use crate::proto::{
+    AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Credential, Extension,
+    Identity, RemoveIdentity, Request, Response, SignRequest, SmartcardKey, Zeroizing,
+};
+
+/// Session which logs every request and response of the wrapped session.
+///
+/// Private material is never logged: private keys, smartcard PINs and
+/// the passphrases used to lock and unlock the agent are replaced by
+/// `<redacted>`. Signatures are redacted by default as well, which can
+/// be changed with [`LoggingSession::redact_signatures`].
+///
+/// Messages are logged at [`log::Level::Debug`] unless configured
+/// otherwise using [`LoggingSession::with_level`].
+///
+/// ```
+/// use ssh_agent_lib::agent::{LoggingSession, Session};
+///
+/// fn debug(session: impl Session) -> impl Session {
+///     LoggingSession::new(session).with_level(log::Level::Info)
+/// }
+/// ```
+#[derive(Debug)]
+pub struct LoggingSession<S> {
+    session: S,
+    level: log::Level,
+    redact_signatures: bool,
+}
+
+impl<S> LoggingSession<S>
+where
+    S: Session,
+{
+    /// Wrap a session logging its requests and responses.
+    pub fn new(session: S) -> Self {
+        Self {
+            session,
+            level: log::Level::Debug,
+            redact_signatures: true,
+        }
+    }
+
+    /// Set the level of the log messages.
+    pub fn with_level(mut self, level: log::Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Set whether signatures are replaced by `<redacted>` in the log.
+    pub fn redact_signatures(mut self, redact: bool) -> Self {
+        self.redact_signatures = redact;
+        self
+    }
+
+    /// Return the wrapped session.
+    pub fn into_inner(self) -> S {
+        self.session
+    }
+
+    fn log_request(&self, request: impl FnOnce() -> Request) {
+        if log::log_enabled!(self.level) {
+            log::log!(self.level, "Request: {:?}", Redacted(&request(), self));
+        }
+    }
+
+    fn log_result<T>(&self, result: &Result<T, AgentError>)
+    where
+        for<'a> Redacted<'a, T, S>: fmt::Debug,
+    {
+        match result {
+            Ok(value) => log::log!(self.level, "Response: {:?}", Redacted(value, self)),
+            Err(error) => log::log!(self.level, "Response: error: {error}"),
+        }
+    }
+}
+
+/// Debug representation of a message with private material hidden.
+struct Redacted<'a, T, S>(&'a T, &'a LoggingSession<S>);
+
+const REDACTED: &str = "<redacted>";
+
+/// Key fingerprint, which identifies the key more concisely than its contents.
+fn fingerprint(key: &KeyData) -> String {
+    key.fingerprint(HashAlg::Sha256).to_string()
+}
+
+fn credential(credential: &Credential) -> String {
+    match credential {
+        Credential::Key { privkey, comment } => format!(
+            "Key {{ algorithm: {:?}, privkey: {REDACTED}, comment: {comment:?} }}",
+            privkey.algorithm().ok()
+        ),
+        Credential::Cert {
+            algorithm,
+            certificate,
+            comment,
+            ..
+        } => format!(
+            "Cert {{ algorithm: {algorithm:?}, key_id: {:?}, privkey: {REDACTED}, comment: {comment:?} }}",
+            certificate.key_id()
+        ),
+    }
+}
+
+fn smartcard_key(key: &SmartcardKey) -> String {
+    format!("SmartcardKey {{ id: {:?}, pin: {REDACTED} }}", key.id)
+}
+
+impl<S> fmt::Debug for Redacted<'_, Request, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Request::SignRequest(request) => write!(
+                f,
+                "SignRequest {{ pubkey: {}, data: {} bytes, flags: {} }}",
+                fingerprint(&request.pubkey),
+                request.data.len(),
+                request.flags
+            ),
+            Request::AddIdentity(identity) => {
+                write!(f, "AddIdentity {{ {} }}", credential(&identity.credential))
+            }
+            Request::AddIdConstrained(identity) => write!(
+                f,
+                "AddIdConstrained {{ {}, constraints: {:?} }}",
+                credential(&identity.identity.credential),
+                identity.constraints
+            ),
+            Request::RemoveIdentity(identity) => write!(
+                f,
+                "RemoveIdentity {{ pubkey: {} }}",
+                fingerprint(&identity.pubkey)
+            ),
+            Request::AddSmartcardKey(key) => write!(f, "AddSmartcardKey({})", smartcard_key(key)),
+            Request::RemoveSmartcardKey(key) => {
+                write!(f, "RemoveSmartcardKey({})", smartcard_key(key))
+            }
+            Request::AddSmartcardKeyConstrained(key) => write!(
+                f,
+                "AddSmartcardKeyConstrained {{ key: {}, constraints: {:?} }}",
+                smartcard_key(&key.key),
+                key.constraints
+            ),
+            Request::Lock(_) => write!(f, "Lock({REDACTED})"),
+            Request::Unlock(_) => write!(f, "Unlock({REDACTED})"),
+            request => write!(f, "{request:?}"),
+        }
+    }
+}
+
+impl<S> fmt::Debug for Redacted<'_, Response, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Response::SignResponse(signature) => {
+                write!(f, "SignResponse({:?})", Redacted(signature, self.1))
+            }
+            Response::IdentitiesAnswer(identities) => {
+                write!(f, "IdentitiesAnswer({:?})", Redacted(identities, self.1))
+            }
+            response => write!(f, "{response:?}"),
+        }
+    }
+}
+
+impl<S> fmt::Debug for Redacted<'_, Signature, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1.redact_signatures {
+            write!(
+                f,
+                "Signature {{ algorithm: {:?}, data: {REDACTED} }}",
+                self.0.algorithm()
+            )
+        } else {
+            write!(f, "{:?}", self.0)
+        }
+    }
+}
+
+impl<S> fmt::Debug for Redacted<'_, Vec<Signature>, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|signature| Redacted(signature, self.1)))
+            .finish()
+    }
+}
+
+impl<S> fmt::Debug for Redacted<'_, Vec<Identity>, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.0
+                    .iter()
+                    .map(|identity| (fingerprint(&identity.pubkey), identity.comment_lossy())),
+            )
+            .finish()
+    }
+}
+
+impl<S> fmt::Debug for Redacted<'_, (), S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Success")
+    }
+}
+
+impl<S> fmt::Debug for Redacted<'_, Option<Extension>, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(extension) => write!(f, "ExtensionResponse({extension:?})"),
+            None => write!(f, "Success"),
+        }
+    }
+}
+
+#[crate::async_trait]
+impl<S> Session for LoggingSession<S>
+where
+    S: Session,
+{
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        self.log_request(|| Request::RequestIdentities);
+        let result = self.session.request_identities().await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        self.log_request(|| Request::SignRequest(request.clone()));
+        let result = self.session.sign(request).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn sign_batch(
+        &mut self,
+        key: &PublicKey,
+        datas: &[Vec<u8>],
+    ) -> Result<Vec<Signature>, AgentError> {
+        for data in datas {
+            self.log_request(|| {
+                Request::SignRequest(SignRequest::new(key.key_data().clone(), data.clone()))
+            });
+        }
+        let result = self.session.sign_batch(key, datas).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.log_request(|| Request::AddIdentity(identity.clone()));
+        let result = self.session.add_identity(identity).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.log_request(|| Request::AddIdConstrained(identity.clone()));
+        let result = self.session.add_identity_constrained(identity).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        self.log_request(|| Request::RemoveIdentity(identity.clone()));
+        let result = self.session.remove_identity(identity).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        self.log_request(|| Request::RemoveAllIdentities);
+        let result = self.session.remove_all_identities().await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn add_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.log_request(|| Request::AddSmartcardKey(key.clone()));
+        let result = self.session.add_smartcard_key(key).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn add_smartcard_key_constrained(
+        &mut self,
+        key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        self.log_request(|| Request::AddSmartcardKeyConstrained(key.clone()));
+        let result = self.session.add_smartcard_key_constrained(key).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn remove_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.log_request(|| Request::RemoveSmartcardKey(key.clone()));
+        let result = self.session.remove_smartcard_key(key).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn lock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        // the passphrase is not even copied for logging
+        self.log_request(|| Request::Lock(Zeroizing::default()));
+        let result = self.session.lock(key).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn unlock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        self.log_request(|| Request::Unlock(Zeroizing::default()));
+        let result = self.session.unlock(key).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
+        self.log_request(|| Request::Extension(extension.clone()));
+        let result = self.session.extension(extension).await;
+        self.log_result(&result);
+        result
+    }
+
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        self.log_request(|| message.clone());
+        let result = self.session.handle(message).await;
+        self.log_result(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::{private::KeypairData, Algorithm};
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::keyring::KeyRing;
+
+    #[test]
+    fn private_material_is_redacted() -> TestResult {
+        let session = LoggingSession::new(KeyRing::new());
+        let key = ssh_key::PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)?;
+        let KeypairData::Ed25519(keypair) = key.key_data() else {
+            panic!("expected an Ed25519 key");
+        };
+
+        let request = Request::AddIdentity(AddIdentity {
+            credential: Credential::Key {
+                privkey: key.key_data().clone(),
+                comment: "comment".into(),
+            },
+        });
+        let logged = format!("{:?}", Redacted(&request, &session));
+        assert!(logged.contains("comment"));
+        assert!(!logged.contains(&format!("{:?}", keypair.private.to_bytes())));
+
+        let request = Request::Unlock("passphrase".to_owned().into());
+        assert_eq!(
+            format!("{:?}", Redacted(&request, &session)),
+            "Unlock(<redacted>)"
+        );
+
+        let request = Request::AddSmartcardKey(SmartcardKey::new("token", "1234"));
+        let logged = format!("{:?}", Redacted(&request, &session));
+        assert!(logged.contains("token") && !logged.contains("1234"));
+
+        let signature = Signature::new(Algorithm::Ed25519, vec![7; 64])?;
+        let logged = format!("{:?}", Redacted(&signature, &session));
+        assert!(logged.contains(REDACTED));
+        let session = session.redact_signatures(false);
+        let logged = format!("{:?}", Redacted(&signature, &session));
+        assert_eq!(logged, format!("{signature:?}"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+//! Agent exposing a subset of the keys of another agent.
+
+use std::fmt;
+use std::sync::Arc;
+
+use ssh_key::{public::KeyData, HashAlg, Signature};
+
+use super::{Agent, Session};
+use crate::error::AgentError;
+use crate::proto::{
+    AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Extension, Identity,
+    RemoveIdentity, Request, Response, SignRequest, SmartcardKey, Zeroizing,
+};
+
+type Predicate = Arc<dyn Fn(&Identity) -> bool + Send + Sync>;
+
+/// Session passing requests on to another agent, which only shows the
+/// keys matching a filter.
+///
+/// The upstream session is usually a [`Client`](crate::client::Client)
+/// of the real agent, so the proxy can be served to programs which
+/// should only see some of its keys:
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use ssh_agent_lib::agent::{Agent, FilteringProxy};
+/// use ssh_agent_lib::client::{Client, SharedClient};
+///
+/// let stream = tokio::net::UnixStream::connect(std::env::var("SSH_AUTH_SOCK").unwrap()).await?;
+/// let upstream = SharedClient::new(Client::new(stream));
+/// let proxy = FilteringProxy::new(upstream)
+///     .filter(|identity| identity.comment.ends_with(b"@work"))
+///     .deny_signing(|identity| identity.comment.starts_with(b"deploy"));
+/// proxy
+///     .listen(tokio::net::UnixListener::bind("work-agent.sock")?)
+///     .await
+/// # }
+/// ```
+///
+/// Keys not matching the [filter](Self::filter) are left out of the
+/// identities answer, and requests to sign with or remove them fail with
+/// [`AgentError::KeyNotFound`], as if the agent did not hold them.
+/// Removing all identities removes the visible keys one by one, so the
+/// hidden ones stay in the upstream agent. Visible keys can still be
+/// [denied signing](Self::deny_signing).
+///
+/// Every sign request is logged at [`log::Level::Info`] with the key
+/// fingerprint and whether it was passed on. All other requests, e.g.
+/// adding keys or locking the agent, are passed on unchanged.
+///
+/// Served as an [`Agent`], every connection gets a clone of the proxy,
+/// so the upstream session must be cloneable, like a
+/// [`SharedClient`](crate::client::SharedClient).
+#[derive(Clone)]
+pub struct FilteringProxy<S> {
+    upstream: S,
+    filter: Option<Predicate>,
+    deny_signing: Option<Predicate>,
+    /// Visible identities of the last listing, to check sign requests.
+    visible: Vec<Identity>,
+}
+
+impl<S> FilteringProxy<S>
+where
+    S: Session,
+{
+    /// Pass requests on to `upstream`, showing all of its keys.
+    pub fn new(upstream: S) -> Self {
+        Self {
+            upstream,
+            filter: None,
+            deny_signing: None,
+            visible: vec![],
+        }
+    }
+
+    /// Only show the keys for which `filter` returns `true`.
+    pub fn filter(mut self, filter: impl Fn(&Identity) -> bool + Send + Sync + 'static) -> Self {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Deny signing with the visible keys for which `deny` returns
+    /// `true`, failing with [`AgentError::Failure`].
+    ///
+    /// The keys stay listed, e.g. to be used for authentication through
+    /// another agent.
+    pub fn deny_signing(
+        mut self,
+        deny: impl Fn(&Identity) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.deny_signing = Some(Arc::new(deny));
+        self
+    }
+
+    /// Return the upstream session.
+    pub fn into_inner(self) -> S {
+        self.upstream
+    }
+
+    fn is_visible(&self, identity: &Identity) -> bool {
+        self.filter.as_ref().map_or(true, |filter| filter(identity))
+    }
+
+    /// Find the visible identity of `key`, listing the upstream keys
+    /// again if it was not seen before.
+    async fn visible(&mut self, key: &KeyData) -> Result<Option<Identity>, AgentError> {
+        let find = |visible: &[Identity]| {
+            visible
+                .iter()
+                .find(|identity| identity.pubkey == *key)
+                .cloned()
+        };
+        match find(&self.visible) {
+            Some(identity) => Ok(Some(identity)),
+            None => Ok(find(&self.request_identities().await?)),
+        }
+    }
+}
+
+impl<S> fmt::Debug for FilteringProxy<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FilteringProxy")
+            .field("upstream", &self.upstream)
+            .field("filter", &self.filter.is_some())
+            .field("deny_signing", &self.deny_signing.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Agent for FilteringProxy<S>
+where
+    S: Session + Clone,
+{
+    fn new_session(&mut self) -> impl Session {
+        self.clone()
+    }
+}
+
+#[crate::async_trait]
+impl<S> Session for FilteringProxy<S>
+where
+    S: Session,
+{
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        let mut identities = self.upstream.request_identities().await?;
+        identities.retain(|identity| self.is_visible(identity));
+        self.visible.clone_from(&identities);
+        Ok(identities)
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        let fingerprint = request.pubkey.fingerprint(HashAlg::Sha256);
+        let Some(identity) = self.visible(&request.pubkey).await? else {
+            log::info!("Sign request for {fingerprint}: denied, the key is hidden");
+            return Err(AgentError::KeyNotFound);
+        };
+        if self
+            .deny_signing
+            .as_ref()
+            .is_some_and(|deny| deny(&identity))
+        {
+            log::info!("Sign request for {fingerprint}: denied");
+            return Err(AgentError::Failure);
+        }
+        log::info!("Sign request for {fingerprint}: passed on");
+        self.upstream.sign(request).await
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.upstream.add_identity(identity).await
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.upstream.add_identity_constrained(identity).await
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        if self.visible(&identity.pubkey).await?.is_none() {
+            return Err(AgentError::KeyNotFound);
+        }
+        self.upstream.remove_identity(identity).await
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        for identity in self.request_identities().await? {
+            self.upstream
+                .remove_identity(RemoveIdentity {
+                    pubkey: identity.pubkey,
+                })
+                .await?;
+        }
+        self.visible.clear();
+        Ok(())
+    }
+
+    async fn add_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.upstream.add_smartcard_key(key).await
+    }
+
+    async fn add_smartcard_key_constrained(
+        &mut self,
+        key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        self.upstream.add_smartcard_key_constrained(key).await
+    }
+
+    async fn remove_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.upstream.remove_smartcard_key(key).await
+    }
+
+    async fn lock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        self.upstream.lock(key).await
+    }
+
+    async fn unlock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        self.upstream.unlock(key).await
+    }
+
+    async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
+        self.upstream.extension(extension).await
+    }
+
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        match message {
+            Request::RequestIdentities => {
+                Ok(Response::IdentitiesAnswer(self.request_identities().await?))
+            }
+            Request::SignRequest(request) => Ok(Response::SignResponse(self.sign(request).await?)),
+            Request::RemoveIdentity(identity) => {
+                self.remove_identity(identity).await?;
+                Ok(Response::Success)
+            }
+            Request::RemoveAllIdentities => {
+                self.remove_all_identities().await?;
+                Ok(Response::Success)
+            }
+            message => self.upstream.handle(message).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::{private::PrivateKey, Algorithm, PublicKey};
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::keyring::KeyRing;
+    use crate::proto::Credential;
+
+    async fn add(keyring: &mut KeyRing, comment: &str) -> Result<PublicKey, AgentError> {
+        let key = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+            .map_err(AgentError::other)?;
+        keyring
+            .add_identity(AddIdentity {
+                credential: Credential::Key {
+                    privkey: key.key_data().clone(),
+                    comment: comment.into(),
+                },
+            })
+            .await?;
+        Ok(key.public_key().clone())
+    }
+
+    #[tokio::test]
+    async fn only_matching_keys_are_shown() -> TestResult {
+        let mut keyring = KeyRing::new();
+        let work = add(&mut keyring, "me@work").await?;
+        let deploy = add(&mut keyring, "deploy@work").await?;
+        let home = add(&mut keyring, "me@home").await?;
+
+        let mut proxy = FilteringProxy::new(keyring.clone())
+            .filter(|identity| identity.comment.ends_with(b"@work"))
+            .deny_signing(|identity| identity.comment.starts_with(b"deploy"));
+
+        let identities = proxy.request_identities().await?;
+        let keys: Vec<_> = identities.iter().map(|identity| &identity.pubkey).collect();
+        assert_eq!(keys, [work.key_data(), deploy.key_data()]);
+
+        proxy.sign_ssh_data(&work, b"data").await?;
+        assert!(matches!(
+            proxy.sign_ssh_data(&deploy, b"data").await,
+            Err(AgentError::Failure)
+        ));
+
+        // hidden keys are checked without listing them first
+        let mut proxy = proxy.clone();
+        proxy.visible.clear();
+        assert!(matches!(
+            proxy
+                .handle(Request::SignRequest(SignRequest::new(
+                    home.key_data().clone(),
+                    b"data".to_vec()
+                )))
+                .await,
+            Err(AgentError::KeyNotFound)
+        ));
+        assert!(matches!(
+            proxy.remove_identity_for(&home).await,
+            Err(AgentError::KeyNotFound)
+        ));
+
+        proxy.remove_all_identities().await?;
+        let remaining = keyring.request_identities().await?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(&remaining[0].pubkey, home.key_data());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+//! Accept loop with tunable resource use.
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bytes::BytesMut;
+use futures::SinkExt;
+use tokio::sync::Semaphore;
+use tokio_util::codec::{Framed, FramedParts};
+
+use super::{handle_socket, Agent, ListeningSocket};
+use crate::codec::{self, Codec};
+use crate::error::AgentError;
+use crate::proto::{Request, Response};
+
+/// Buffers which grew larger than this are freed instead of being reused.
+const MAX_REUSED_CAPACITY: usize = 64 * 1024;
+
+/// Settings for serving an agent on a listening socket.
+///
+/// [`Agent::listen`] accepts connections as fast as they arrive and gives
+/// each of them new buffers. Agents used by many short-lived clients,
+/// such as scripts running `ssh` in a loop, can instead bound the number
+/// of connections served at once and keep the buffers of closed
+/// connections for the next ones:
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # async fn example(agent: impl ssh_agent_lib::agent::Agent) -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use ssh_agent_lib::agent::Server;
+///
+/// let listener = tokio::net::UnixListener::bind("agent.sock")?;
+/// Server::new()
+///     .max_concurrent_connections(64)
+///     .reuse_buffers(true)
+///     .listen(agent, listener)
+///     .await
+/// # }
+/// ```
+///
+/// Connections over the limit are not accepted until another one closes,
+/// so they wait in the backlog of the listening socket. The size of the
+/// backlog is chosen when the socket starts listening, e.g. with
+/// [`UnixSocket::listen`](https://docs.rs/tokio/latest/tokio/net/struct.UnixSocket.html#method.listen)
+/// or [`TcpSocket::listen`](tokio::net::TcpSocket::listen); clients
+/// connecting while it is full are refused.
+///
+/// A connection whose client sends a malformed message, or which fails
+/// otherwise, is closed and the error logged, while the other
+/// connections are served on. Only errors of the listening socket
+/// itself end the loop, except for connections aborted by the client
+/// before they were accepted.
+///
+/// The `connection_churn` benchmark compares the settings for clients
+/// which open a new connection for every request.
+#[derive(Clone, Debug, Default)]
+pub struct Server {
+    max_connections: Option<usize>,
+    reuse_buffers: bool,
+}
+
+impl Server {
+    /// Create a server accepting any number of connections, with new
+    /// buffers for each.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve at most `limit` connections at the same time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero, which would never accept a connection.
+    pub fn max_concurrent_connections(mut self, limit: usize) -> Self {
+        assert!(limit > 0, "the connection limit must not be zero");
+        self.max_connections = Some(limit);
+        self
+    }
+
+    /// Keep the buffers of closed connections for new ones.
+    ///
+    /// Buffers are kept for as many connections as were open at the same
+    /// time, except for buffers which grew beyond 64 KiB while handling
+    /// large messages. Requests may carry private keys and passphrases,
+    /// so the buffers are zeroed before they are kept.
+    pub fn reuse_buffers(mut self, enabled: bool) -> Self {
+        self.reuse_buffers = enabled;
+        self
+    }
+
+    /// Accept connections on `socket` and serve each with a new session
+    /// of `agent`.
+    ///
+    /// Unlike [`Agent::listen`], this does not call any override of it,
+    /// e.g. the one of [`KeyRing`](crate::keyring::KeyRing) removing keys
+    /// on shutdown.
+    pub async fn listen<A, S>(&self, mut agent: A, socket: S) -> Result<(), AgentError>
+    where
+        A: Agent,
+        S: ListeningSocket + fmt::Debug + Send,
+    {
+        self.accept(&mut agent, socket).await
+    }
+
+    pub(crate) async fn accept<A, S>(&self, agent: &mut A, mut socket: S) -> Result<(), AgentError>
+    where
+        A: Agent,
+        S: ListeningSocket + fmt::Debug + Send,
+    {
+        let slots = self
+            .max_connections
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let pool = self.reuse_buffers.then(|| Arc::new(BufferPool::default()));
+
+        log::info!("Listening; socket = {:?}", socket);
+        loop {
+            let slot = match &slots {
+                Some(slots) => Some(
+                    Arc::clone(slots)
+                        .acquire_owned()
+                        .await
+                        .expect("the semaphore is never closed"),
+                ),
+                None => None,
+            };
+            match socket.accept().await {
+                Ok(socket) => {
+                    let peer = S::peer_credentials(&socket);
+                    log::debug!("Accepted connection; peer = {peer:?}");
+                    let session = agent.new_session_for(&peer);
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        let mut connection = match &pool {
+                            Some(pool) => pool.connection(socket),
+                            None => super::connection(socket),
+                        };
+                        if let Err(e) = handle_socket(session, &mut connection).await {
+                            log::error!("Agent protocol error: {:?}; peer = {peer:?}", e);
+                            // the stream cannot be resynchronized after a
+                            // malformed frame, so only shutting it down is
+                            // left, which fails if the peer is gone already
+                            let _ = connection.close().await;
+                        }
+                        if let Some(pool) = pool {
+                            pool.put(connection.into_parts());
+                        }
+                        drop(slot);
+                    });
+                }
+                Err(e) if is_aborted(&e) => {
+                    // the client gave up before its connection was accepted
+                    log::warn!("Connection aborted before it was accepted: {:?}", e);
+                }
+                Err(e) => {
+                    log::error!("Failed to accept socket: {:?}", e);
+                    return Err(AgentError::IO(e));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `error` of accepting a connection only concerns that connection.
+fn is_aborted(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+    )
+}
+
+/// Read and write buffers of closed connections.
+#[derive(Default)]
+struct BufferPool(Mutex<Vec<(BytesMut, BytesMut)>>);
+
+impl BufferPool {
+    fn connection<Stream>(&self, stream: Stream) -> super::Connection<Stream> {
+        let mut parts = FramedParts::new::<Response>(stream, Codec::default());
+        if let Some((read, write)) = self.buffers().pop() {
+            parts.read_buf = read;
+            parts.write_buf = write;
+        }
+        Framed::from_parts(parts)
+    }
+
+    fn put<Stream>(&self, parts: FramedParts<Stream, Codec<Request, Response>>) {
+        let (mut read, mut write) = (parts.read_buf, parts.write_buf);
+        // data left by a client which disconnected mid-message is dropped
+        codec::wipe(&mut read);
+        codec::wipe(&mut write);
+        if read.capacity() > MAX_REUSED_CAPACITY || write.capacity() > MAX_REUSED_CAPACITY {
+            return;
+        }
+        self.buffers().push((read, write));
+    }
+
+    fn buffers(&self) -> MutexGuard<'_, Vec<(BytesMut, BytesMut)>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use testresult::TestResult;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::agent::Session;
+    use crate::client::Client;
+    use crate::keyring::KeyRing;
+
+    #[test]
+    #[should_panic = "must not be zero"]
+    fn connection_limit_must_not_be_zero() {
+        let _ = Server::new().max_concurrent_connections(0);
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait() -> TestResult {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let server = Server::new()
+            .max_concurrent_connections(1)
+            .reuse_buffers(true);
+        tokio::spawn(async move { server.listen(KeyRing::new(), listener).await });
+
+        let mut first = Client::new(TcpStream::connect(address).await?);
+        assert!(first.request_identities().await?.is_empty());
+
+        // the second connection is not accepted while the first is open
+        let mut second = Client::new(TcpStream::connect(address).await?);
+        second.set_request_timeout(Some(Duration::from_millis(100)));
+        assert!(matches!(
+            second.request_identities().await,
+            Err(AgentError::Timeout)
+        ));
+
+        drop(first);
+        let mut third = Client::new(TcpStream::connect(address).await?);
+        third.set_request_timeout(Some(Duration::from_secs(10)));
+        assert!(third.request_identities().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_close_only_their_connection() -> TestResult {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move { Server::new().listen(KeyRing::new(), listener).await });
+
+        let mut clients = vec![];
+        for _ in 0..3 {
+            let mut client = Client::new(TcpStream::connect(address).await?);
+            assert!(client.request_identities().await?.is_empty());
+            clients.push(client);
+        }
+
+        // a sign request whose key blob is cut short
+        let mut misbehaving = TcpStream::connect(address).await?;
+        misbehaving.write_all(&[0, 0, 0, 5, 13, 0, 0, 0, 9]).await?;
+        let mut rest = vec![];
+        misbehaving.read_to_end(&mut rest).await?;
+        assert!(
+            rest.is_empty(),
+            "the connection is closed without an answer"
+        );
+
+        for client in &mut clients {
+            assert!(client.request_identities().await?.is_empty());
+        }
+        let mut client = Client::new(TcpStream::connect(address).await?);
+        assert!(client.request_identities().await?.is_empty());
+
+        Ok(())
+    }
+}
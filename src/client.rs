@@ -1,111 +1,1681 @@
 //! SSH agent client support.
 
 use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures::{SinkExt, TryStreamExt};
-use ssh_key::Signature;
-use tokio::io::{AsyncRead, AsyncWrite};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt, SinkExt, TryStreamExt,
+};
+use ssh_encoding::{Decode, Reader};
+use ssh_key::{public::KeyData, Algorithm, HashAlg, PublicKey, Signature};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio_util::codec::Framed;
 
 use crate::{
-    codec::Codec,
-    error::AgentError,
+    codec::{self, Capture, Codec},
+    error::{AgentError, ConnectError},
     proto::{
+        extension::{MessageExtension, Query, QueryResponse},
+        signature::{RSA_SHA2_256, RSA_SHA2_512},
         AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Extension, Identity,
-        ProtoError, RemoveIdentity, Request, Response, SignRequest, SmartcardKey,
+        ProtoError, RemoveIdentity, Request, Response, SignRequest, SmartcardKey, Zeroizing,
+        SSH_AGENT_EXTENSION_FAILURE, SSH_AGENT_FAILURE,
     },
 };
 
+mod discover;
+mod keepalive;
+#[cfg(windows)]
+mod pageant;
+mod refresh;
+mod shared;
+mod task;
+mod tcp;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tracing")]
+mod trace;
+
+/// The `rustls` crate, to configure [`connect_tls`].
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+
+pub use self::discover::{discover_agents, AgentInfo};
+pub use self::keepalive::Keepalive;
+#[cfg(windows)]
+pub use self::pageant::PageantStream;
+pub use self::refresh::KeyRefresher;
+pub use self::shared::SharedClient;
+pub use self::tcp::TcpOptions;
+#[cfg(feature = "tls")]
+pub use self::tls::{connect_tls, tls_config_without_verification};
+
+/// Function opening a new connection to the agent.
+struct Dialer<Stream>(Box<dyn Fn() -> BoxFuture<'static, io::Result<Stream>> + Send + Sync>);
+
+impl<Stream> fmt::Debug for Dialer<Stream> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dialer").finish_non_exhaustive()
+    }
+}
+
+/// Function receiving the timing of every request.
+#[cfg(feature = "timing")]
+struct TimingHook(Box<dyn Fn(RequestTiming) + Send + Sync>);
+
+#[cfg(feature = "timing")]
+impl fmt::Debug for TimingHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimingHook").finish_non_exhaustive()
+    }
+}
+
 /// SSH agent client
+///
+/// Dropping the client closes the connection to the agent, see
+/// [`Client::close`] for shutting it down explicitly.
+///
+/// With the `tracing` feature, every request except those of
+/// [batches](crate::agent::Session::sign_batch) is wrapped in a
+/// `ssh_agent_request` span at the debug level. The span records the
+/// types of the request and response and the time taken, but never the
+/// contents of either.
+///
+/// A response which does not fill the frame announced by its length
+/// prefix fails with [`ProtoError::Desync`]. Since the following
+/// responses cannot be found reliably, the connection is closed and
+/// must be [reopened](Client::reconnect).
+///
+/// Signatures are checked to use the algorithm implied by the key and
+/// flags of the request, failing with [`AgentError::AlgorithmMismatch`]
+/// otherwise. Legacy `ssh-rsa` signatures using SHA-1 always fail with
+/// [`ProtoError::LegacySha1Signature`], so a signature returned by the
+/// client never relies on SHA-1.
+///
+/// # Pageant
+///
+/// PuTTY's Pageant answers identity and sign requests like OpenSSH for
+/// the usual key types, with two differences the client accounts for:
+///
+/// - it rejects sign requests with flags for keys other than RSA, which
+///   OpenSSH ignores, so such flags are cleared once the client knows it
+///   talks to Pageant, i.e. after [`Client::detect_flavor`] found it or
+///   if it was created by `connect_pageant` on Windows;
+/// - it answers the `query` extension with `SSH_AGENT_SUCCESS` followed
+///   by the extension names, which is decoded as
+///   [`Response::SuccessWithData`] and understood by
+///   [`query_extensions`](crate::agent::Session::query_extensions).
+///   Data after a success message answering any other request fails
+///   with [`ProtoError::Desync`] instead.
+///
+/// Old versions of Pageant without support for SHA-2 signatures with RSA
+/// keys only make SHA-1 signatures, which are rejected as described above.
+///
+/// # gpg-agent
+///
+/// GnuPG's `gpg-agent` emulates an SSH agent when started with
+/// `enable-ssh-support`, but implements only part of the protocol. Once
+/// the client knows it talks to `gpg-agent`, i.e. after
+/// [`Client::detect_flavor`] found it or it was set with
+/// [`Client::set_flavor`]:
+///
+/// - the `query` extension is not sent, including when
+///   [querying on connect](Client::set_query_on_connect) is enabled, and
+///   the agent is taken to support no extensions;
+/// - a failure to add an identity, with or without constraints, is
+///   returned as [`AgentError::KeyNotAdded`]. `gpg-agent` asks for a
+///   passphrase to protect every added key and fails if it cannot, e.g.
+///   without a pinentry program, or if it does not support the key type.
+///
+/// Listing identities and signing work as with OpenSSH. Operations
+/// known to be unsupported are:
+///
+/// - all extensions, e.g. [`session_bind`](crate::agent::Session::session_bind),
+///   which fail;
+/// - adding and removing smartcard keys, which fail, as `gpg-agent` uses
+///   the OpenPGP cards it knows by itself;
+/// - removing identities, as well as locking and unlocking the agent.
+///   These are acknowledged as successful, but at most make `gpg-agent`
+///   forget the cached passphrase of a key: keys are removed by editing
+///   its `sshcontrol` file.
+///
+/// # Locked agents
+///
+/// A locked agent lists no identities and answers other requests with a
+/// plain failure, which does not say why. Failed sign requests are
+/// reported as [`AgentError::Locked`] after the agent listed no
+/// identities or was locked through the client on the current
+/// connection. This is a guess, as is
+/// [`Client::is_locked`], which does not change the state of the agent.
+///
+/// # Cancellation
+///
+/// Requests can be cancelled by dropping their futures, e.g. when a sign
+/// request waits for user confirmation and another branch of
+/// `tokio::select!` completes first. The agent protocol has no way to
+/// cancel a request, so the connection is closed instead: later requests
+/// fail with a "not connected" error until the client
+/// [reconnects](Client::reconnect), which happens automatically if
+/// [enabled](Client::set_auto_reconnect).
 #[derive(Debug)]
 pub struct Client<Stream>
 where
-    Stream: fmt::Debug + AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
-    adapter: Framed<Stream, Codec<Response, Request>>,
+    adapter: Option<Framed<Stream, Codec<Answer, Request>>>,
+    timeout: Option<Duration>,
+    dialer: Option<Dialer<Stream>>,
+    auto_reconnect: bool,
+    retry: RetryPolicy,
+    check_keys: bool,
+    drop_constraints: bool,
+    flavor: Option<AgentFlavor>,
+    capture: Option<Capture>,
+    drain: bool,
+    prelude: bool,
+    /// Extensions listed by the agent on the current connection.
+    extensions: Option<Vec<String>>,
+    identity_ttl: Duration,
+    /// Last listing of the identities, with the time it was received.
+    identities: Option<(std::time::Instant, Vec<Identity>)>,
+    /// Whether the agent listed no identities or was locked by this
+    /// client last on the current connection.
+    lock_suspected: bool,
+    #[cfg(feature = "timing")]
+    timing: Option<TimingHook>,
+}
+
+/// Policy for retrying requests which failed due to a broken connection
+/// or a timeout.
+///
+/// Only requests which can safely be sent twice are retried: listing
+/// identities and signing. Requests changing the state of the agent,
+/// such as adding identities or locking, are never retried by the policy.
+/// Every retry opens a new connection, so the client must be created
+/// with [`Client::connect_with`].
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use ssh_agent_lib::client::RetryPolicy;
+///
+/// let policy = RetryPolicy::default()
+///     .max_attempts(5)
+///     .timeout(Some(Duration::from_secs(2)));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    timeout: Option<Duration>,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Send every request only once.
+    ///
+    /// This is the policy of new clients.
+    pub fn no_retry() -> Self {
+        Self {
+            max_attempts: 1,
+            timeout: None,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    /// Set the total number of attempts, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the time to wait for the response of each attempt.
+    ///
+    /// With [`None`] the [request timeout](Client::set_request_timeout)
+    /// of the client is used.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the delay before the first retry, which doubles for every
+    /// following one.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts of at most ten seconds each, retried after 100 and
+    /// 200 milliseconds.
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            timeout: Some(Duration::from_secs(10)),
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Time taken by the phases of an exchange with the agent, as reported
+/// by [`Client::with_timing`].
+///
+/// All durations are measured from the start of writing the request.
+/// Comparing them tells an agent which is slow to answer, e.g. while it
+/// waits for a confirmation or a hardware token, apart from a slow
+/// transport, which delays writing or spreads a response out.
+#[cfg(feature = "timing")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RequestTiming {
+    /// Time until the request was written and flushed.
+    pub write: Duration,
+
+    /// Time until the first byte of the response was read.
+    pub first_byte: Duration,
+
+    /// Time until the whole response was read and decoded.
+    pub complete: Duration,
+}
+
+/// Implementation of the agent, as guessed by [`Client::detect_flavor`].
+///
+/// Agents differ in the extensions and key types they support, e.g.
+/// `gpg-agent` implements none of the OpenSSH extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AgentFlavor {
+    /// The `ssh-agent` of OpenSSH.
+    OpenSsh,
+
+    /// PuTTY's Pageant.
+    Pageant,
+
+    /// The SSH agent emulation of GnuPG's `gpg-agent`.
+    GpgAgent,
+
+    /// An agent which could not be recognized, e.g. one written with
+    /// this library.
+    Unknown,
 }
 
 impl<Stream> Client<Stream>
 where
-    Stream: fmt::Debug + AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
     /// Create a new SSH agent client wrapping a given socket.
+    ///
+    /// Any bidirectional stream carrying the agent protocol can be used,
+    /// not only sockets opened by [`connect`]. For example, an SSH server
+    /// which received an agent forwarding request can open an
+    /// `auth-agent@openssh.com` channel back to the SSH client and wrap
+    /// that channel to talk to the user's forwarded agent.
     pub fn new(socket: Stream) -> Self {
         let adapter = Framed::new(socket, Codec::default());
-        Self { adapter }
+        Self {
+            adapter: Some(adapter),
+            timeout: None,
+            dialer: None,
+            auto_reconnect: false,
+            retry: RetryPolicy::no_retry(),
+            check_keys: false,
+            drop_constraints: false,
+            flavor: None,
+            capture: None,
+            drain: false,
+            prelude: false,
+            extensions: None,
+            identity_ttl: Duration::ZERO,
+            identities: None,
+            lock_suspected: false,
+            #[cfg(feature = "timing")]
+            timing: None,
+        }
+    }
+
+    /// Record the raw bytes of every request and response to `writer`.
+    ///
+    /// This is meant for looking into the traffic with an agent, e.g. to
+    /// reverse-engineer the extensions it supports. Requests and responses
+    /// are written in the order they are sent and received, as complete
+    /// frames including their length prefix, see [`Codec::with_capture`]
+    /// for details. The capture continues on connections opened by
+    /// [reconnecting](Client::reconnect).
+    ///
+    /// ```no_run
+    /// # #[cfg(unix)]
+    /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// use ssh_agent_lib::{agent::Session, client::Client};
+    ///
+    /// let stream = tokio::net::UnixStream::connect(std::env::var("SSH_AUTH_SOCK")?).await?;
+    /// let mut client = Client::new(stream).with_capture(std::fs::File::create("agent.bin")?);
+    /// client.request_identities().await?;
+    /// # Ok(()) }
+    /// ```
+    ///
+    /// The frames can be read back with [`decode_frame`](crate::proto::decode_frame),
+    /// alternating between [`Request`] and [`Response`].
+    ///
+    /// **Warning: captures contain the private keys of added identities,
+    /// lock passphrases and smartcard PINs in plaintext.** Store them as
+    /// carefully as the keys themselves, and do not share them.
+    pub fn with_capture(mut self, writer: impl io::Write + Send + 'static) -> Self {
+        self.capture = Some(Capture::new(writer));
+        let codec = self.codec();
+        if let Some(adapter) = &mut self.adapter {
+            *adapter.codec_mut() = codec;
+        }
+        self
+    }
+
+    /// Call `hook` with the [timing](RequestTiming) of every exchange
+    /// with the agent which got its responses.
+    ///
+    /// A [batch](crate::agent::Session::sign_batch) is reported once for
+    /// every eight requests, which are sent together, with the first byte
+    /// of their first response and the completion of their last one.
+    /// Since the first byte is only noticed once it has been read, a
+    /// response arriving at once is reported as complete right when its
+    /// first byte was read.
+    ///
+    /// ```no_run
+    /// # #[cfg(unix)]
+    /// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+    /// use ssh_agent_lib::{agent::Session, client::Client};
+    ///
+    /// let stream = tokio::net::UnixStream::connect(std::env::var("SSH_AUTH_SOCK")?).await?;
+    /// let mut client = Client::new(stream).with_timing(|timing| eprintln!("{timing:?}"));
+    /// client.request_identities().await?;
+    /// # Ok(()) }
+    /// ```
+    ///
+    /// This requires the `timing` feature.
+    #[cfg(feature = "timing")]
+    pub fn with_timing(mut self, hook: impl Fn(RequestTiming) + Send + Sync + 'static) -> Self {
+        self.timing = Some(TimingHook(Box::new(hook)));
+        self
+    }
+
+    fn codec(&self) -> Codec<Answer, Request> {
+        Codec::default().captured_by(self.capture.clone())
+    }
+
+    /// Create a new SSH agent client using a function which opens
+    /// connections to the agent.
+    ///
+    /// The function is called once to open the initial connection and
+    /// then again each time the client [reconnects](Self::reconnect).
+    ///
+    /// ```no_run
+    /// # #[cfg(unix)]
+    /// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+    /// use ssh_agent_lib::client::Client;
+    ///
+    /// let path = std::env::var("SSH_AUTH_SOCK").unwrap();
+    /// let mut client =
+    ///     Client::connect_with(move || tokio::net::UnixStream::connect(path.clone())).await?;
+    /// client.set_auto_reconnect(true);
+    /// # Ok(()) }
+    /// ```
+    pub async fn connect_with<F, Fut>(dial: F) -> Result<Self, AgentError>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<Stream>> + Send + 'static,
+    {
+        let dialer = Dialer(Box::new(move || dial().boxed()));
+        let mut client = Self::new((dialer.0)().await?);
+        client.dialer = Some(dialer);
+        Ok(client)
+    }
+
+    /// Set the maximum time to wait for the agent to answer a single request.
+    ///
+    /// The timeout applies to each request separately. When a request
+    /// times out [`AgentError::Timeout`] is returned and the connection
+    /// is closed, since a late response could otherwise be mistaken for
+    /// the answer to the next request. All subsequent requests on this
+    /// client will fail until it [reconnects](Self::reconnect).
+    ///
+    /// Passing [`None`] disables the timeout (this is the default).
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Enable or disable automatic reconnection.
+    ///
+    /// When enabled, a request failing due to a broken connection,
+    /// including one the agent [closed](AgentError::Disconnected), is
+    /// retried once after reconnecting to the agent. This requires the
+    /// client to be created with [`Client::connect_with`].
+    ///
+    /// This is disabled by default, as retrying requests which are
+    /// not idempotent (e.g. adding identities) may be surprising.
+    pub fn set_auto_reconnect(&mut self, enabled: bool) {
+        self.auto_reconnect = enabled;
+    }
+
+    /// Set the policy for retrying requests, see [`RetryPolicy`].
+    ///
+    /// Retried requests are not affected by
+    /// [automatic reconnection](Self::set_auto_reconnect), which applies
+    /// to all other requests.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    /// Check that the agent holds the key before asking it to sign.
+    ///
+    /// Agents answer sign requests for keys they do not hold with a
+    /// generic failure, which cannot be told apart from e.g. a denied
+    /// confirmation. When enabled, the identities of the agent are
+    /// requested first, and signing with a key which is not among them
+    /// fails with [`AgentError::KeyNotFound`]. A batch of signatures is
+    /// checked once.
+    ///
+    /// This is disabled by default, as it costs an extra round trip for
+    /// every signature.
+    pub fn set_check_key_presence(&mut self, enabled: bool) {
+        self.check_keys = enabled;
+    }
+
+    /// Add identities without their constraints if the agent rejects them.
+    ///
+    /// Minimal agents answer every
+    /// [constrained add](crate::agent::Session::add_identity_constrained)
+    /// with a failure. When enabled, such a failure is followed by adding
+    /// the identity again without any constraints, and a warning naming
+    /// the dropped constraints is logged. A key meant to expire or to need
+    /// confirmation for every use then stays usable without either, so
+    /// this should only be enabled where that is acceptable.
+    ///
+    /// This is disabled by default.
+    pub fn set_drop_unsupported_constraints(&mut self, enabled: bool) {
+        self.drop_constraints = enabled;
+    }
+
+    /// Answer repeated identity listings from a cache for `ttl`.
+    ///
+    /// User interfaces refreshing a list of keys may ask for the
+    /// [identities](crate::agent::Session::request_identities) far more
+    /// often than they change. When enabled, an answer of the agent is
+    /// returned again for calls within `ttl` of it, without sending a
+    /// request. Any other request except signing, e.g. adding or removing
+    /// keys or locking the agent, as well as
+    /// [reconnecting](Self::reconnect), discards the cached answer, so the
+    /// client's own changes are seen immediately. Changes made by other
+    /// clients of the agent may remain unseen until `ttl` has passed.
+    ///
+    /// This is disabled by default, a `ttl` of zero disables it again.
+    pub fn enable_identity_cache(&mut self, ttl: Duration) {
+        self.identity_ttl = ttl;
+        self.identities = None;
+    }
+
+    /// Discard data received outside of a response before each request.
+    ///
+    /// The agent only ever speaks when asked, so any bytes waiting to be
+    /// read before a request is sent would otherwise be read as (part of)
+    /// its response. Forwarded agents reached through flaky tunnels, or
+    /// proxies replaying data, are known to do this. When enabled, such
+    /// data is read and discarded with a warning naming its length.
+    ///
+    /// This is disabled by default, since unsolicited data usually points
+    /// to a bug in the agent or in the transport, which failing the next
+    /// request makes visible. Responses which arrive late, e.g. after a
+    /// [timeout](Self::set_request_timeout), are never read this way, as
+    /// such a connection is closed.
+    pub fn set_drain_unsolicited(&mut self, enabled: bool) {
+        self.drain = enabled;
+    }
+
+    /// Query the agent's extensions before the first request on every
+    /// connection.
+    ///
+    /// The agent protocol has no version handshake, but agents differ in
+    /// the extensions they support. When enabled, the [`Query`] extension
+    /// is sent ahead of the first request on a connection, including one
+    /// opened by [reconnecting](Self::reconnect), whether explicitly or
+    /// while retrying a request, and its answer is kept
+    /// as the [capabilities](Self::capabilities) of the agent. Flavor
+    /// [detection](Self::detect_flavor) and
+    /// [`query_extensions`](crate::agent::Session::query_extensions) then
+    /// use the recorded list instead of asking again.
+    ///
+    /// Agents answering the query with a failure are recorded as
+    /// supporting no extensions. This is disabled by default, since some
+    /// minimal agents close the connection on any extension request.
+    pub fn set_query_on_connect(&mut self, enabled: bool) {
+        self.prelude = enabled;
+    }
+
+    /// Extensions the agent listed on the current connection, if it was
+    /// queried already.
+    ///
+    /// The list is recorded by [querying on connect](Self::set_query_on_connect),
+    /// [detecting the flavor](Self::detect_flavor) or
+    /// [`query_extensions`](crate::agent::Session::query_extensions), and
+    /// is forgotten when the client [reconnects](Self::reconnect).
+    pub fn capabilities(&self) -> Option<&[String]> {
+        self.extensions.as_deref()
+    }
+
+    /// The extensions of the agent, querying them if they are not known
+    /// for the current connection yet.
+    async fn extensions(&mut self) -> Result<Vec<String>, AgentError> {
+        if let Some(extensions) = &self.extensions {
+            return Ok(extensions.clone());
+        }
+        if self.flavor == Some(AgentFlavor::GpgAgent) {
+            // gpg-agent implements no extensions, not even `query`
+            self.extensions = Some(vec![]);
+            return Ok(vec![]);
+        }
+        let query = Request::Extension(Extension::new_message(Query)?);
+        let response = self.send_all(vec![query]).await?.remove(0);
+        let extensions = extension_names(response)?;
+        self.extensions = Some(extensions.clone());
+        Ok(extensions)
+    }
+
+    /// Guess which implementation the agent is.
+    ///
+    /// The agent protocol has no way to ask an agent for its name, so the
+    /// flavor is derived from the agent's behavior:
+    ///
+    /// 1. An agent answering the [`query`](crate::agent::Session::query_extensions)
+    ///    extension is [Pageant](AgentFlavor::Pageant) if it lists any of
+    ///    PuTTY's `@putty.projects.tartarus.org` extensions and
+    ///    [unknown](AgentFlavor::Unknown) otherwise.
+    /// 2. Of the agents refusing it, one holding keys with a `cardno:`
+    ///    comment, which `gpg-agent` gives to the keys of OpenPGP cards, is
+    ///    [`gpg-agent`](AgentFlavor::GpgAgent).
+    /// 3. Any other agent is assumed to be [OpenSSH](AgentFlavor::OpenSsh),
+    ///    which does not implement `query`. In particular, `gpg-agent`
+    ///    without card keys is reported as OpenSSH.
+    ///
+    /// The result is kept for later calls. Since the guess can be wrong,
+    /// it can be replaced using [`Client::set_flavor`], and no requests
+    /// are sent once a flavor is set.
+    pub async fn detect_flavor(&mut self) -> Result<AgentFlavor, AgentError> {
+        if let Some(flavor) = self.flavor {
+            return Ok(flavor);
+        }
+        let extensions = self.extensions().await?;
+        let flavor = if extensions
+            .iter()
+            .any(|name| name.ends_with("@putty.projects.tartarus.org"))
+        {
+            AgentFlavor::Pageant
+        } else if !extensions.is_empty() {
+            AgentFlavor::Unknown
+        } else {
+            let identities = match self.request(Request::RequestIdentities).await? {
+                Response::IdentitiesAnswer(identities) => identities,
+                response => return Err(failure(response, "IdentitiesAnswer")),
+            };
+            if identities
+                .iter()
+                .any(|identity| identity.comment.starts_with(b"cardno:"))
+            {
+                AgentFlavor::GpgAgent
+            } else {
+                AgentFlavor::OpenSsh
+            }
+        };
+        self.flavor = Some(flavor);
+        Ok(flavor)
+    }
+
+    /// Set the flavor returned by [`Client::detect_flavor`] instead of
+    /// guessing it, or with [`None`] guess it again on the next call.
+    pub fn set_flavor(&mut self, flavor: Option<AgentFlavor>) {
+        self.flavor = flavor;
+    }
+
+    /// Fail with [`AgentError::KeyNotFound`] if checking keys is enabled
+    /// and the agent does not hold `key`.
+    async fn check_key_presence(&mut self, key: &KeyData) -> Result<(), AgentError> {
+        if !self.check_keys {
+            return Ok(());
+        }
+        match self.request(Request::RequestIdentities).await? {
+            Response::IdentitiesAnswer(identities) => {
+                if identities.iter().any(|identity| identity.pubkey == *key) {
+                    Ok(())
+                } else {
+                    Err(AgentError::KeyNotFound)
+                }
+            }
+            response => Err(failure(response, "IdentitiesAnswer")),
+        }
+    }
+
+    /// Close the current connection and open a new one to the agent.
+    ///
+    /// This requires the client to be created with [`Client::connect_with`].
+    pub async fn reconnect(&mut self) -> Result<(), AgentError> {
+        let Some(dialer) = &self.dialer else {
+            return Err(ProtoError::IO(io::Error::other(
+                "client was not created with a dialer and cannot reconnect",
+            ))
+            .into());
+        };
+        self.adapter = None;
+        self.extensions = None;
+        self.identities = None;
+        self.lock_suspected = false;
+        let stream = (dialer.0)().await?;
+        self.adapter = Some(Framed::new(stream, self.codec()));
+        Ok(())
+    }
+
+    /// Flush any pending requests and shut down the connection.
+    ///
+    /// Every request is flushed before its response is awaited, so
+    /// dropping a client between requests loses nothing. A client dropped
+    /// while a request is still being written, e.g. because the request
+    /// future was cancelled, simply closes the stream and the agent sees
+    /// a truncated message. Short-lived tools can call this method before
+    /// exiting to make sure the agent sees the connection end cleanly.
+    ///
+    /// The [request timeout](Self::set_request_timeout) also applies here.
+    pub async fn close(mut self) -> Result<(), AgentError> {
+        match self.adapter.take() {
+            Some(mut adapter) => with_timeout(self.timeout, adapter.close()).await?,
+            None => Ok(()),
+        }
+    }
+
+    /// Check whether the agent is currently locked.
+    ///
+    /// A locked agent reports no identities and rejects all other
+    /// requests except for unlocking. This method first requests the
+    /// list of identities: if any are returned the agent is not locked.
+    ///
+    /// An empty list is ambiguous, since an unlocked agent may simply
+    /// hold no keys. In that case the read-only [`Query`] extension is
+    /// requested: an agent answering it, or rejecting it with an
+    /// extension failure, is not locked. A plain failure is taken to mean
+    /// the agent is locked. Neither request changes the state of the
+    /// agent, so this is a guess: agents which hold no keys and do not
+    /// support the extension, like OpenSSH's `ssh-agent`, are reported
+    /// as locked as well.
+    pub async fn is_locked(&mut self) -> Result<bool, AgentError> {
+        match self.request(Request::RequestIdentities).await? {
+            Response::IdentitiesAnswer(identities) if !identities.is_empty() => return Ok(false),
+            Response::IdentitiesAnswer(_) => {}
+            response => return Err(unexpected(&response, "IdentitiesAnswer")),
+        }
+
+        let query = Request::Extension(Extension::new_message(Query)?);
+        match self.request(query).await? {
+            Response::Failure => Ok(true),
+            Response::ExtensionFailure
+            | Response::Success
+            | Response::SuccessWithData(_)
+            | Response::ExtensionResponse(_) => Ok(false),
+            response => Err(unexpected(&response, "ExtensionResponse")),
+        }
+    }
+
+    /// Lock the agent with `passphrase`.
+    ///
+    /// This is [`Session::lock`](crate::agent::Session::lock), which
+    /// takes a [`Zeroizing<String>`] to stay usable as a trait object,
+    /// accepting a plain `String` as well. Either is wiped from memory
+    /// once sent, as is the client's copy of the encoded request.
+    pub async fn lock(
+        &mut self,
+        passphrase: impl Into<Zeroizing<String>>,
+    ) -> Result<(), AgentError> {
+        self.expect_success(Request::lock(passphrase)).await
+    }
+
+    /// Unlock the agent with `passphrase`.
+    ///
+    /// Like [`Client::lock`], this is
+    /// [`Session::unlock`](crate::agent::Session::unlock) accepting a
+    /// plain `String` as well.
+    pub async fn unlock(
+        &mut self,
+        passphrase: impl Into<Zeroizing<String>>,
+    ) -> Result<(), AgentError> {
+        self.expect_success(Request::unlock(passphrase)).await
+    }
+
+    /// Lock the agent with the passphrase [derived](lock_passphrase) from `secret`.
+    ///
+    /// This allows locking the agent programmatically, as with
+    /// `ssh-add -x`, without storing a plaintext passphrase: the same
+    /// secret, e.g. the bytes of a key, unlocks the agent again with
+    /// [`Client::unlock_with_secret`].
+    pub async fn lock_with_secret(&mut self, secret: &[u8]) -> Result<(), AgentError> {
+        self.expect_success(Request::Lock(lock_passphrase(secret)))
+            .await
+    }
+
+    /// Unlock an agent locked with [`Client::lock_with_secret`].
+    pub async fn unlock_with_secret(&mut self, secret: &[u8]) -> Result<(), AgentError> {
+        self.expect_success(Request::Unlock(lock_passphrase(secret)))
+            .await
+    }
+
+    async fn expect_success(&mut self, message: Request) -> Result<(), AgentError> {
+        match self.request(message).await? {
+            Response::Success => Ok(()),
+            response => Err(failure(response, "Success")),
+        }
+    }
+
+    /// Send a single request and wait for its response.
+    async fn request(&mut self, message: Request) -> Result<Response, AgentError> {
+        #[cfg(feature = "tracing")]
+        let span = trace::RequestSpan::new(&message);
+
+        let exchange = self.request_all(vec![message]);
+        #[cfg(feature = "tracing")]
+        let exchange = tracing::Instrument::instrument(exchange, span.span());
+        let result = exchange.await.map(|mut responses| responses.remove(0));
+
+        #[cfg(feature = "tracing")]
+        span.finish(&result);
+        result
+    }
+
+    /// Send all messages before reading their responses.
+    ///
+    /// The agent answers requests in the order they are received, so
+    /// the responses are returned in the order of `messages`.
+    ///
+    /// The connection is owned by the exchange until it completes. If the
+    /// exchange is cancelled by dropping its future, the connection is
+    /// dropped with it, so a late response can never be read as the
+    /// answer to the next request.
+    ///
+    /// Writes failing with [`io::ErrorKind::Interrupted`] are retried.
+    /// Streams are not expected to fail with
+    /// [`io::ErrorKind::WouldBlock`], which Tokio turns into waiting for
+    /// the stream to become writable, so such errors are returned.
+    async fn exchange_all(
+        &mut self,
+        messages: Vec<Request>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Response>, AgentError> {
+        let Some(mut adapter) = self.adapter.take() else {
+            return Err(ProtoError::IO(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "connection closed after a cancelled, timed out or desynchronized request, or by the agent",
+            ))
+            .into());
+        };
+
+        let queries: Vec<bool> = messages.iter().map(is_query).collect();
+        let secret = messages.iter().any(carries_secret);
+        let drain = self.drain;
+        #[cfg(feature = "timing")]
+        let timing = self.timing.as_ref();
+        let result = async {
+            if drain {
+                discard_unsolicited(&mut adapter).await?;
+            }
+            #[cfg(feature = "timing")]
+            let start = {
+                adapter.codec_mut().take_first_byte();
+                std::time::Instant::now()
+            };
+            with_timeout(timeout, async {
+                // writes interrupted by a signal leave the unwritten part
+                // of the frames buffered, so they can simply be resumed
+                for message in messages {
+                    while let Err(e) = future::poll_fn(|cx| adapter.poll_ready_unpin(cx)).await {
+                        if !interrupted(&e) {
+                            return Err(e);
+                        }
+                    }
+                    adapter.start_send_unpin(message)?;
+                }
+                while let Err(e) = adapter.flush().await {
+                    if !interrupted(&e) {
+                        return Err(e);
+                    }
+                }
+                if secret {
+                    // everything was written, only the secrets are left
+                    codec::wipe(adapter.write_buffer_mut());
+                }
+                Ok(())
+            })
+            .await??;
+            #[cfg(feature = "timing")]
+            let write = start.elapsed();
+
+            let mut responses = Vec::with_capacity(queries.len());
+            for query in queries {
+                match with_timeout(timeout, adapter.try_next()).await?? {
+                    // only Pageant's query answers carry data after a
+                    // success message, anywhere else it is a desync
+                    Some(Answer(Response::SuccessWithData(data))) if !query => {
+                        return Err(ProtoError::Desync {
+                            length: 1 + data.0.len(),
+                            unread: data.0.len(),
+                        }
+                        .into());
+                    }
+                    Some(Answer(response)) => responses.push(response),
+                    None => return Err(AgentError::Disconnected),
+                }
+            }
+            #[cfg(feature = "timing")]
+            if let Some(TimingHook(hook)) = timing {
+                let complete = start.elapsed();
+                let first_byte = adapter
+                    .codec_mut()
+                    .take_first_byte()
+                    .map_or(complete, |at| at.saturating_duration_since(start));
+                hook(RequestTiming {
+                    write,
+                    first_byte,
+                    complete,
+                });
+            }
+            Ok(responses)
+        }
+        .await;
+
+        // After a timeout the response may still arrive later and would
+        // then be read as the answer to the next request, and after a
+        // desync the message boundaries are lost: drop the stream. A
+        // stream which has ended is of no further use either.
+        if !matches!(
+            result,
+            Err(AgentError::Timeout)
+                | Err(AgentError::Disconnected)
+                | Err(AgentError::Proto(
+                    ProtoError::Desync { .. } | ProtoError::Truncated { .. }
+                ))
+        ) {
+            self.adapter = Some(adapter);
+        }
+        result
+    }
+
+    /// Exchange messages, keeping track of the agent's state.
+    async fn request_all(&mut self, messages: Vec<Request>) -> Result<Vec<Response>, AgentError> {
+        let read_only = messages.iter().all(|message| {
+            matches!(
+                message,
+                Request::RequestIdentities | Request::SignRequest(_)
+            )
+        });
+        if !read_only {
+            // the request may change the identities
+            self.identities = None;
+        }
+        let hints: Vec<_> = messages.iter().map(LockHint::of).collect();
+        let responses = self.send_all(messages).await?;
+        for (hint, response) in hints.into_iter().zip(&responses) {
+            match (hint, response) {
+                (Some(LockHint::Identities), Response::IdentitiesAnswer(identities)) => {
+                    self.lock_suspected = identities.is_empty();
+                }
+                (Some(LockHint::Sign), Response::SignResponse(_))
+                | (Some(LockHint::Unlock), Response::Success) => {
+                    self.lock_suspected = false;
+                }
+                (Some(LockHint::Lock), Response::Success) => self.lock_suspected = true,
+                _ => {}
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Error for a sign request answered with `response`, reporting
+    /// failures as [`AgentError::Locked`] if the agent seems locked.
+    fn sign_failure(&self, response: Response) -> AgentError {
+        match response {
+            Response::Failure if self.lock_suspected => AgentError::Locked,
+            response => failure(response, "SignResponse"),
+        }
+    }
+
+    /// Exchange messages, after querying the agent's extensions if
+    /// enabled and they are not known for the connection yet.
+    ///
+    /// This runs for every attempt, so a connection opened by
+    /// reconnecting while sending is queried as well.
+    async fn exchange(
+        &mut self,
+        messages: Vec<Request>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Response>, AgentError> {
+        // a query among the messages is recorded by its sender
+        if self.prelude && self.extensions.is_none() && !messages.iter().any(is_query) {
+            if self.flavor == Some(AgentFlavor::GpgAgent) {
+                self.extensions = Some(vec![]);
+            } else {
+                let query = Request::Extension(Extension::new_message(Query)?);
+                let response = self.exchange_all(vec![query], timeout).await?.remove(0);
+                self.extensions = Some(extension_names(response)?);
+            }
+        }
+        self.exchange_all(messages, timeout).await
+    }
+
+    /// Exchange messages, retrying according to the retry policy or
+    /// reconnecting once if enabled and the connection broke.
+    async fn send_all(&mut self, messages: Vec<Request>) -> Result<Vec<Response>, AgentError> {
+        let idempotent = messages.iter().all(|message| {
+            matches!(
+                message,
+                Request::RequestIdentities | Request::SignRequest(_)
+            )
+        });
+        if idempotent && self.retry.max_attempts > 1 && self.dialer.is_some() {
+            return self.retry_all(messages).await;
+        }
+        if !self.auto_reconnect || self.dialer.is_none() {
+            return self.exchange(messages, self.timeout).await;
+        }
+
+        match self.exchange(messages.clone(), self.timeout).await {
+            Err(
+                AgentError::IO(_)
+                | AgentError::Disconnected
+                | AgentError::Proto(ProtoError::IO(_) | ProtoError::Truncated { .. }),
+            ) => {
+                self.reconnect().await?;
+                self.exchange(messages, self.timeout).await
+            }
+            result => result,
+        }
+    }
+
+    /// Exchange idempotent messages following the retry policy.
+    async fn retry_all(&mut self, messages: Vec<Request>) -> Result<Vec<Response>, AgentError> {
+        let RetryPolicy {
+            max_attempts,
+            timeout,
+            mut backoff,
+        } = self.retry;
+        let timeout = timeout.or(self.timeout);
+
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                if self.adapter.is_none() {
+                    self.reconnect().await?;
+                }
+                self.exchange(messages.clone(), timeout).await
+            }
+            .await;
+            match result {
+                Err(
+                    AgentError::Timeout
+                    | AgentError::IO(_)
+                    | AgentError::Disconnected
+                    | AgentError::Proto(ProtoError::IO(_) | ProtoError::Truncated { .. }),
+                ) if attempt < max_attempts => {
+                    log::debug!("Retrying agent request after attempt {attempt} failed");
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                    // the connection may be broken even if it is still open
+                    self.adapter = None;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Response as read by the client, before it is matched to its request.
+///
+/// Contents following `SSH_AGENT_SUCCESS` are kept, since Pageant
+/// answers the [`Query`] extension this way.
+#[derive(Debug)]
+struct Answer(Response);
+
+impl Decode for Answer {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Response::decode_with_data(reader).map(Self)
+    }
+}
+
+/// Request whose answer hints at whether the agent is locked.
+#[derive(Clone, Copy)]
+enum LockHint {
+    Identities,
+    Sign,
+    Lock,
+    Unlock,
+}
+
+impl LockHint {
+    fn of(request: &Request) -> Option<Self> {
+        match request {
+            Request::RequestIdentities => Some(Self::Identities),
+            Request::SignRequest(_) => Some(Self::Sign),
+            Request::Lock(_) => Some(Self::Lock),
+            Request::Unlock(_) => Some(Self::Unlock),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `request` is a [`Query`] extension request.
+fn is_query(request: &Request) -> bool {
+    matches!(request, Request::Extension(extension) if extension.name == Query::NAME)
+}
+
+/// Sign requests of a batch which are sent before reading any of their
+/// responses.
+///
+/// An agent answering every request before reading the next one stops
+/// reading once its responses fill the buffers of the connection, while
+/// the client is still writing requests. Few enough signatures always
+/// fit, so the batch is sent in parts of this many requests.
+const MAX_SIGNS_IN_FLIGHT: usize = 8;
+
+/// Whether `request` holds a private key, PIN or passphrase.
+fn carries_secret(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::AddIdentity(_)
+            | Request::AddIdConstrained(_)
+            | Request::AddSmartcardKey(_)
+            | Request::AddSmartcardKeyConstrained(_)
+            | Request::Lock(_)
+            | Request::Unlock(_)
+    )
+}
+
+/// Read and discard all data which is already waiting in `adapter`.
+async fn discard_unsolicited<Stream>(
+    adapter: &mut Framed<Stream, Codec<Answer, Request>>,
+) -> io::Result<()>
+where
+    Stream: AsyncRead + Unpin,
+{
+    // let the runtime notice data which arrived since the last request
+    tokio::task::yield_now().await;
+
+    let mut discarded = adapter.read_buffer().len();
+    adapter.read_buffer_mut().clear();
+    let mut buf = [0; 1024];
+    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+    loop {
+        let mut read = ReadBuf::new(&mut buf);
+        match Pin::new(adapter.get_mut()).poll_read(&mut cx, &mut read) {
+            Poll::Ready(Ok(())) if !read.filled().is_empty() => discarded += read.filled().len(),
+            Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+            Poll::Ready(Err(e)) => return Err(e),
+            // the end of the stream is reported by the next request
+            Poll::Ready(Ok(())) | Poll::Pending => break,
+        }
+    }
+    if discarded > 0 {
+        log::warn!("Discarded {discarded} bytes received from the agent outside of a response");
+    }
+    Ok(())
+}
+
+/// Whether `error` is an I/O operation interrupted by a signal, which
+/// should be retried.
+fn interrupted(error: &AgentError) -> bool {
+    match error {
+        AgentError::IO(e) | AgentError::Proto(ProtoError::IO(e)) => {
+            e.kind() == io::ErrorKind::Interrupted
+        }
+        _ => false,
     }
 }
 
+/// Await `future`, failing with [`AgentError::Timeout`] if it takes longer than `timeout`.
+async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    future: F,
+) -> Result<F::Output, AgentError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| AgentError::Timeout),
+        None => Ok(future.await),
+    }
+}
+
+/// Derive an agent lock passphrase from `secret`.
+///
+/// The passphrase is the lowercase hex encoding of the SHA-512 digest of
+/// the string `ssh-agent-lib lock passphrase`, a NUL byte and `secret`.
+/// The derivation is deterministic and will not change, so passphrases
+/// derived by other programs can be used with
+/// [`Client::unlock_with_secret`] as long as they follow it.
+///
+/// A single hash does not slow down guessing, so `secret` should be a
+/// high-entropy value such as key material rather than a passphrase
+/// chosen by a user. Those can be passed to `lock` directly.
+pub fn lock_passphrase(secret: &[u8]) -> Zeroizing<String> {
+    const CONTEXT: &[u8] = b"ssh-agent-lib lock passphrase\0";
+
+    use std::fmt::Write as _;
+
+    // keep copies of the secret and the digest from lingering in memory
+    let input = Zeroizing::new([CONTEXT, secret].concat());
+    let digest = Zeroizing::new(HashAlg::Sha512.digest(&input));
+    let mut passphrase = Zeroizing::new(String::with_capacity(2 * digest.len()));
+    for byte in digest.iter() {
+        let _ = write!(passphrase, "{byte:02x}");
+    }
+    passphrase
+}
+
+/// Open a Windows named pipe, waiting while all pipe instances are busy.
+#[cfg(windows)]
+pub async fn open_named_pipe(
+    pipe: impl AsRef<std::ffi::OsStr>,
+) -> io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    loop {
+        // https://docs.rs/windows-sys/latest/windows_sys/Win32/Foundation/constant.ERROR_PIPE_BUSY.html
+        const ERROR_PIPE_BUSY: u32 = 231u32;
+
+        // correct way to do it taken from
+        // https://docs.rs/tokio/latest/tokio/net/windows/named_pipe/struct.NamedPipeClient.html
+        match ClientOptions::new().open(pipe.as_ref()) {
+            Ok(client) => break Ok(client),
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => (),
+            Err(e) => Err(e)?,
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Connect to a running PuTTY Pageant agent.
+///
+/// The named pipe used by recent Pageant versions is tried first. If it
+/// is not available, requests are passed to the Pageant window through
+/// shared memory, which is supported by all versions.
+///
+/// The [flavor](Client::detect_flavor) of the client is set to Pageant.
+#[cfg(windows)]
+pub async fn connect_pageant() -> Result<Client<PageantStream>, AgentError> {
+    let pipe = match pageant::pipe_name() {
+        Ok(name) => open_named_pipe(name).await,
+        Err(e) => Err(e),
+    };
+    let stream = match pipe {
+        Ok(pipe) => PageantStream::pipe(pipe),
+        Err(e) => {
+            log::debug!("Pageant pipe not available ({e}), falling back to WM_COPYDATA");
+            PageantStream::copy_data()?
+        }
+    };
+    let mut client = Client::new(stream);
+    client.set_flavor(Some(AgentFlavor::Pageant));
+    Ok(client)
+}
+
+/// Connect to an agent listening on a vsock address.
+///
+/// Virtual machines reach services of their host and of each other
+/// through `AF_VSOCK` sockets, addressed by a context ID (CID) and a
+/// port. The CID names the machine: [`VMADDR_CID_HOST`](tokio_vsock::VMADDR_CID_HOST)
+/// (2) is the host as seen from a guest, while guests have CIDs of 3 and
+/// up assigned by the hypervisor. The port is chosen by the agent, which
+/// listens on it like on a TCP port.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use ssh_agent_lib::agent::Session;
+///
+/// // an agent on the host, listening on vsock port 5000
+/// let mut client =
+///     ssh_agent_lib::client::connect_vsock(tokio_vsock::VMADDR_CID_HOST, 5000).await?;
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+///
+/// This requires the `vsock` feature and is only available on Linux.
+#[cfg(all(target_os = "linux", feature = "vsock"))]
+pub async fn connect_vsock(
+    cid: u32,
+    port: u32,
+) -> Result<Client<tokio_vsock::VsockStream>, AgentError> {
+    let address = tokio_vsock::VsockAddr::new(cid, port);
+    Ok(Client::new(
+        tokio_vsock::VsockStream::connect(address).await?,
+    ))
+}
+
+/// Connect to an agent listening on an abstract Unix socket address.
+///
+/// Abstract addresses live in a namespace of their own instead of the
+/// file system, and are written with a leading NUL byte (often shown
+/// as `@`) which is not part of the `name`. They cannot be passed as a
+/// path, e.g. in a [`Binding::FilePath`](service_binding::Binding::FilePath):
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use ssh_agent_lib::agent::Session;
+///
+/// // an agent listening on "@ssh-agent"
+/// let mut client = ssh_agent_lib::client::connect_abstract("ssh-agent").await?;
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+///
+/// Abstract addresses are only available on Linux.
+#[cfg(target_os = "linux")]
+pub async fn connect_abstract(
+    name: impl AsRef<[u8]>,
+) -> Result<Client<tokio::net::UnixStream>, ConnectError> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    // the standard library connects to the address, blocking while the
+    // backlog of the listener is full
+    let stream =
+        tokio::task::spawn_blocking(move || std::os::unix::net::UnixStream::connect_addr(&address))
+            .await
+            .map_err(io::Error::other)??;
+    stream.set_nonblocking(true)?;
+    Ok(Client::new(tokio::net::UnixStream::from_std(stream)?))
+}
+
 /// Wrap a stream into an SSH agent client.
+///
+/// Failures are reported as a [`ConnectError`], telling a missing socket
+/// apart from an agent which is not running anymore:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use service_binding::{Binding, Stream};
+/// use ssh_agent_lib::{client::connect, error::ConnectError};
+///
+/// let binding = Binding::FilePath(std::env::var("SSH_AUTH_SOCK")?.into());
+/// let connected = match Stream::try_from(binding) {
+///     Ok(stream) => connect(stream).await,
+///     Err(e) => Err(ConnectError::from(e)),
+/// };
+/// let mut client = match connected {
+///     Ok(client) => client,
+///     Err(ConnectError::NotFound(_) | ConnectError::ConnectionRefused(_)) => {
+///         return Err("no agent found, is ssh-agent running?".into())
+///     }
+///     Err(e) => return Err(e.into()),
+/// };
+/// # Ok(()) }
+/// ```
+///
+/// `TCP_NODELAY` is enabled on TCP streams. Other socket options have to
+/// be set before connecting, see [`TcpOptions`].
 pub async fn connect(
     stream: service_binding::Stream,
-) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, Box<dyn std::error::Error>> {
+) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError> {
+    from_stream(stream, None).await
+}
+
+/// Connect to the agent named by the `SSH_AUTH_SOCK` environment variable.
+///
+/// This is how `ssh` and `ssh-add` find the agent. On Windows, where the
+/// variable is usually not set, the pipe of the OpenSSH agent
+/// (`\\.\pipe\openssh-ssh-agent`) is used instead. Elsewhere an unset or
+/// empty variable fails with [`ConnectError::NotConfigured`].
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut client = ssh_agent_lib::client::connect_env().await?;
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+pub async fn connect_env() -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError> {
+    connect_auth_sock(std::env::var_os("SSH_AUTH_SOCK")).await
+}
+
+/// Connect to the agent at `path`, the value of `SSH_AUTH_SOCK`.
+async fn connect_auth_sock(
+    path: Option<std::ffi::OsString>,
+) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError> {
+    let path = path.filter(|path| !path.is_empty());
+    #[cfg(windows)]
+    let path = path.or_else(|| Some(r"\\.\pipe\openssh-ssh-agent".into()));
+    let Some(path) = path else {
+        return Err(ConnectError::NotConfigured);
+    };
+    connect_path(path).await
+}
+
+/// Connect to the agent socket given explicitly, set in a configuration
+/// file, or named by `SSH_AUTH_SOCK`, in this order.
+///
+/// Tools which keep the agent socket in their settings can pass their
+/// configuration file as `config`. It is read as lines of `key value` or
+/// `key=value` pairs, with `#` starting a comment line. The first
+/// `IdentityAgent` setting (spelled in any case, as in `ssh_config`) or
+/// `SSH_AUTH_SOCK` setting names the socket:
+///
+/// ```text
+/// # written by the session manager
+/// IdentityAgent ~/.ssh/agent.sock
+/// ```
+///
+/// A leading `~/` is replaced with the home directory, and the value
+/// may be enclosed in double quotes. As in `ssh_config`, a value of
+/// `SSH_AUTH_SOCK` or `$SSH_AUTH_SOCK` defers to the environment and one
+/// of `none` fails with [`ConnectError::NotConfigured`]. A missing
+/// configuration file, or one without either setting, falls back to
+/// [`connect_env`].
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// // a socket given on the command line takes precedence
+/// let socket: Option<std::path::PathBuf> = std::env::args_os().nth(1).map(Into::into);
+/// let mut client =
+///     ssh_agent_lib::client::connect_configured(socket.as_deref(), "/etc/mytool.conf").await?;
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+pub async fn connect_configured(
+    explicit: Option<&std::path::Path>,
+    config: impl AsRef<std::path::Path>,
+) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError> {
+    if let Some(path) = explicit {
+        return connect_path(path.into()).await;
+    }
+    let Some(value) = configured_socket(config.as_ref())? else {
+        return connect_env().await;
+    };
+    match value.as_str() {
+        "none" => Err(ConnectError::NotConfigured),
+        "SSH_AUTH_SOCK" | "$SSH_AUTH_SOCK" => connect_env().await,
+        value => match (value.strip_prefix("~/"), std::env::var_os("HOME")) {
+            (Some(relative), Some(home)) => {
+                connect_path(std::path::Path::new(&home).join(relative).into()).await
+            }
+            _ => connect_path(value.into()).await,
+        },
+    }
+}
+
+/// The agent socket set in the configuration file `config`, if any.
+fn configured_socket(config: &std::path::Path) -> Result<Option<String>, ConnectError> {
+    let contents = match std::fs::read_to_string(config) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        // not to be mistaken for an error of the socket
+        Err(e) => return Err(ConnectError::Io(e)),
+    };
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once(|c: char| c == '=' || c.is_whitespace()) else {
+            continue;
+        };
+        if !key.eq_ignore_ascii_case("IdentityAgent") && key != "SSH_AUTH_SOCK" {
+            continue;
+        }
+        let value = value.trim_start().strip_prefix('=').unwrap_or(value).trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        return Ok(Some(value.to_owned()));
+    }
+    Ok(None)
+}
+
+/// Connect to the agent socket or, on Windows, named pipe at `path`.
+async fn connect_path(
+    path: std::ffi::OsString,
+) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError> {
+    #[cfg(unix)]
+    {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(Box::pin(Client::new(stream)))
+    }
+    #[cfg(windows)]
+    {
+        from_stream(service_binding::Stream::NamedPipe(path), None).await
+    }
+}
+
+/// Wrap a stream into an SSH agent client, limiting the time spent
+/// waiting for the agent.
+///
+/// The `timeout` bounds the time spent waiting for a busy named pipe
+/// and is then applied to every request sent through the returned client.
+/// See [`Client::set_request_timeout`] for details. Waiting too long for
+/// the pipe fails with an [`io::ErrorKind::TimedOut`] error.
+pub async fn connect_with_timeout(
+    stream: service_binding::Stream,
+    timeout: Duration,
+) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError> {
+    from_stream(stream, Some(timeout)).await
+}
+
+/// Connect to an agent through a custom dialer.
+///
+/// The `dial` function opens the stream to the agent, which can be any
+/// transport carrying the agent protocol, e.g. a connection through a
+/// SOCKS proxy or into another network namespace:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// // an agent socket forwarded to a local TCP port
+/// let mut client =
+///     ssh_agent_lib::client::connect_with(|| tokio::net::TcpStream::connect("127.0.0.1:3022"))
+///         .await?;
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+///
+/// Errors of `dial` are classified as a [`ConnectError`]. Use
+/// [`Client::connect_with`] to keep access to the client's settings,
+/// such as [automatic reconnection](Client::set_auto_reconnect).
+pub async fn connect_with<F, Fut, S>(
+    dial: F,
+) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    match Client::connect_with(dial).await {
+        Ok(client) => Ok(Box::pin(client)),
+        Err(AgentError::IO(e)) => Err(e.into()),
+        Err(e) => Err(ConnectError::Io(io::Error::other(e))),
+    }
+}
+
+async fn from_stream(
+    stream: service_binding::Stream,
+    timeout: Option<Duration>,
+) -> Result<std::pin::Pin<Box<dyn crate::agent::Session>>, ConnectError> {
+    fn client<S>(stream: S, timeout: Option<Duration>) -> Client<S>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut client = Client::new(stream);
+        client.set_request_timeout(timeout);
+        client
+    }
+
     match stream {
         #[cfg(unix)]
         service_binding::Stream::Unix(stream) => {
+            // streams connected by hand, e.g. to an abstract address,
+            // are still in blocking mode
+            stream.set_nonblocking(true)?;
             let stream = tokio::net::UnixStream::from_std(stream)?;
-            Ok(Box::pin(Client::new(stream)))
+            Ok(Box::pin(client(stream, timeout)))
         }
         service_binding::Stream::Tcp(stream) => {
             let stream = tokio::net::TcpStream::from_std(stream)?;
-            Ok(Box::pin(Client::new(stream)))
+            stream.set_nodelay(true)?;
+            Ok(Box::pin(client(stream, timeout)))
         }
         #[cfg(windows)]
         service_binding::Stream::NamedPipe(pipe) => {
-            use tokio::net::windows::named_pipe::ClientOptions;
-            let stream = loop {
-                // https://docs.rs/windows-sys/latest/windows_sys/Win32/Foundation/constant.ERROR_PIPE_BUSY.html
-                const ERROR_PIPE_BUSY: u32 = 231u32;
-
-                // correct way to do it taken from
-                // https://docs.rs/tokio/latest/tokio/net/windows/named_pipe/struct.NamedPipeClient.html
-                match ClientOptions::new().open(&pipe) {
-                    Ok(client) => break client,
-                    Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => (),
-                    Err(e) => Err(e)?,
-                }
-
-                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let stream = if let Some(timeout) = timeout {
+                tokio::time::timeout(timeout, open_named_pipe(&pipe))
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??
+            } else {
+                open_named_pipe(&pipe).await?
             };
-            Ok(Box::pin(Client::new(stream)))
+            Ok(Box::pin(client(stream, timeout)))
         }
         #[cfg(not(windows))]
-        service_binding::Stream::NamedPipe(_) => Err(ProtoError::IO(std::io::Error::other(
+        service_binding::Stream::NamedPipe(_) => Err(ConnectError::Io(io::Error::new(
+            io::ErrorKind::Unsupported,
             "Named pipes supported on Windows only",
-        ))
-        .into()),
+        ))),
+    }
+}
+
+/// Convert a response the client did not expect into an error.
+///
+/// Failures are reported as such, any other response as an
+/// [`AgentError::UnexpectedResponse`] to a request `expected` to be
+/// answered with the named response.
+pub(crate) fn failure(response: Response, expected: &'static str) -> AgentError {
+    match response {
+        Response::Failure => AgentError::AgentFailure {
+            code: SSH_AGENT_FAILURE,
+        },
+        Response::ExtensionFailure => AgentError::AgentFailure {
+            code: SSH_AGENT_EXTENSION_FAILURE,
+        },
+        response => unexpected(&response, expected),
+    }
+}
+
+/// Error for a `response` of another kind than `expected`.
+fn unexpected(response: &Response, expected: &'static str) -> AgentError {
+    let got = match response {
+        Response::Failure => "Failure",
+        Response::Success => "Success",
+        Response::SuccessWithData(_) => "SuccessWithData",
+        Response::IdentitiesAnswer(_) => "IdentitiesAnswer",
+        Response::SignResponse(_) => "SignResponse",
+        Response::ExtensionFailure => "ExtensionFailure",
+        Response::ExtensionResponse(_) => "ExtensionResponse",
+    };
+    AgentError::UnexpectedResponse { expected, got }
+}
+
+/// Extension names listed in the answer to a [`Query`] request.
+fn extension_names(response: Response) -> Result<Vec<String>, AgentError> {
+    Ok(QueryResponse::from_answer(&response)?
+        .ok_or_else(|| failure(response, "ExtensionResponse"))?
+        .extensions)
+}
+
+/// Check that `signature` was made with the algorithm requested by signing
+/// with `pubkey` and `flags`.
+pub(crate) fn check_algorithm(
+    pubkey: &KeyData,
+    flags: u32,
+    signature: Signature,
+) -> Result<Signature, AgentError> {
+    let requested = match pubkey.algorithm() {
+        // the SHA-256 flag takes precedence, as in OpenSSH
+        Algorithm::Rsa { .. } if flags & RSA_SHA2_256 != 0 => Algorithm::Rsa {
+            hash: Some(HashAlg::Sha256),
+        },
+        Algorithm::Rsa { .. } if flags & RSA_SHA2_512 != 0 => Algorithm::Rsa {
+            hash: Some(HashAlg::Sha512),
+        },
+        algorithm => algorithm,
+    };
+    if signature.algorithm() != requested {
+        return Err(AgentError::AlgorithmMismatch {
+            requested,
+            returned: signature.algorithm(),
+        });
     }
+    Ok(signature)
 }
 
 #[async_trait::async_trait]
 impl<Stream> crate::agent::Session for Client<Stream>
 where
-    Stream: fmt::Debug + AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+    Stream: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
 {
     async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
-        if let Response::IdentitiesAnswer(identities) =
-            self.handle(Request::RequestIdentities).await?
-        {
-            Ok(identities)
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+        if let Some((received, identities)) = &self.identities {
+            if received.elapsed() < self.identity_ttl {
+                return Ok(identities.clone());
+            }
+        }
+        match self.handle(Request::RequestIdentities).await? {
+            Response::IdentitiesAnswer(identities) => {
+                if !self.identity_ttl.is_zero() {
+                    self.identities = Some((std::time::Instant::now(), identities.clone()));
+                }
+                Ok(identities)
+            }
+            response => Err(failure(response, "IdentitiesAnswer")),
         }
     }
 
-    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
-        if let Response::SignResponse(response) = self.handle(Request::SignRequest(request)).await?
-        {
-            Ok(response)
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+    async fn sign(&mut self, mut request: SignRequest) -> Result<Signature, AgentError> {
+        self.check_key_presence(&request.pubkey).await?;
+        // Pageant rejects flags which do not apply to the key, while
+        // OpenSSH ignores them; only RSA keys have any
+        if self.flavor == Some(AgentFlavor::Pageant) && !matches!(request.pubkey, KeyData::Rsa(_)) {
+            request.flags = 0;
+        }
+        let (pubkey, flags) = (request.pubkey.clone(), request.flags);
+        match self.handle(Request::SignRequest(request)).await? {
+            Response::SignResponse(response) => check_algorithm(&pubkey, flags, response),
+            response => Err(self.sign_failure(response)),
+        }
+    }
+
+    async fn sign_batch(
+        &mut self,
+        key: &PublicKey,
+        datas: &[Vec<u8>],
+    ) -> Result<Vec<Signature>, AgentError> {
+        self.check_key_presence(key.key_data()).await?;
+        let mut signatures = Vec::with_capacity(datas.len());
+        for datas in datas.chunks(MAX_SIGNS_IN_FLIGHT) {
+            let requests: Vec<_> = datas
+                .iter()
+                .map(|data| SignRequest::new(key.key_data().clone(), data.clone()))
+                .collect();
+            let flags = requests[0].flags;
+            let requests = requests.into_iter().map(Request::SignRequest).collect();
+            for response in self.request_all(requests).await? {
+                signatures.push(match response {
+                    Response::SignResponse(signature) => {
+                        check_algorithm(key.key_data(), flags, signature)?
+                    }
+                    response => return Err(self.sign_failure(response)),
+                });
+            }
         }
+        Ok(signatures)
     }
 
     async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::AddIdentity(identity)).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+        match self.handle(Request::AddIdentity(identity)).await? {
+            Response::Success => Ok(()),
+            Response::Failure if self.flavor == Some(AgentFlavor::GpgAgent) => {
+                Err(AgentError::KeyNotAdded)
+            }
+            response => Err(failure(response, "Success")),
         }
     }
 
@@ -113,34 +1683,52 @@ where
         &mut self,
         identity: AddIdentityConstrained,
     ) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::AddIdConstrained(identity)).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+        // the private key is only copied if it may be sent again
+        let fallback = self
+            .drop_constraints
+            .then(|| (identity.identity.clone(), identity.constraints.clone()));
+        match (
+            self.handle(Request::AddIdConstrained(identity)).await?,
+            fallback,
+        ) {
+            (Response::Success, _) => Ok(()),
+            (Response::Failure, Some((identity, constraints))) => {
+                log::warn!(
+                    "Agent does not support constrained keys, adding key without {constraints:?}"
+                );
+                self.add_identity(identity).await
+            }
+            (Response::Failure, None) if self.flavor == Some(AgentFlavor::GpgAgent) => {
+                Err(AgentError::KeyNotAdded)
+            }
+            (response, _) => Err(failure(response, "Success")),
         }
     }
 
     async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::RemoveIdentity(identity)).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+        match self.handle(Request::RemoveIdentity(identity)).await? {
+            Response::Success => Ok(()),
+            response => Err(failure(response, "Success")),
         }
     }
 
     async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::RemoveAllIdentities).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+        match self.handle(Request::RemoveAllIdentities).await? {
+            Response::Success => Ok(()),
+            response => Err(failure(response, "Success")),
         }
     }
 
     async fn add_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::AddSmartcardKey(key)).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+        if !key.options.is_empty() {
+            // only the constrained request can carry the options
+            return self
+                .add_smartcard_key_constrained(AddSmartcardKeyConstrained::new(key, vec![]))
+                .await;
+        }
+        match self.handle(Request::AddSmartcardKey(key)).await? {
+            Response::Success => Ok(()),
+            response => Err(failure(response, "Success")),
         }
     }
 
@@ -148,54 +1736,1186 @@ where
         &mut self,
         key: AddSmartcardKeyConstrained,
     ) -> Result<(), AgentError> {
-        if let Response::Success = self
+        match self
             .handle(Request::AddSmartcardKeyConstrained(key))
             .await?
         {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+            Response::Success => Ok(()),
+            response => Err(failure(response, "Success")),
         }
     }
 
     async fn remove_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::RemoveSmartcardKey(key)).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
+        match self.handle(Request::RemoveSmartcardKey(key)).await? {
+            Response::Success => Ok(()),
+            response => Err(failure(response, "Success")),
         }
     }
 
-    async fn lock(&mut self, key: String) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::Lock(key)).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
-        }
+    async fn lock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        Client::lock(self, key).await
     }
 
-    async fn unlock(&mut self, key: String) -> Result<(), AgentError> {
-        if let Response::Success = self.handle(Request::Unlock(key)).await? {
-            Ok(())
-        } else {
-            Err(ProtoError::UnexpectedResponse.into())
-        }
+    async fn unlock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        Client::unlock(self, key).await
     }
 
     async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
         match self.handle(Request::Extension(extension)).await? {
             Response::Success => Ok(None),
             Response::ExtensionResponse(response) => Ok(Some(response)),
-            _ => Err(ProtoError::UnexpectedResponse.into()),
+            response => Err(failure(response, "ExtensionResponse")),
         }
     }
 
+    async fn query_extensions(&mut self) -> Result<Vec<String>, AgentError> {
+        self.extensions().await
+    }
+
     async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
-        self.adapter.send(message).await?;
-        if let Some(response) = self.adapter.try_next().await? {
-            Ok(response)
-        } else {
-            Err(ProtoError::IO(std::io::Error::other("server disconnected")).into())
+        self.request(message).await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::Mutex;
+
+    use ssh_key::{public::RsaPublicKey, Mpint};
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::agent::Session;
+
+    /// Spawn an agent answering requests with the given responses, in order.
+    fn scripted_agent(responses: Vec<Response>) -> io::Result<tokio::net::UnixStream> {
+        let (client, agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            for response in responses {
+                if !matches!(adapter.try_next().await, Ok(Some(_))) {
+                    break;
+                }
+                if adapter.send(response).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(client)
+    }
+
+    /// Dialer handing out `streams` in order, failing once they run out.
+    fn scripted_dialer(
+        mut streams: Vec<tokio::net::UnixStream>,
+    ) -> impl Fn() -> future::Ready<io::Result<tokio::net::UnixStream>> + Send + Sync + 'static
+    {
+        streams.reverse();
+        let streams = Mutex::new(streams);
+        move || {
+            let stream = streams.lock().unwrap().pop();
+            future::ready(stream.ok_or_else(|| io::Error::other("no more streams")))
         }
     }
+
+    #[tokio::test]
+    async fn request_timeout_closes_connection() -> TestResult {
+        // the other end of the socket pair never answers
+        let (stream, _agent) = tokio::net::UnixStream::pair()?;
+        let mut client = Client::new(stream);
+        client.set_request_timeout(Some(Duration::from_millis(10)));
+
+        let result = client.request_identities().await;
+        assert!(matches!(result, Err(AgentError::Timeout)));
+
+        let result = client.request_identities().await;
+        assert!(matches!(result, Err(AgentError::Proto(ProtoError::IO(_)))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auto_reconnect_retries_once() -> TestResult {
+        let (first, agent) = tokio::net::UnixStream::pair()?;
+        // the first connection is dropped by the agent immediately
+        drop(agent);
+
+        let (second, agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            if let Ok(Some(Request::RequestIdentities)) = adapter.try_next().await {
+                let _ = adapter.send(Response::IdentitiesAnswer(vec![])).await;
+            }
+        });
+
+        let mut client = Client::connect_with(scripted_dialer(vec![first, second])).await?;
+
+        // reconnection is explicit-only by default
+        assert!(client.request_identities().await.is_err());
+
+        client.set_auto_reconnect(true);
+        assert_eq!(client.request_identities().await?, vec![]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn agent_closing_the_connection_is_reported() -> TestResult {
+        // the agent closes the connection after reading the request
+        let (stream, agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            adapter.try_next().await
+        });
+        let mut client = Client::new(stream);
+        let error = client.request_identities().await.unwrap_err();
+        assert!(matches!(error, AgentError::Disconnected));
+
+        // the connection is not used again
+        let error = client.request_identities().await.unwrap_err();
+        assert!(
+            matches!(error, AgentError::Proto(ProtoError::IO(e)) if e.kind() == io::ErrorKind::NotConnected)
+        );
+
+        // the agent closes the connection in the middle of its answer
+        let (stream, mut agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut request = [0; 5];
+            agent.read_exact(&mut request).await?;
+            agent.write_all(&[0, 0, 0, 5, 12, 0]).await
+        });
+        let mut client = Client::new(stream);
+        let error = client.request_identities().await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::Proto(ProtoError::Truncated {
+                received: 6,
+                needed: 3,
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_policy_applies_to_idempotent_requests() -> TestResult {
+        // every first connection never answers, every second one does
+        let (silent, _first) = tokio::net::UnixStream::pair()?;
+        let (silent_too, _second) = tokio::net::UnixStream::pair()?;
+        let mut client = Client::connect_with(scripted_dialer(vec![
+            silent,
+            scripted_agent(vec![Response::IdentitiesAnswer(vec![])])?,
+            silent_too,
+            scripted_agent(vec![Response::Success])?,
+        ]))
+        .await?;
+        client.set_retry_policy(
+            RetryPolicy::default()
+                .max_attempts(2)
+                .timeout(Some(Duration::from_millis(10)))
+                .backoff(Duration::ZERO),
+        );
+
+        assert_eq!(client.request_identities().await?, vec![]);
+
+        // state changing requests are sent only once
+        client.reconnect().await?;
+        client.set_request_timeout(Some(Duration::from_millis(10)));
+        let error = client.lock("passphrase".to_owned()).await.unwrap_err();
+        assert!(matches!(error, AgentError::Timeout));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ping_sends_a_query() -> TestResult {
+        let (stream, agent) = tokio::net::UnixStream::pair()?;
+        let agent = tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            let mut names = vec![];
+            for response in [Response::Failure, Response::ExtensionFailure] {
+                let Some(Request::Extension(extension)) = adapter.try_next().await? else {
+                    panic!("expected an extension request");
+                };
+                names.push(extension.name);
+                adapter.send(response).await?;
+            }
+            Ok::<_, AgentError>(names)
+        });
+
+        // agents without the extension are alive as well
+        let mut client = Client::new(stream);
+        client.ping().await?;
+        client.ping().await?;
+        assert_eq!(agent.await??, [Query::NAME, Query::NAME]);
+
+        // the agent is gone
+        assert!(client.ping().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unsolicited_data_is_discarded() -> TestResult {
+        use tokio::io::AsyncWriteExt;
+
+        for drain in [false, true] {
+            let (stream, mut agent) = tokio::net::UnixStream::pair()?;
+            // a stray success message and half of another frame
+            agent.write_all(&[0, 0, 0, 1, 6, 0, 0]).await?;
+            let agent = tokio::spawn(async move {
+                let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+                adapter.try_next().await?;
+                adapter.send(Response::IdentitiesAnswer(vec![])).await?;
+                Ok::<_, AgentError>(adapter)
+            });
+
+            let mut client = Client::new(stream);
+            client.set_drain_unsolicited(drain);
+            let result = client.request_identities().await;
+            if drain {
+                assert!(result?.is_empty());
+            } else {
+                assert!(matches!(
+                    result,
+                    Err(AgentError::UnexpectedResponse { got: "Success", .. })
+                ));
+            }
+            agent.await??;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extensions_are_queried_on_connect() -> TestResult {
+        let (stream, agent) = tokio::net::UnixStream::pair()?;
+        let agent = tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            let mut requests = vec![];
+            let answer = QueryResponse {
+                extensions: vec!["session-bind@openssh.com".into()],
+            };
+            for response in [
+                Response::ExtensionResponse(Extension::new_message(answer)?),
+                Response::IdentitiesAnswer(vec![]),
+                Response::IdentitiesAnswer(vec![]),
+            ] {
+                let Some(request) = adapter.try_next().await? else {
+                    break;
+                };
+                requests.push(request);
+                adapter.send(response).await?;
+            }
+            Ok::<_, AgentError>(requests)
+        });
+
+        let mut client = Client::new(stream);
+        client.set_query_on_connect(true);
+        assert_eq!(client.capabilities(), None);
+        client.request_identities().await?;
+        assert_eq!(
+            client.capabilities(),
+            Some(&["session-bind@openssh.com".to_owned()][..])
+        );
+        // answered from the recorded list
+        assert_eq!(
+            client.query_extensions().await?,
+            ["session-bind@openssh.com"]
+        );
+        assert_eq!(client.detect_flavor().await?, AgentFlavor::Unknown);
+        client.request_identities().await?;
+        drop(client);
+
+        let requests = agent.await??;
+        assert!(
+            matches!(&requests[0], Request::Extension(extension) if extension.name == Query::NAME)
+        );
+        assert_eq!(
+            requests[1..],
+            [Request::RequestIdentities, Request::RequestIdentities]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extensions_are_queried_after_reconnecting() -> TestResult {
+        let answer = QueryResponse {
+            extensions: vec!["session-bind@openssh.com".into()],
+        };
+        let (dead, _) = tokio::net::UnixStream::pair()?;
+        let mut client = Client::connect_with(scripted_dialer(vec![
+            dead,
+            scripted_agent(vec![
+                Response::ExtensionResponse(Extension::new_message(answer)?),
+                Response::IdentitiesAnswer(vec![]),
+            ])?,
+        ]))
+        .await?;
+        client.set_auto_reconnect(true);
+        client.set_query_on_connect(true);
+
+        // the query fails on the first connection and is sent on the next
+        assert!(client.request_identities().await?.is_empty());
+        assert_eq!(
+            client.capabilities(),
+            Some(&["session-bind@openssh.com".to_owned()][..])
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "timing")]
+    #[tokio::test]
+    async fn requests_are_timed() -> TestResult {
+        use std::sync::Arc;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (stream, mut agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(async move {
+            let mut request = [0; 5];
+            agent.read_exact(&mut request).await?;
+            // a success message, written in two parts
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            agent.write_all(&[0, 0]).await?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            agent.write_all(&[0, 1, 6]).await?;
+            Ok::<_, io::Error>(agent)
+        });
+
+        let timings = Arc::new(Mutex::new(vec![]));
+        let mut client = Client::new(stream).with_timing({
+            let timings = Arc::clone(&timings);
+            move |timing| timings.lock().expect("not poisoned").push(timing)
+        });
+        client.remove_all_identities().await?;
+
+        let timings = timings.lock().expect("not poisoned");
+        let [timing] = timings[..] else {
+            panic!("expected one timing, got {timings:?}");
+        };
+        assert!(timing.write <= timing.first_byte);
+        assert!(timing.first_byte >= Duration::from_millis(20));
+        assert!(timing.complete >= timing.first_byte);
+        assert!(timing.complete >= Duration::from_millis(40));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_failures_are_guessed_to_be_locks() -> TestResult {
+        let key = KeyData::Ed25519(ssh_key::public::Ed25519PublicKey([0; 32]));
+        let request = SignRequest::new(key, b"data".to_vec());
+        let mut client = Client::new(scripted_agent(vec![
+            Response::Failure,
+            Response::IdentitiesAnswer(vec![]),
+            Response::Failure,
+            Response::Success,
+            Response::Failure,
+        ])?);
+
+        let error = client.sign(request.clone()).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_FAILURE
+            }
+        ));
+        assert!(client.request_identities().await?.is_empty());
+        let error = client.sign(request.clone()).await.unwrap_err();
+        assert!(matches!(error, AgentError::Locked));
+
+        client.unlock("secret".to_owned()).await?;
+        let error = client.sign(request.clone()).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_FAILURE
+            }
+        ));
+
+        // the guess is forgotten with the connection
+        let mut client = Client::connect_with(scripted_dialer(vec![
+            scripted_agent(vec![Response::IdentitiesAnswer(vec![])])?,
+            scripted_agent(vec![Response::Failure])?,
+        ]))
+        .await?;
+        assert!(client.request_identities().await?.is_empty());
+        client.reconnect().await?;
+        let error = client.sign(request).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_FAILURE
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lock_detection() -> TestResult {
+        let mut client = Client::new(scripted_agent(vec![
+            Response::IdentitiesAnswer(vec![]),
+            Response::Failure,
+        ])?);
+        assert!(client.is_locked().await?);
+
+        // an empty but unlocked agent
+        let mut client = Client::new(scripted_agent(vec![
+            Response::IdentitiesAnswer(vec![]),
+            Response::ExtensionFailure,
+        ])?);
+        assert!(!client.is_locked().await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_batch_pipelines_requests() -> TestResult {
+        use ssh_key::{public::Ed25519PublicKey, Algorithm};
+
+        let (client, agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            // every request is read before the first response is sent
+            let mut requests = vec![];
+            for _ in 0..3 {
+                if let Ok(Some(Request::SignRequest(request))) = adapter.try_next().await {
+                    requests.push(request);
+                }
+            }
+            for request in requests {
+                let signature = Signature::new(Algorithm::Ed25519, request.data.repeat(64))
+                    .expect("valid signature");
+                adapter.feed(Response::SignResponse(signature)).await?;
+            }
+            adapter.flush().await
+        });
+
+        let mut client = Client::new(client);
+        client.set_request_timeout(Some(Duration::from_secs(5)));
+        let key = PublicKey::from(KeyData::Ed25519(Ed25519PublicKey([0; 32])));
+        let signatures = client
+            .sign_batch(&key, &[vec![1], vec![2], vec![3]])
+            .await?;
+
+        let bytes: Vec<u8> = signatures.iter().map(|s| s.as_bytes()[0]).collect();
+        assert_eq!(bytes, vec![1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_batch_reads_responses_while_sending() -> TestResult {
+        use ssh_key::{public::RsaPublicKey, Mpint};
+
+        // an agent answering every request as soon as it was read, with
+        // responses which together fill the buffers of the socket
+        let (client, agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            while let Ok(Some(Request::SignRequest(_))) = adapter.try_next().await {
+                let algorithm = Algorithm::Rsa {
+                    hash: Some(HashAlg::Sha512),
+                };
+                let signature = Signature::new(algorithm, vec![0; 8192]).expect("valid signature");
+                adapter.send(Response::SignResponse(signature)).await?;
+            }
+            Ok::<_, AgentError>(())
+        });
+
+        let mut client = Client::new(client);
+        client.set_request_timeout(Some(Duration::from_secs(10)));
+        let key = PublicKey::from(KeyData::Rsa(RsaPublicKey {
+            e: Mpint::from_bytes(&[1, 0, 1]).map_err(ProtoError::from)?,
+            n: Mpint::from_bytes(&[0, 0xc5, 0xa3, 0xb2, 0xf1]).map_err(ProtoError::from)?,
+        }));
+        let datas = vec![vec![0; 64 * 1024]; 64];
+        assert_eq!(client.sign_batch(&key, &datas).await?.len(), 64);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failures_are_distinguished() -> TestResult {
+        let mut client = Client::new(scripted_agent(vec![
+            Response::Failure,
+            Response::ExtensionFailure,
+        ])?);
+
+        let error = client.lock("passphrase".to_owned()).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_FAILURE
+            }
+        ));
+
+        let extension = Extension {
+            name: "unknown@example.com".into(),
+            details: vec![].into(),
+        };
+        let error = client.extension(extension).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_EXTENSION_FAILURE
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unexpected_responses_are_named() -> TestResult {
+        let mut client = Client::new(scripted_agent(vec![
+            Response::IdentitiesAnswer(vec![]),
+            Response::Success,
+        ])?);
+
+        let key = KeyData::Ed25519(ssh_key::public::Ed25519PublicKey([1; 32]));
+        let error = client
+            .sign(SignRequest::new(key, b"data".to_vec()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::UnexpectedResponse {
+                expected: "SignResponse",
+                got: "IdentitiesAnswer",
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Agent: Expected SignResponse response, but got IdentitiesAnswer"
+        );
+
+        let error = client.request_identities().await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::UnexpectedResponse {
+                expected: "IdentitiesAnswer",
+                got: "Success",
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn algorithm_mismatch_is_detected() -> TestResult {
+        let signature = Signature::new(
+            Algorithm::Rsa {
+                hash: Some(HashAlg::Sha256),
+            },
+            vec![0; 256],
+        )?;
+        let mut client = Client::new(scripted_agent(vec![
+            Response::SignResponse(signature.clone()),
+            Response::SignResponse(signature),
+        ])?);
+
+        let key = KeyData::Rsa(RsaPublicKey {
+            e: Mpint::from_positive_bytes(&[1, 0, 1])?,
+            n: Mpint::from_positive_bytes(&[0xff; 128])?,
+        });
+        let mut request = SignRequest::new(key, b"data".to_vec());
+        request.flags = RSA_SHA2_256;
+        client.sign(request.clone()).await?;
+
+        request.flags = RSA_SHA2_512;
+        let error = client.sign(request).await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AlgorithmMismatch {
+                requested: Algorithm::Rsa {
+                    hash: Some(HashAlg::Sha512)
+                },
+                returned: Algorithm::Rsa {
+                    hash: Some(HashAlg::Sha256)
+                },
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_keys_are_reported() -> TestResult {
+        use ssh_key::public::Ed25519PublicKey;
+
+        let held = KeyData::Ed25519(Ed25519PublicKey([1; 32]));
+        let missing = KeyData::Ed25519(Ed25519PublicKey([2; 32]));
+        let identities = vec![Identity {
+            pubkey: held.clone(),
+            comment: b"held".to_vec(),
+        }];
+        let signature = Signature::new(Algorithm::Ed25519, vec![0; 64])?;
+        let mut client = Client::new(scripted_agent(vec![
+            Response::IdentitiesAnswer(identities.clone()),
+            Response::SignResponse(signature.clone()),
+            Response::IdentitiesAnswer(identities),
+            // answers the request sent after the missing key was reported
+            Response::Success,
+        ])?);
+        client.set_check_key_presence(true);
+
+        let request = SignRequest::new(held, b"data".to_vec());
+        assert_eq!(client.sign(request).await?, signature);
+
+        let request = SignRequest::new(missing, b"data".to_vec());
+        let error = client.sign(request).await.unwrap_err();
+        assert!(matches!(error, AgentError::KeyNotFound));
+        client.remove_all_identities().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn constraints_are_only_dropped_when_enabled() -> TestResult {
+        use crate::proto::{AddIdentity, Credential, KeyConstraint};
+
+        let key = ssh_key::PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)?;
+        let identity = AddIdentityConstrained {
+            identity: AddIdentity {
+                credential: Credential::Key {
+                    privkey: key.key_data().clone(),
+                    comment: "constrained".into(),
+                },
+            },
+            constraints: vec![KeyConstraint::Confirm],
+        };
+
+        let mut client = Client::new(scripted_agent(vec![Response::Failure])?);
+        let error = client
+            .add_identity_constrained(identity.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::AgentFailure {
+                code: SSH_AGENT_FAILURE
+            }
+        ));
+
+        let (stream, agent) = tokio::net::UnixStream::pair()?;
+        let requests = tokio::spawn(async move {
+            let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+            let mut requests = vec![];
+            for response in [Response::Failure, Response::Success] {
+                requests.push(adapter.try_next().await?);
+                adapter.send(response).await?;
+            }
+            Ok::<_, AgentError>(requests)
+        });
+        let mut client = Client::new(stream);
+        client.set_drop_unsupported_constraints(true);
+        client.add_identity_constrained(identity.clone()).await?;
+        assert_eq!(
+            requests.await??,
+            [
+                Some(Request::AddIdConstrained(identity.clone())),
+                Some(Request::AddIdentity(identity.identity))
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gpg_agent_quirks_are_tolerated() -> TestResult {
+        use crate::proto::{AddIdentity, Credential, KeyConstraint};
+
+        let key = ssh_key::PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)?;
+        let identity = AddIdentity {
+            credential: Credential::Key {
+                privkey: key.key_data().clone(),
+                comment: "gpg".into(),
+            },
+        };
+        let constrained = AddIdentityConstrained {
+            identity: identity.clone(),
+            constraints: vec![KeyConstraint::Confirm],
+        };
+
+        let mut client = Client::new(scripted_agent(vec![Response::Failure])?);
+        assert!(client.add_identity(identity.clone()).await.is_err());
+
+        let mut client = Client::new(scripted_agent(vec![
+            Response::Failure,
+            Response::Failure,
+            Response::IdentitiesAnswer(vec![]),
+        ])?);
+        client.set_flavor(Some(AgentFlavor::GpgAgent));
+        client.set_query_on_connect(true);
+        let error = client.add_identity(identity).await.unwrap_err();
+        assert!(matches!(error, AgentError::KeyNotAdded));
+        let error = client
+            .add_identity_constrained(constrained)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AgentError::KeyNotAdded));
+        // answered without sending the query
+        assert!(client.query_extensions().await?.is_empty());
+        assert!(client.request_identities().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extension_payloads_are_accessible() -> TestResult {
+        let request = Extension {
+            name: "text@example.com".into(),
+            details: vec![].into(),
+        };
+        let mut client = Client::new(scripted_agent(vec![Response::ExtensionResponse(
+            Extension {
+                name: "text@example.com".into(),
+                details: b"caf\xc3\xa9 \xff".to_vec().into(),
+            },
+        )])?);
+
+        let response = client.extension(request).await?.unwrap();
+        assert_eq!(response.as_bytes(), b"caf\xc3\xa9 \xff");
+        assert_eq!(response.to_string_lossy(), "caf\u{e9} \u{fffd}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn identities_are_cached() -> TestResult {
+        let identity = Identity {
+            pubkey: KeyData::Ed25519(ssh_key::public::Ed25519PublicKey([0; 32])),
+            comment: "cached".into(),
+        };
+        let mut client = Client::new(scripted_agent(vec![
+            Response::IdentitiesAnswer(vec![identity.clone()]),
+            Response::Success,
+            Response::IdentitiesAnswer(vec![]),
+            Response::IdentitiesAnswer(vec![identity.clone()]),
+        ])?);
+        client.enable_identity_cache(Duration::from_secs(60));
+        let listing = vec![identity.clone()];
+        assert_eq!(client.request_identities().await?, listing);
+        assert_eq!(client.request_identities().await?, listing);
+
+        // removing keys discards the cached answer
+        client.remove_all_identities().await?;
+        assert!(client.request_identities().await?.is_empty());
+
+        client.enable_identity_cache(Duration::ZERO);
+        assert_eq!(client.request_identities().await?, listing);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flavors_are_detected() -> TestResult {
+        let query = |extensions: &[&str]| -> Result<Response, ProtoError> {
+            let details = QueryResponse {
+                extensions: extensions.iter().map(|name| name.to_string()).collect(),
+            };
+            Ok(Response::ExtensionResponse(Extension::new_message(
+                details,
+            )?))
+        };
+        let identities = |comment: &[u8]| {
+            Response::IdentitiesAnswer(vec![Identity {
+                pubkey: KeyData::Ed25519(ssh_key::public::Ed25519PublicKey([0; 32])),
+                comment: comment.to_vec(),
+            }])
+        };
+
+        // Pageant lists the names right after `SSH_AGENT_SUCCESS`
+        let mut names = vec![];
+        for name in [
+            "add-ppk@putty.projects.tartarus.org",
+            "list-extended@putty.projects.tartarus.org",
+        ] {
+            ssh_encoding::Encode::encode(&name, &mut names)?;
+        }
+        let pageant_query = Response::SuccessWithData(names.into());
+
+        let cases = [
+            (
+                vec![query(&[
+                    "query",
+                    "list-extended@putty.projects.tartarus.org",
+                ])?],
+                AgentFlavor::Pageant,
+            ),
+            (vec![pageant_query], AgentFlavor::Pageant),
+            (vec![query(&["query"])?], AgentFlavor::Unknown),
+            (
+                vec![Response::Failure, identities(b"cardno:000F12345678")],
+                AgentFlavor::GpgAgent,
+            ),
+            (
+                vec![Response::Failure, identities(b"user@host")],
+                AgentFlavor::OpenSsh,
+            ),
+        ];
+        for (responses, flavor) in cases {
+            let mut client = Client::new(scripted_agent(responses)?);
+            assert_eq!(client.detect_flavor().await?, flavor);
+            // the result is kept, the agent would not answer again
+            assert_eq!(client.detect_flavor().await?, flavor);
+        }
+
+        let mut client = Client::new(scripted_agent(vec![])?);
+        client.set_flavor(Some(AgentFlavor::GpgAgent));
+        assert_eq!(client.detect_flavor().await?, AgentFlavor::GpgAgent);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_writes_are_resumed() -> TestResult {
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        use tokio::io::ReadBuf;
+
+        /// Stream failing every other write with `Interrupted`, after
+        /// writing a single byte on the others.
+        struct Interrupting {
+            inner: tokio::net::UnixStream,
+            interrupt: bool,
+        }
+
+        impl AsyncRead for Interrupting {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_read(cx, buf)
+            }
+        }
+
+        impl AsyncWrite for Interrupting {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                self.interrupt = !self.interrupt;
+                if self.interrupt {
+                    return Poll::Ready(Err(io::ErrorKind::Interrupted.into()));
+                }
+                Pin::new(&mut self.inner).poll_write(cx, &buf[..1])
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_shutdown(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.inner).poll_shutdown(cx)
+            }
+        }
+
+        let mut client = Client::new(Interrupting {
+            inner: scripted_agent(vec![Response::Success])?,
+            interrupt: false,
+        });
+        client.lock("passphrase".to_owned()).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn desync_closes_connection() -> TestResult {
+        use tokio::io::AsyncWriteExt;
+
+        let (stream, mut agent) = tokio::net::UnixStream::pair()?;
+        // a success response claiming to be longer than it is, followed by
+        // a byte which must not be read as the start of the next response
+        agent.write_all(&[0, 0, 0, 2, 6, 0]).await?;
+        agent.write_all(&[0, 0, 0, 1, 6]).await?;
+        let mut client = Client::new(stream);
+
+        let error = client.remove_all_identities().await.unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::Proto(ProtoError::Desync {
+                length: 2,
+                unread: 1
+            })
+        ));
+
+        let error = client.remove_all_identities().await.unwrap_err();
+        assert!(
+            matches!(error, AgentError::Proto(ProtoError::IO(e)) if e.kind() == io::ErrorKind::NotConnected)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_shuts_down_the_stream() -> TestResult {
+        use tokio::io::AsyncReadExt;
+
+        let (stream, mut agent) = tokio::net::UnixStream::pair()?;
+        Client::new(stream).close().await?;
+
+        // the agent sees the end of the stream
+        let mut buffer = vec![];
+        assert_eq!(agent.read_to_end(&mut buffer).await?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_with_custom_dialer() -> TestResult {
+        let identity = Identity {
+            pubkey: KeyData::Ed25519(ssh_key::public::Ed25519PublicKey([0; 32])),
+            comment: "tunneled".into(),
+        };
+        let stream = Mutex::new(Some(scripted_agent(vec![Response::IdentitiesAnswer(
+            vec![identity.clone()],
+        )])?));
+
+        let mut client = connect_with(move || {
+            let stream = stream.lock().unwrap().take();
+            async move { stream.ok_or_else(|| io::Error::other("already dialed")) }
+        })
+        .await?;
+        assert_eq!(client.request_identities().await?, [identity]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_errors_are_classified() -> TestResult {
+        use crate::error::ConnectError;
+
+        let dir = tempfile::tempdir()?;
+        let missing = dir.path().join("missing");
+        let stale = dir.path().join("stale");
+        crate::test_util::stale_socket(&stale)?;
+
+        let dial = |path: std::path::PathBuf| {
+            connect_with(move || tokio::net::UnixStream::connect(path.clone()))
+        };
+        let missing = dial(missing).await;
+        let stale = dial(stale).await;
+
+        assert!(matches!(missing, Err(ConnectError::NotFound(_))));
+        assert!(matches!(stale, Err(ConnectError::ConnectionRefused(_))));
+        assert!(matches!(
+            ConnectError::from(io::Error::from(io::ErrorKind::PermissionDenied)),
+            ConnectError::PermissionDenied(_)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_env_uses_ssh_auth_sock() -> TestResult {
+        use crate::{agent::Agent, error::ConnectError};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("agent.sock");
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let agent = tokio::spawn(crate::keyring::KeyRing::new().listen(listener));
+
+        let mut client = connect_auth_sock(Some(path.into())).await?;
+        assert!(client.request_identities().await?.is_empty());
+        assert!(matches!(
+            connect_auth_sock(None).await,
+            Err(ConnectError::NotConfigured)
+        ));
+        assert!(matches!(
+            connect_auth_sock(Some("".into())).await,
+            Err(ConnectError::NotConfigured)
+        ));
+        agent.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_configured_reads_the_socket_from_a_file() -> TestResult {
+        use crate::{agent::Agent, error::ConnectError};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("agent.sock");
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let agent = tokio::spawn(crate::keyring::KeyRing::new().listen(listener));
+
+        let config = dir.path().join("tool.conf");
+        std::fs::write(
+            &config,
+            format!(
+                "# agent settings\nForwardAgent yes\nidentityagent = \"{}\"\nIdentityAgent none\n",
+                path.display()
+            ),
+        )?;
+        let mut client = connect_configured(None, &config).await?;
+        assert!(client.request_identities().await?.is_empty());
+
+        // an explicit path wins over the configuration
+        let missing = dir.path().join("missing.sock");
+        assert!(matches!(
+            connect_configured(Some(&missing), &config).await,
+            Err(ConnectError::NotFound(_))
+        ));
+
+        std::fs::write(&config, "SSH_AUTH_SOCK=none\n")?;
+        assert!(matches!(
+            connect_configured(None, &config).await,
+            Err(ConnectError::NotConfigured)
+        ));
+
+        agent.abort();
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn abstract_addresses() -> TestResult {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixListener, UnixStream};
+
+        use crate::agent::Agent;
+
+        // the path of a temporary directory is a name no other test uses
+        let dir = tempfile::tempdir()?;
+        let name = dir.path().to_string_lossy().into_owned();
+        let address = SocketAddr::from_abstract_name(&name)?;
+        let listener = UnixListener::bind_addr(&address)?;
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+        let agent = tokio::spawn(crate::keyring::KeyRing::new().listen(listener));
+
+        let mut client = connect_abstract(&name).await?;
+        assert!(client.request_identities().await?.is_empty());
+
+        // a blocking stream connected by hand
+        let stream = UnixStream::connect_addr(&address)?;
+        let mut client = connect(service_binding::Stream::Unix(stream)).await?;
+        assert!(client.request_identities().await?.is_empty());
+
+        agent.abort();
+        assert!(matches!(
+            connect_abstract(format!("{name}-missing")).await,
+            Err(ConnectError::ConnectionRefused(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_close_the_connection() -> TestResult {
+        // the first agent never answers, the second one does
+        let (silent, _agent) = tokio::net::UnixStream::pair()?;
+        let mut client = Client::connect_with(scripted_dialer(vec![
+            silent,
+            scripted_agent(vec![Response::Success])?,
+        ]))
+        .await?;
+
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(10), client.lock("a".to_owned()));
+        assert!(cancelled.await.is_err());
+        let error = client.lock("b".to_owned()).await.unwrap_err();
+        assert!(
+            matches!(error, AgentError::Proto(ProtoError::IO(e)) if e.kind() == io::ErrorKind::NotConnected)
+        );
+
+        client.set_auto_reconnect(true);
+        client.lock("c".to_owned()).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lock_with_derived_passphrase() -> TestResult {
+        assert_eq!(
+            *lock_passphrase(b"secret"),
+            "a9bc3d69995802339b2774ed1cd3df3cf7f6061bb7ec944e77dffa562fe24cb35866eb0d8c01196888788ef15aa0d33da1b128069d4d9de10bd76779ec001d8d"
+        );
+
+        let (client, agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(crate::agent::serve(crate::keyring::KeyRing::new(), agent));
+        let mut client = Client::new(client);
+
+        client.lock_with_secret(b"secret").await?;
+        assert!(client.unlock_with_secret(b"other secret").await.is_err());
+        client.unlock(lock_passphrase(b"secret")).await?;
+
+        client.lock_with_secret(b"secret").await?;
+        client.unlock_with_secret(b"secret").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn traffic_is_captured() -> TestResult {
+        let captured = crate::test_util::SharedBuffer::default();
+        let stream = scripted_agent(vec![Response::IdentitiesAnswer(vec![]), Response::Success])?;
+        let mut client = Client::new(stream).with_capture(captured.clone());
+
+        assert!(client.request_identities().await?.is_empty());
+        client.lock("secret".to_string()).await?;
+
+        // the capture is made of whole frames, requests and responses in turn
+        fn next<T>(bytes: &mut &[u8]) -> Result<T, ProtoError>
+        where
+            T: ssh_encoding::Decode<Error = ProtoError>,
+        {
+            match crate::proto::decode_frame(bytes, crate::codec::MAX_MESSAGE_LENGTH)? {
+                crate::proto::Frame::Complete { message, length } => {
+                    *bytes = &bytes[length..];
+                    Ok(message)
+                }
+                crate::proto::Frame::Incomplete { .. } => panic!("truncated capture"),
+            }
+        }
+        let captured = captured.contents();
+        let mut bytes = &captured[..];
+        assert_eq!(next::<Request>(&mut bytes)?, Request::RequestIdentities);
+        assert_eq!(
+            next::<Response>(&mut bytes)?,
+            Response::IdentitiesAnswer(vec![])
+        );
+        assert_eq!(
+            next::<Request>(&mut bytes)?,
+            Request::Lock("secret".to_string().into())
+        );
+        assert_eq!(next::<Response>(&mut bytes)?, Response::Success);
+        assert!(bytes.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pageant_gets_no_flags_for_other_keys() -> TestResult {
+        let ed25519 = KeyData::Ed25519(ssh_key::public::Ed25519PublicKey([0; 32]));
+        let signature = Signature::new(Algorithm::Ed25519, vec![0; 64])?;
+        let mut request = SignRequest::new(ed25519, b"data".to_vec());
+        request.flags = RSA_SHA2_512;
+
+        let mut sent_flags = vec![];
+        for flavor in [None, Some(AgentFlavor::Pageant)] {
+            let (stream, agent) = tokio::net::UnixStream::pair()?;
+            let response = Response::SignResponse(signature.clone());
+            let sent = tokio::spawn(async move {
+                let mut adapter = Framed::new(agent, Codec::<Request, Response>::default());
+                let request = adapter.try_next().await?;
+                adapter.send(response).await?;
+                Ok::<_, AgentError>(request)
+            });
+            let mut client = Client::new(stream);
+            client.set_flavor(flavor);
+            assert_eq!(client.sign(request.clone()).await?, signature);
+            let Some(Request::SignRequest(sent)) = sent.await?? else {
+                panic!("expected a sign request");
+            };
+            sent_flags.push(sent.flags);
+        }
+        assert_eq!(sent_flags, [RSA_SHA2_512, 0]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,186 @@
+//! Discovery of running agents.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{with_timeout, Client};
+use crate::agent::Session;
+use crate::error::AgentError;
+use crate::proto::Identity;
+
+/// Time allowed for connecting to a candidate agent and for its answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Agent found by [`discover_agents`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AgentInfo {
+    /// Path of the agent's socket, or the name of its pipe on Windows.
+    pub path: PathBuf,
+
+    /// Identities held by the agent when it was probed.
+    pub identities: Vec<Identity>,
+}
+
+/// Find the agents running for the current user.
+///
+/// Candidate sockets are taken from `SSH_AUTH_SOCK` and the places
+/// agents usually create them in:
+///
+/// - on Unix, the `ssh-*/agent.*` sockets of `ssh-agent` and forwarded
+///   agents in `TMPDIR` or `/tmp`, the sockets in `~/.ssh/agent` and the
+///   SSH socket of `gpg-agent` (`S.gpg-agent.ssh`) in `GNUPGHOME`,
+///   `$XDG_RUNTIME_DIR/gnupg` or `~/.gnupg`,
+/// - on Windows, the `openssh-ssh-agent` pipe of the OpenSSH agent.
+///
+/// Sockets and `ssh-*` directories found by listing a directory are only
+/// taken if they belong to the current user.
+///
+/// Each candidate is asked for its identities, and those answering are
+/// returned in the order above, each socket only once. Candidates which
+/// do not exist, cannot be accessed, or do not answer within a second are
+/// skipped.
+pub async fn discover_agents() -> Vec<AgentInfo> {
+    probe(candidates()).await
+}
+
+/// Ask every agent in `paths` for its identities, skipping the failing ones.
+async fn probe(paths: Vec<PathBuf>) -> Vec<AgentInfo> {
+    let mut agents = vec![];
+    for path in paths {
+        match identities(&path).await {
+            Ok(identities) => agents.push(AgentInfo { path, identities }),
+            Err(e) => log::debug!("No agent at {}: {e}", path.display()),
+        }
+    }
+    agents
+}
+
+async fn identities(path: &Path) -> Result<Vec<Identity>, AgentError> {
+    #[cfg(unix)]
+    let stream = tokio::net::UnixStream::connect(path);
+    #[cfg(windows)]
+    let stream = super::open_named_pipe(path);
+
+    let mut client = Client::new(with_timeout(Some(PROBE_TIMEOUT), stream).await??);
+    client.set_request_timeout(Some(PROBE_TIMEOUT));
+    client.request_identities().await
+}
+
+fn candidates() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if let Some(path) = std::env::var_os("SSH_AUTH_SOCK") {
+        paths.push(PathBuf::from(path));
+    }
+
+    #[cfg(unix)]
+    {
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        let tmp = std::env::var_os("TMPDIR").unwrap_or_else(|| "/tmp".into());
+
+        let uid = current_uid();
+
+        for dir in sockets_in(Path::new(&tmp), "ssh-", false, uid) {
+            paths.extend(sockets_in(&dir, "agent.", true, uid));
+        }
+        if let Some(home) = &home {
+            paths.extend(sockets_in(&home.join(".ssh/agent"), "", true, uid));
+        }
+
+        let gnupg_dirs = [
+            std::env::var_os("GNUPGHOME").map(PathBuf::from),
+            std::env::var_os("XDG_RUNTIME_DIR").map(|dir| Path::new(&dir).join("gnupg")),
+            home.map(|home| home.join(".gnupg")),
+        ];
+        for dir in gnupg_dirs.into_iter().flatten() {
+            paths.push(dir.join("S.gpg-agent.ssh"));
+        }
+    }
+    #[cfg(windows)]
+    paths.push(PathBuf::from(r"\\.\pipe\openssh-ssh-agent"));
+
+    // `SSH_AUTH_SOCK` usually points to one of the other candidates
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(fs::canonicalize(path).unwrap_or_else(|_| path.clone())));
+    paths
+}
+
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn current_uid() -> u32 {
+    // SAFETY: `getuid` has no preconditions and always succeeds
+    unsafe { libc::getuid() }
+}
+
+/// Entries of `dir` whose names start with `prefix`, either sockets or
+/// directories, owned by `uid`. Unreadable directories have no entries.
+///
+/// Other users can create sockets in shared directories like `/tmp`, so
+/// those could impersonate an agent, e.g. to collect the keys added to it.
+#[cfg(unix)]
+fn sockets_in(dir: &Path, prefix: &str, sockets: bool, uid: u32) -> Vec<PathBuf> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut paths: Vec<_> = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+        .filter(|entry| {
+            // the metadata of symbolic links, which are skipped
+            entry.metadata().is_ok_and(|metadata| {
+                let file_type = metadata.file_type();
+                let kind = if sockets {
+                    file_type.is_socket()
+                } else {
+                    file_type.is_dir()
+                };
+                kind && metadata.uid() == uid
+            })
+        })
+        .map(|entry| entry.path())
+        .collect();
+    // directory order is arbitrary
+    paths.sort();
+    paths
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::agent::Agent;
+    use crate::keyring::KeyRing;
+
+    #[tokio::test]
+    async fn only_answering_agents_are_found() -> TestResult {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+
+        let agent = dir.join("agent.1");
+        tokio::spawn(KeyRing::new().listen(tokio::net::UnixListener::bind(&agent)?));
+        let stale = dir.join("agent.2");
+        crate::test_util::stale_socket(&stale)?;
+        let file = dir.join("agent.3");
+        fs::write(&file, b"")?;
+
+        let uid = std::os::unix::fs::MetadataExt::uid(&fs::metadata(dir)?);
+        assert_eq!(
+            sockets_in(dir, "agent.", true, uid),
+            [agent.clone(), stale.clone()]
+        );
+        // sockets of other users are skipped
+        assert!(sockets_in(dir, "agent.", true, uid.wrapping_add(1)).is_empty());
+
+        let agents = probe(vec![agent.clone(), stale, file, dir.join("missing")]).await;
+
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].path, agent);
+        assert!(agents[0].identities.is_empty());
+        Ok(())
+    }
+}
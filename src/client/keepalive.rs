@@ -0,0 +1,134 @@
+//! Keeping idle agent connections open.
+
+use std::time::Duration;
+
+use futures::channel::oneshot;
+
+use super::task::{self, BackgroundTask};
+use crate::agent::Session;
+use crate::error::AgentError;
+
+/// Background task pinging an agent while the connection is idle.
+///
+/// Forwarded agent channels and TCP connections through a NAT may be
+/// dropped after some time without traffic. The keepalive sends the
+/// read-only [`ping`](Session::ping) request every `interval`, so such a
+/// connection stays in use. Pinging stops at the first failure, which is
+/// logged and returned by [`Keepalive::stop`]:
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use std::time::Duration;
+///
+/// use ssh_agent_lib::agent::Session;
+/// use ssh_agent_lib::client::{Client, Keepalive, SharedClient};
+///
+/// let stream = tokio::net::UnixStream::connect(std::env::var("SSH_AUTH_SOCK").unwrap()).await?;
+/// let mut client = SharedClient::new(Client::new(stream));
+/// let keepalive = Keepalive::spawn(client.clone(), Duration::from_secs(60));
+/// // ... hours later ...
+/// if keepalive.is_finished() {
+///     return keepalive.stop().await;
+/// }
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+///
+/// A [`SharedClient`](super::SharedClient) handle lets the connection be
+/// used for other requests meanwhile. Pings are sent every `interval`
+/// whether or not there were other requests in between. Dropping the
+/// keepalive stops it as well.
+#[derive(Debug)]
+pub struct Keepalive(BackgroundTask);
+
+impl Keepalive {
+    /// Start pinging the agent of `session` every `interval`.
+    ///
+    /// The first ping is sent after one `interval`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero or if called outside of a Tokio
+    /// runtime.
+    pub fn spawn(session: impl Session, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "keepalive interval must not be zero");
+        Self(BackgroundTask::spawn(|stopped| {
+            ping(session, interval, stopped)
+        }))
+    }
+
+    /// Whether the keepalive stopped after a failed ping.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    /// Stop pinging the agent.
+    ///
+    /// A ping in progress is completed first. Returns the error of the
+    /// failed ping if the keepalive already stopped.
+    pub async fn stop(self) -> Result<(), AgentError> {
+        self.0.stop().await
+    }
+}
+
+async fn ping(
+    mut session: impl Session,
+    interval: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<(), AgentError> {
+    loop {
+        if task::sleep(interval, &mut stopped).await {
+            return Ok(());
+        }
+        if let Err(e) = session.ping().await {
+            log::warn!("Keepalive ping failed: {e}");
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::test_util::{advance, CountingSession};
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_connections_are_pinged() -> TestResult {
+        let session = CountingSession::default();
+        let keepalive = Keepalive::spawn(session.clone(), Duration::from_secs(60));
+        // let the keepalive start waiting
+        advance(Duration::ZERO).await;
+
+        for pings in 1..=3 {
+            advance(Duration::from_secs(60)).await;
+            assert_eq!(session.pings.load(Ordering::SeqCst), pings);
+        }
+        assert!(!keepalive.is_finished());
+        keepalive.stop().await?;
+        advance(Duration::from_secs(120)).await;
+
+        assert_eq!(session.pings.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_pings_are_returned() -> TestResult {
+        let session = CountingSession {
+            fail: true,
+            ..Default::default()
+        };
+        let keepalive = Keepalive::spawn(session.clone(), Duration::from_secs(60));
+        advance(Duration::ZERO).await;
+
+        advance(Duration::from_secs(180)).await;
+        assert!(keepalive.is_finished());
+        assert!(matches!(keepalive.stop().await, Err(AgentError::Failure)));
+        assert_eq!(session.pings.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
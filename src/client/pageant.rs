@@ -0,0 +1,366 @@
+//! Transports used by PuTTY's Pageant agent.
+//!
+//! Pageant speaks the regular SSH agent protocol but does not listen on
+//! a socket advertised through an environment variable. Recent versions
+//! listen on a named pipe whose name is derived from the user's name,
+//! while all versions accept requests passed through a shared memory
+//! mapping announced to the Pageant window with a `WM_COPYDATA` message.
+//!
+//! The behavior mirrors PuTTY's `windows/agent-client.c`.
+#![allow(unsafe_code)]
+
+use std::ffi::c_void;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::windows::named_pipe::NamedPipeClient;
+use tokio::task::JoinHandle;
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, HANDLE, HWND, INVALID_HANDLE_VALUE},
+    Security::{
+        Authentication::Identity::{GetUserNameExW, NameUserPrincipal},
+        Cryptography::{
+            CryptProtectMemory, CRYPTPROTECTMEMORY_BLOCK_SIZE, CRYPTPROTECTMEMORY_CROSS_PROCESS,
+        },
+    },
+    System::{
+        DataExchange::COPYDATASTRUCT,
+        Memory::{
+            CreateFileMappingW, MapViewOfFile, UnmapViewOfFile, FILE_MAP_WRITE,
+            MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+        },
+        Threading::GetCurrentThreadId,
+        WindowsProgramming::GetUserNameW,
+    },
+    UI::WindowsAndMessaging::{FindWindowW, SendMessageW, WM_COPYDATA},
+};
+
+/// Maximum size of a message exchanged through the shared memory mapping.
+const AGENT_MAX_MSGLEN: usize = 8192;
+
+/// `dwData` value identifying agent requests sent with `WM_COPYDATA`.
+const AGENT_COPYDATA_ID: usize = 0x804e50ba;
+
+/// Connection to a running Pageant instance.
+///
+/// Created by [`connect_pageant`](super::connect_pageant).
+#[derive(Debug)]
+pub struct PageantStream(Transport);
+
+#[derive(Debug)]
+enum Transport {
+    Pipe(NamedPipeClient),
+    CopyData(CopyDataStream),
+}
+
+impl PageantStream {
+    pub(super) fn pipe(pipe: NamedPipeClient) -> Self {
+        Self(Transport::Pipe(pipe))
+    }
+
+    pub(super) fn copy_data() -> io::Result<Self> {
+        // check early so that connecting fails instead of the first request
+        find_window()?;
+        Ok(Self(Transport::CopyData(CopyDataStream::default())))
+    }
+}
+
+impl AsyncRead for PageantStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().0 {
+            Transport::Pipe(pipe) => Pin::new(pipe).poll_read(cx, buf),
+            Transport::CopyData(stream) => stream.poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PageantStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().0 {
+            Transport::Pipe(pipe) => Pin::new(pipe).poll_write(cx, buf),
+            Transport::CopyData(stream) => stream.poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().0 {
+            Transport::Pipe(pipe) => Pin::new(pipe).poll_flush(cx),
+            Transport::CopyData(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().0 {
+            Transport::Pipe(pipe) => Pin::new(pipe).poll_shutdown(cx),
+            Transport::CopyData(_) => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// Request/response stream on top of `WM_COPYDATA` exchanges.
+///
+/// Written bytes are buffered until a complete length-prefixed message
+/// is available, which is then handed to Pageant on a blocking thread
+/// since `SendMessageW` waits for Pageant to process the request.
+#[derive(Debug, Default)]
+struct CopyDataStream {
+    request: Vec<u8>,
+    pending: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    response: io::Cursor<Vec<u8>>,
+    reader: Option<Waker>,
+}
+
+impl CopyDataStream {
+    fn poll_response(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(pending) = &mut self.pending {
+            let result = futures::ready!(Pin::new(pending).poll(cx));
+            self.pending = None;
+            let response = result.map_err(io::Error::other)??;
+            self.response = io::Cursor::new(response);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.poll_response(cx))?;
+
+        let remaining = &self.response.get_ref()[self.response.position() as usize..];
+        if remaining.is_empty() {
+            // nothing to read until the next request is written
+            self.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let len = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..len]);
+        self.response
+            .set_position(self.response.position() + len as u64);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_response(cx))?;
+
+        if self.request.len() + buf.len() > AGENT_MAX_MSGLEN {
+            self.request.clear();
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "request too large for Pageant",
+            )));
+        }
+        self.request.extend_from_slice(buf);
+
+        if let Some(prefix) = self.request.get(..4) {
+            let len = u32::from_be_bytes(prefix.try_into().expect("four bytes")) as usize + 4;
+            if self.request.len() >= len {
+                let request = std::mem::take(&mut self.request);
+                self.pending = Some(tokio::task::spawn_blocking(move || {
+                    copy_data_exchange(&request)
+                }));
+                if let Some(reader) = self.reader.take() {
+                    reader.wake();
+                }
+            }
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+}
+
+/// Encode a string as a nul-terminated wide string.
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(Some(0)).collect()
+}
+
+fn find_window() -> io::Result<HWND> {
+    let name = wide("Pageant");
+    // SAFETY: both arguments are valid nul-terminated wide strings.
+    let window = unsafe { FindWindowW(name.as_ptr(), name.as_ptr()) };
+    if window == 0 {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Pageant window not found",
+        ))
+    } else {
+        Ok(window)
+    }
+}
+
+/// Owned handle closed on drop.
+struct Handle(HANDLE);
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        // SAFETY: the handle is valid and owned by this value.
+        unsafe { CloseHandle(self.0) };
+    }
+}
+
+/// Mapped view of a file mapping, unmapped on drop.
+struct View(MEMORY_MAPPED_VIEW_ADDRESS);
+
+impl Drop for View {
+    fn drop(&mut self) {
+        // SAFETY: the view was returned by `MapViewOfFile` and is not used afterwards.
+        unsafe { UnmapViewOfFile(self.0) };
+    }
+}
+
+/// Send a single request to Pageant through shared memory.
+///
+/// The request is copied into a named file mapping whose name is then
+/// sent to the Pageant window. Pageant writes its response into the
+/// same mapping before `SendMessageW` returns.
+fn copy_data_exchange(request: &[u8]) -> io::Result<Vec<u8>> {
+    let window = find_window()?;
+
+    // SAFETY: `GetCurrentThreadId` has no preconditions.
+    let map_name = format!("PageantRequest{:08x}", unsafe { GetCurrentThreadId() });
+    let wide_name = wide(&map_name);
+    // SAFETY: the name is a valid nul-terminated wide string and the
+    // default security attributes restrict the mapping to the current user.
+    let mapping = unsafe {
+        CreateFileMappingW(
+            INVALID_HANDLE_VALUE,
+            std::ptr::null(),
+            PAGE_READWRITE,
+            0,
+            AGENT_MAX_MSGLEN as u32,
+            wide_name.as_ptr(),
+        )
+    };
+    if mapping == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mapping = Handle(mapping);
+
+    // SAFETY: `mapping` is a valid file mapping handle.
+    let view = unsafe { MapViewOfFile(mapping.0, FILE_MAP_WRITE, 0, 0, 0) };
+    if view.Value.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let view = View(view);
+    let memory = view.0.Value as *mut u8;
+
+    // SAFETY: the view spans `AGENT_MAX_MSGLEN` bytes, which bounds the
+    // request size checked when it was buffered.
+    unsafe { std::ptr::copy_nonoverlapping(request.as_ptr(), memory, request.len()) };
+
+    // Pageant expects the ANSI mapping name including the terminating nul.
+    let mut ansi_name = map_name.into_bytes();
+    ansi_name.push(0);
+    let copy_data = COPYDATASTRUCT {
+        dwData: AGENT_COPYDATA_ID,
+        cbData: ansi_name.len() as u32,
+        lpData: ansi_name.as_mut_ptr() as *mut c_void,
+    };
+    // SAFETY: `copy_data` and the buffer it points to outlive the call.
+    let id = unsafe {
+        SendMessageW(
+            window,
+            WM_COPYDATA,
+            0,
+            &copy_data as *const COPYDATASTRUCT as isize,
+        )
+    };
+    if id <= 0 {
+        return Err(io::Error::other("Pageant rejected the request"));
+    }
+
+    let mut prefix = [0u8; 4];
+    // SAFETY: the view spans at least four bytes.
+    unsafe { std::ptr::copy_nonoverlapping(memory, prefix.as_mut_ptr(), prefix.len()) };
+    let len = u32::from_be_bytes(prefix) as usize + prefix.len();
+    if len > AGENT_MAX_MSGLEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Pageant response too large",
+        ));
+    }
+
+    let mut response = vec![0u8; len];
+    // SAFETY: `len` was checked against the size of the view.
+    unsafe { std::ptr::copy_nonoverlapping(memory, response.as_mut_ptr(), len) };
+    Ok(response)
+}
+
+/// Retrieve the name of the current user as used by Pageant.
+///
+/// This is the user principal name without the domain part or,
+/// if that is not available, the plain user name.
+fn user_name() -> io::Result<String> {
+    fn from_wide(buffer: &[u16]) -> String {
+        let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        String::from_utf16_lossy(&buffer[..len])
+    }
+
+    let mut len = 0u32;
+    // SAFETY: a null buffer queries the required length.
+    unsafe { GetUserNameExW(NameUserPrincipal, std::ptr::null_mut(), &mut len) };
+    if len > 0 {
+        let mut buffer = vec![0u16; len as usize];
+        // SAFETY: the buffer holds `len` characters.
+        if unsafe { GetUserNameExW(NameUserPrincipal, buffer.as_mut_ptr(), &mut len) } != 0 {
+            let name = from_wide(&buffer);
+            return Ok(match name.split_once('@') {
+                Some((user, _)) => user.to_string(),
+                None => name,
+            });
+        }
+    }
+
+    let mut len = 0u32;
+    // SAFETY: a null buffer queries the required length.
+    unsafe { GetUserNameW(std::ptr::null_mut(), &mut len) };
+    let mut buffer = vec![0u16; len as usize];
+    // SAFETY: the buffer holds `len` characters.
+    if unsafe { GetUserNameW(buffer.as_mut_ptr(), &mut len) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(from_wide(&buffer))
+}
+
+/// Compute the name of the pipe Pageant listens on.
+///
+/// The name ends with a hash of the string `Pageant` encrypted with
+/// `CryptProtectMemory`, which makes it specific to the user's logon.
+pub(super) fn pipe_name() -> io::Result<String> {
+    let user = user_name()?;
+
+    let mut secret = [0u8; CRYPTPROTECTMEMORY_BLOCK_SIZE as usize];
+    secret[..7].copy_from_slice(b"Pageant");
+    // SAFETY: the buffer length is a multiple of the block size.
+    let protected = unsafe {
+        CryptProtectMemory(
+            secret.as_mut_ptr() as *mut c_void,
+            secret.len() as u32,
+            CRYPTPROTECTMEMORY_CROSS_PROCESS,
+        )
+    };
+    if protected == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update((secret.len() as u32).to_be_bytes());
+    hasher.update(secret);
+    let suffix: String = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    Ok(format!(r"\\.\pipe\pageant.{user}.{suffix}"))
+}
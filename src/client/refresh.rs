@@ -0,0 +1,181 @@
+//! Keeping keys with a limited lifetime in an agent.
+
+use std::time::Duration;
+
+use futures::channel::oneshot;
+
+use super::task::{self, BackgroundTask};
+use crate::agent::Session;
+use crate::error::AgentError;
+use crate::proto::{AddIdentity, AddIdentityConstrained, KeyConstraints, ProtoError};
+
+/// Consecutive failed adds after which the refresher gives up.
+const MAX_FAILURES: u32 = 3;
+
+/// Background task adding a key with a limited lifetime to an agent again
+/// before it expires.
+///
+/// The key is added right away with a [lifetime](KeyConstraints::lifetime)
+/// constraint, and again each time three quarters of the lifetime have
+/// passed, so the agent never holds it for longer than a lifetime after
+/// the refresher stops. A failed add is retried a few times within the
+/// remaining quarter. After three failures in a row the refresher stops,
+/// and the error is returned by [`KeyRefresher::stop`].
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # async fn example(identity: ssh_agent_lib::proto::AddIdentity) -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use std::time::Duration;
+///
+/// use ssh_agent_lib::client::{Client, KeyRefresher};
+///
+/// let stream = tokio::net::UnixStream::connect(std::env::var("SSH_AUTH_SOCK").unwrap()).await?;
+/// let refresher =
+///     KeyRefresher::spawn(Client::new(stream), identity, Duration::from_secs(600))?;
+/// // ... use the key ...
+/// refresher.stop().await?;
+/// # Ok(()) }
+/// ```
+///
+/// A [`SharedClient`](super::SharedClient) handle can be passed to keep
+/// using the connection for other requests. Dropping the refresher stops
+/// it as well, without waiting for an add in progress.
+#[derive(Debug)]
+pub struct KeyRefresher(BackgroundTask);
+
+impl KeyRefresher {
+    /// Start adding `identity` to the agent of `session`, limiting its
+    /// lifetime to `lifetime`.
+    ///
+    /// Fails if the lifetime is shorter than a second or does not fit in
+    /// the 32-bit seconds field of the constraint.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    pub fn spawn(
+        session: impl Session,
+        identity: AddIdentity,
+        lifetime: Duration,
+    ) -> Result<Self, AgentError> {
+        if lifetime.as_secs() == 0 {
+            return Err(ProtoError::LifetimeOutOfRange { seconds: 0 }.into());
+        }
+        let identity = AddIdentityConstrained {
+            identity,
+            constraints: KeyConstraints::new().lifetime(lifetime)?.build(),
+        };
+        // the agent counts whole seconds
+        let lifetime = Duration::from_secs(lifetime.as_secs());
+
+        Ok(Self(BackgroundTask::spawn(|stopped| {
+            refresh(session, identity, lifetime, stopped)
+        })))
+    }
+
+    /// Whether the refresher stopped after failing to add the key.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    /// Stop refreshing the key.
+    ///
+    /// An add in progress is completed first. Returns the error of the
+    /// last add if the refresher already stopped after failing.
+    pub async fn stop(self) -> Result<(), AgentError> {
+        self.0.stop().await
+    }
+}
+
+async fn refresh(
+    mut session: impl Session,
+    identity: AddIdentityConstrained,
+    lifetime: Duration,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<(), AgentError> {
+    let margin = lifetime / 4;
+    let mut failures = 0;
+    loop {
+        let delay = match session.add_identity_constrained(identity.clone()).await {
+            Ok(()) => {
+                failures = 0;
+                lifetime - margin
+            }
+            Err(e) => {
+                failures += 1;
+                log::warn!("Adding key failed ({failures} of {MAX_FAILURES} attempts): {e}");
+                if failures == MAX_FAILURES {
+                    return Err(e);
+                }
+                margin / MAX_FAILURES
+            }
+        };
+        if task::sleep(delay, &mut stopped).await {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::{private::Ed25519Keypair, private::KeypairData};
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::proto::{Credential, KeyConstraint};
+    use crate::test_util::{advance, CountingSession};
+
+    fn identity() -> AddIdentity {
+        AddIdentity {
+            credential: Credential::Key {
+                privkey: KeypairData::Ed25519(Ed25519Keypair::from_seed(&[1; 32])),
+                comment: "refreshed".into(),
+            },
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_are_added_before_they_expire() -> TestResult {
+        let session = CountingSession::default();
+        let lifetime = Duration::from_secs(600);
+        let refresher = KeyRefresher::spawn(session.clone(), identity(), lifetime)?;
+
+        // added at once, then after 450 and 900 seconds
+        advance(Duration::ZERO).await;
+        assert_eq!(session.adds.lock().unwrap().len(), 1);
+        for adds in 2..=3 {
+            advance(Duration::from_secs(450)).await;
+            assert_eq!(session.adds.lock().unwrap().len(), adds);
+        }
+        refresher.stop().await?;
+        advance(Duration::from_secs(900)).await;
+
+        let adds = session.adds.lock().unwrap();
+        assert_eq!(adds.len(), 3);
+        assert_eq!(adds[0].identity, identity());
+        assert_eq!(adds[0].constraints, [KeyConstraint::Lifetime(600)]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refreshing_stops_after_repeated_failures() -> TestResult {
+        let session = CountingSession {
+            fail: true,
+            ..Default::default()
+        };
+        let lifetime = Duration::from_secs(600);
+        let refresher = KeyRefresher::spawn(session.clone(), identity(), lifetime)?;
+
+        // retried after 50 and 100 seconds, within the last quarter
+        advance(Duration::ZERO).await;
+        advance(Duration::from_secs(50)).await;
+        assert!(!refresher.is_finished());
+        advance(Duration::from_secs(50)).await;
+        assert!(refresher.is_finished());
+        assert!(matches!(refresher.stop().await, Err(AgentError::Failure)));
+        assert_eq!(session.adds.lock().unwrap().len(), 3);
+
+        assert!(KeyRefresher::spawn(session, identity(), Duration::from_millis(100)).is_err());
+        Ok(())
+    }
+}
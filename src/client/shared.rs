@@ -0,0 +1,336 @@
+//! Agent connection shared between tasks.
+
+use std::fmt;
+use std::io;
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use ssh_key::{PublicKey, Signature};
+
+use crate::agent::Session;
+use crate::error::AgentError;
+use crate::proto::{
+    AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Extension, Identity,
+    RemoveIdentity, Request, Response, SignRequest, SmartcardKey, Zeroizing,
+};
+
+/// Operation run by the task owning the session.
+type Call = Box<dyn for<'a> FnOnce(&'a mut dyn Session) -> BoxFuture<'a, ()> + Send>;
+
+/// Cloneable handle to an agent connection.
+///
+/// The connection is owned by a task which runs the requests of all
+/// handles one after another, as the agent protocol allows only one
+/// request at a time per connection. Each request waits for its own
+/// response, so handles can be used from any number of tasks:
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use ssh_agent_lib::{
+///     agent::Session,
+///     client::{Client, SharedClient},
+/// };
+///
+/// let stream = tokio::net::UnixStream::connect(std::env::var("SSH_AUTH_SOCK").unwrap()).await?;
+/// let client = SharedClient::new(Client::new(stream));
+///
+/// let mut handle = client.clone();
+/// tokio::spawn(async move { handle.request_identities().await });
+/// client.clone().request_identities().await?;
+/// # Ok(()) }
+/// ```
+///
+/// The task stops when all handles are dropped. It must be created from
+/// within a Tokio runtime.
+///
+/// # Backpressure
+///
+/// Requests waiting for the connection are kept in a bounded queue.
+/// When the agent is slow and the queue is full, requests wait for space
+/// instead of using more memory: [`SharedClient::try_sign`] can be used
+/// to fail with [`AgentError::Busy`] instead. Every handle may queue one
+/// request in addition to the capacity of the queue.
+#[derive(Clone)]
+pub struct SharedClient {
+    calls: mpsc::Sender<Call>,
+}
+
+impl SharedClient {
+    /// Queue capacity used by [`SharedClient::new`].
+    pub const DEFAULT_CAPACITY: usize = 32;
+
+    /// Move `session`, usually a [`Client`](super::Client), into a task
+    /// and return a handle to it.
+    pub fn new(session: impl Session) -> Self {
+        Self::with_capacity(session, Self::DEFAULT_CAPACITY)
+    }
+
+    /// Like [`SharedClient::new`], but with a queue holding at most
+    /// `capacity` requests of all handles.
+    pub fn with_capacity(mut session: impl Session, capacity: usize) -> Self {
+        let (calls, mut queue) = mpsc::channel::<Call>(capacity);
+        tokio::spawn(async move {
+            while let Some(call) = queue.next().await {
+                call(&mut session).await;
+            }
+        });
+        Self { calls }
+    }
+
+    /// Sign a request unless the queue is full.
+    ///
+    /// Unlike [`Session::sign`], which waits for space in the queue, this
+    /// fails with [`AgentError::Busy`] if the request cannot be queued
+    /// immediately. Once queued, the request waits for its response.
+    pub async fn try_sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        let (call, response) = wrap(move |session| session.sign(request));
+        self.calls.try_send(call).map_err(|e| {
+            if e.is_full() {
+                AgentError::Busy
+            } else {
+                stopped()
+            }
+        })?;
+        response.await.map_err(|_| stopped())?
+    }
+
+    /// Run `f` on the shared session and wait for its result.
+    async fn call<T, F>(&mut self, f: F) -> Result<T, AgentError>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut dyn Session) -> BoxFuture<'a, Result<T, AgentError>>
+            + Send
+            + 'static,
+    {
+        let (call, response) = wrap(f);
+        self.calls.send(call).await.map_err(|_| stopped())?;
+        response.await.map_err(|_| stopped())?
+    }
+}
+
+/// Wrap `f` into a call, returning the receiver of its result.
+fn wrap<T, F>(f: F) -> (Call, oneshot::Receiver<Result<T, AgentError>>)
+where
+    T: Send + 'static,
+    F: for<'a> FnOnce(&'a mut dyn Session) -> BoxFuture<'a, Result<T, AgentError>> + Send + 'static,
+{
+    let (result, response) = oneshot::channel();
+    let call: Call = Box::new(move |session| {
+        Box::pin(async move {
+            // the requester may have given up waiting
+            let _ = result.send(f(session).await);
+        })
+    });
+    (call, response)
+}
+
+fn stopped() -> AgentError {
+    io::Error::new(io::ErrorKind::BrokenPipe, "shared agent connection stopped").into()
+}
+
+impl fmt::Debug for SharedClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedClient")
+            .field("closed", &self.calls.is_closed())
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl Session for SharedClient {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        self.call(|session| session.request_identities()).await
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        self.call(move |session| session.sign(request)).await
+    }
+
+    async fn sign_batch(
+        &mut self,
+        key: &PublicKey,
+        datas: &[Vec<u8>],
+    ) -> Result<Vec<Signature>, AgentError> {
+        let (key, datas) = (key.clone(), datas.to_vec());
+        self.call(move |session| Box::pin(async move { session.sign_batch(&key, &datas).await }))
+            .await
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.call(move |session| session.add_identity(identity))
+            .await
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.call(move |session| session.add_identity_constrained(identity))
+            .await
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        self.call(move |session| session.remove_identity(identity))
+            .await
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        self.call(|session| session.remove_all_identities()).await
+    }
+
+    async fn add_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.call(move |session| session.add_smartcard_key(key))
+            .await
+    }
+
+    async fn add_smartcard_key_constrained(
+        &mut self,
+        key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        self.call(move |session| session.add_smartcard_key_constrained(key))
+            .await
+    }
+
+    async fn remove_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.call(move |session| session.remove_smartcard_key(key))
+            .await
+    }
+
+    async fn lock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        self.call(move |session| session.lock(key)).await
+    }
+
+    async fn unlock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        self.call(move |session| session.unlock(key)).await
+    }
+
+    async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
+        self.call(move |session| session.extension(extension)).await
+    }
+
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        self.call(move |session| session.handle(message)).await
+    }
+
+    async fn query_extensions(&mut self) -> Result<Vec<String>, AgentError> {
+        self.call(|session| session.query_extensions()).await
+    }
+
+    async fn count_identities(&mut self) -> Result<usize, AgentError> {
+        self.call(|session| session.count_identities()).await
+    }
+
+    async fn ping(&mut self) -> Result<(), AgentError> {
+        self.call(|session| session.ping()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use signature::Verifier;
+    use ssh_key::{Algorithm, PrivateKey};
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::agent::serve;
+    use crate::client::Client;
+    use crate::keyring::KeyRing;
+    use crate::proto::Credential;
+
+    #[tokio::test]
+    async fn concurrent_requests_get_their_own_responses() -> TestResult {
+        let (client, agent) = tokio::io::duplex(4096);
+        tokio::spawn(serve(KeyRing::new(), agent));
+        let mut client = SharedClient::new(Client::new(client));
+
+        let key = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)?;
+        client
+            .add_identity(AddIdentity {
+                credential: Credential::Key {
+                    privkey: key.key_data().clone(),
+                    comment: "shared".into(),
+                },
+            })
+            .await?;
+
+        let tasks: Vec<_> = (0..16u8)
+            .map(|i| {
+                let mut client = client.clone();
+                let public = key.public_key().clone();
+                tokio::spawn(async move {
+                    let signature = client.sign_ssh_data(&public, &[i]).await?;
+                    Ok::<_, AgentError>((i, signature))
+                })
+            })
+            .collect();
+        for task in tasks {
+            let (i, signature) = task.await??;
+            key.public_key().key_data().verify(&[i], &signature)?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_busy() -> TestResult {
+        use futures::FutureExt;
+
+        let (client, agent) = tokio::io::duplex(4096);
+        tokio::spawn(serve(KeyRing::new(), agent));
+        let mut client = SharedClient::with_capacity(Client::new(client), 0);
+
+        let key = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)?;
+        let request = SignRequest::new(key.public_key().key_data().clone(), vec![]);
+
+        // the task owning the session has not run yet, so the first
+        // request stays in the queue, which then has no more space
+        assert!(client.sign(request.clone()).now_or_never().is_none());
+        let result = client.try_sign(request).now_or_never();
+        assert!(matches!(result, Some(Err(AgentError::Busy))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extensions_are_queried_once() -> TestResult {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use crate::proto::extension::QueryResponse;
+
+        /// Session answering queries, counting them.
+        struct QuerySession(Arc<AtomicUsize>);
+
+        #[async_trait::async_trait]
+        impl Session for QuerySession {
+            async fn extension(
+                &mut self,
+                _extension: Extension,
+            ) -> Result<Option<Extension>, AgentError> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                let answer = QueryResponse {
+                    extensions: vec!["session-bind@openssh.com".into()],
+                };
+                Ok(Some(Extension::new_message(answer)?))
+            }
+        }
+
+        let queries = Arc::new(AtomicUsize::new(0));
+        let (client, agent) = tokio::io::duplex(4096);
+        tokio::spawn(serve(QuerySession(Arc::clone(&queries)), agent));
+        let mut client = SharedClient::new(Client::new(client));
+
+        // the client caches the answer behind the shared handle as well
+        for _ in 0..2 {
+            assert_eq!(
+                client.query_extensions().await?,
+                ["session-bind@openssh.com"]
+            );
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+}
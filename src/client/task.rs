@@ -0,0 +1,74 @@
+//! Background tasks working with an agent session.
+
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use tokio::task::JoinHandle;
+
+use crate::error::AgentError;
+
+/// Task running until it is stopped or fails, behind
+/// [`Keepalive`](super::Keepalive) and [`KeyRefresher`](super::KeyRefresher).
+///
+/// Dropping the handle aborts the task. This must be created from within
+/// a Tokio runtime.
+pub(super) struct BackgroundTask {
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<(), AgentError>>,
+}
+
+impl BackgroundTask {
+    /// Spawn the future returned by `run`, which is told through its
+    /// receiver when to stop.
+    pub(super) fn spawn<F>(run: impl FnOnce(oneshot::Receiver<()>) -> F) -> Self
+    where
+        F: Future<Output = Result<(), AgentError>> + Send + 'static,
+    {
+        let (stop, stopped) = oneshot::channel();
+        Self {
+            stop: Some(stop),
+            task: tokio::spawn(run(stopped)),
+        }
+    }
+
+    /// Whether the task stopped by itself after failing.
+    pub(super) fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Tell the task to stop and wait for it, returning its error if it
+    /// already stopped after failing.
+    pub(super) async fn stop(mut self) -> Result<(), AgentError> {
+        if let Some(stop) = self.stop.take() {
+            // the task may have finished already
+            let _ = stop.send(());
+        }
+        (&mut self.task).await.map_err(AgentError::other)?
+    }
+}
+
+impl fmt::Debug for BackgroundTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackgroundTask")
+            .field("finished", &self.is_finished())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        if self.stop.is_some() {
+            self.task.abort();
+        }
+    }
+}
+
+/// Wait for `duration`, returning `true` if the task was told to stop
+/// meanwhile.
+pub(super) async fn sleep(duration: Duration, stopped: &mut oneshot::Receiver<()>) -> bool {
+    let sleep = Box::pin(tokio::time::sleep(duration));
+    matches!(future::select(sleep, stopped).await, Either::Right(_))
+}
@@ -0,0 +1,123 @@
+//! Connecting to agents listening on TCP.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Socket options for connecting to an agent over TCP.
+///
+/// Hosts with several interfaces can pick the address connections are
+/// made from, e.g. the one allowed by the firewall in front of the agent:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use ssh_agent_lib::{agent::Session, client::Client, client::TcpOptions};
+///
+/// let options = TcpOptions::new()
+///     .bind("10.0.0.2:0".parse().unwrap())
+///     .reuse_address(true);
+/// let mut client = Client::new(options.connect("10.0.0.1:3022".parse().unwrap()).await?);
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+///
+/// To reconnect with the same options, open the stream in the dialer
+/// passed to [`Client::connect_with`](super::Client::connect_with).
+///
+/// `TCP_NODELAY` is enabled by default, as an agent request is a single
+/// small write waiting for an answer: with Nagle's algorithm it may be
+/// held back until the acknowledgement of the previous request. Streams
+/// opened by [`connect`](super::connect) have it enabled as well.
+#[derive(Clone, Debug)]
+pub struct TcpOptions {
+    source: Option<SocketAddr>,
+    reuse_address: bool,
+    nodelay: bool,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            source: None,
+            reuse_address: false,
+            nodelay: true,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Create options connecting from any local address, with
+    /// `TCP_NODELAY` enabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect from the local `source` address.
+    ///
+    /// A port of 0 lets the system pick a free one.
+    pub fn bind(mut self, source: SocketAddr) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Set `SO_REUSEADDR` before binding the source address.
+    ///
+    /// This allows binding a fixed source port while earlier connections
+    /// from it are still in the `TIME_WAIT` state.
+    pub fn reuse_address(mut self, enabled: bool) -> Self {
+        self.reuse_address = enabled;
+        self
+    }
+
+    /// Set `TCP_NODELAY`, disabling Nagle's algorithm.
+    pub fn nodelay(mut self, enabled: bool) -> Self {
+        self.nodelay = enabled;
+        self
+    }
+
+    /// Open a connection to the agent at `address`.
+    pub async fn connect(&self, address: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if address.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(self.reuse_address)?;
+        if let Some(source) = self.source {
+            socket.bind(source)?;
+        }
+        let stream = socket.connect(address).await?;
+        stream.set_nodelay(self.nodelay)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connections_use_the_options() -> TestResult {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+
+        // pick a free port to bind to
+        let source = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let options = TcpOptions::new().bind(source).reuse_address(true);
+
+        let stream = options.connect(address).await?;
+        assert_eq!(stream.local_addr()?, source);
+        assert!(stream.nodelay()?);
+        let (_, peer) = listener.accept().await?;
+        assert_eq!(peer, source);
+
+        let stream = TcpOptions::new().nodelay(false).connect(address).await?;
+        assert!(!stream.nodelay()?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+//! Connecting to remote agents over TLS.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, Error, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+use super::{Client, TcpOptions};
+use crate::error::ConnectError;
+
+/// Connect to an agent at `address` through a TLS connection.
+///
+/// Remote agents, e.g. bridges to a hardware security module, may only
+/// be reachable over TLS. The server's certificate is checked against
+/// the roots of `config` and must be valid for `server_name`, a DNS name
+/// or an IP address:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// use std::sync::Arc;
+///
+/// use rustls::pki_types::{pem::PemObject, CertificateDer};
+/// use ssh_agent_lib::agent::Session;
+/// use ssh_agent_lib::client::{connect_tls, rustls};
+///
+/// let mut roots = rustls::RootCertStore::empty();
+/// for certificate in CertificateDer::pem_file_iter("ca.pem")? {
+///     roots.add(certificate?)?;
+/// }
+/// let config = rustls::ClientConfig::builder()
+///     .with_root_certificates(roots)
+///     .with_no_client_auth();
+/// let address = "192.0.2.1:3022".parse()?;
+/// let mut client = connect_tls(address, "agent.example.com", Arc::new(config)).await?;
+/// let identities = client.request_identities().await?;
+/// # Ok(()) }
+/// ```
+///
+/// An agent which only accepts clients with a certificate can be given
+/// one with [`ConfigBuilder::with_client_auth_cert`](tokio_rustls::rustls::ConfigBuilder::with_client_auth_cert).
+/// Any other stream wrapped in TLS can be passed to [`Client::new`] the
+/// same way.
+///
+/// This requires the `tls` feature.
+pub async fn connect_tls(
+    address: SocketAddr,
+    server_name: &str,
+    config: Arc<ClientConfig>,
+) -> Result<Client<TlsStream<TcpStream>>, ConnectError> {
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|e| ConnectError::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+    let stream = TcpOptions::new().connect(address).await?;
+    let stream = TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await?;
+    Ok(Client::new(stream))
+}
+
+/// TLS configuration accepting any server certificate.
+///
+/// **This is discouraged**: the connection is still encrypted, but anyone
+/// able to intercept it can pose as the agent, and learn about or sign
+/// with the keys of every request. It is meant for trying out agents
+/// with a self-signed certificate, which should rather be added to the
+/// roots of a [`ClientConfig`] for any lasting use.
+///
+/// The handshake signatures are still verified, so the server has to hold
+/// the private key of the certificate it presents.
+pub fn tls_config_without_verification() -> ClientConfig {
+    let provider = Arc::new(crypto::ring::default_provider());
+    ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .expect("the default protocol versions are supported by ring")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth()
+}
+
+/// Verifier accepting any certificate.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+    use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::agent::{serve, Session};
+
+    #[tokio::test]
+    async fn certificates_are_verified() -> TestResult {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(["localhost".to_owned()])?;
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+            )?;
+        let acceptor = TlsAcceptor::from(Arc::new(server));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                // failed handshakes are expected
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    tokio::spawn(serve(crate::keyring::KeyRing::new(), stream));
+                }
+            }
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der().clone())?;
+        let config = Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let mut client = connect_tls(address, "localhost", Arc::clone(&config)).await?;
+        assert!(client.request_identities().await?.is_empty());
+
+        // the certificate is not valid for the address
+        assert!(connect_tls(address, "127.0.0.1", config).await.is_err());
+        // nor trusted without the roots
+        let config = Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth(),
+        );
+        assert!(connect_tls(address, "localhost", config).await.is_err());
+
+        let config = Arc::new(tls_config_without_verification());
+        let mut client = connect_tls(address, "127.0.0.1", config).await?;
+        assert!(client.request_identities().await?.is_empty());
+
+        Ok(())
+    }
+}
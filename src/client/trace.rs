@@ -0,0 +1,207 @@
+//! Tracing spans around agent requests.
+
+use std::time::Instant;
+
+use tracing::{field::Empty, Span};
+
+use crate::error::AgentError;
+use crate::proto::{Request, Response};
+
+/// Span covering a single request and its response.
+///
+/// Only the types of the messages are recorded, never their contents,
+/// which may include passphrases, PINs and private keys.
+#[derive(Debug)]
+pub(super) struct RequestSpan {
+    span: Span,
+    start: Instant,
+}
+
+impl RequestSpan {
+    /// Open the span for `request`.
+    pub(super) fn new(request: &Request) -> Self {
+        let span = tracing::debug_span!(
+            "ssh_agent_request",
+            request = request_name(request),
+            response = Empty,
+            duration_us = Empty,
+        );
+        Self {
+            span,
+            start: Instant::now(),
+        }
+    }
+
+    /// The span itself, for instrumenting the exchange.
+    pub(super) fn span(&self) -> Span {
+        self.span.clone()
+    }
+
+    /// Record the outcome of the request and close the span.
+    pub(super) fn finish(self, result: &Result<Response, AgentError>) {
+        let response = match result {
+            Ok(Response::Failure) => "SSH_AGENT_FAILURE",
+            Ok(Response::Success | Response::SuccessWithData(_)) => "SSH_AGENT_SUCCESS",
+            Ok(Response::IdentitiesAnswer(_)) => "SSH_AGENT_IDENTITIES_ANSWER",
+            Ok(Response::SignResponse(_)) => "SSH_AGENT_SIGN_RESPONSE",
+            Ok(Response::ExtensionFailure) => "SSH_AGENT_EXTENSION_FAILURE",
+            Ok(Response::ExtensionResponse(_)) => "SSH_AGENT_EXTENSION_RESPONSE",
+            Err(_) => "error",
+        };
+        self.span.record("response", response);
+        let elapsed = u64::try_from(self.start.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.span.record("duration_us", elapsed);
+    }
+}
+
+fn request_name(request: &Request) -> &'static str {
+    match request {
+        Request::RequestIdentities => "SSH_AGENTC_REQUEST_IDENTITIES",
+        Request::SignRequest(_) => "SSH_AGENTC_SIGN_REQUEST",
+        Request::AddIdentity(_) => "SSH_AGENTC_ADD_IDENTITY",
+        Request::RemoveIdentity(_) => "SSH_AGENTC_REMOVE_IDENTITY",
+        Request::RemoveAllIdentities => "SSH_AGENTC_REMOVE_ALL_IDENTITIES",
+        Request::AddSmartcardKey(_) => "SSH_AGENTC_ADD_SMARTCARD_KEY",
+        Request::RemoveSmartcardKey(_) => "SSH_AGENTC_REMOVE_SMARTCARD_KEY",
+        Request::Lock(_) => "SSH_AGENTC_LOCK",
+        Request::Unlock(_) => "SSH_AGENTC_UNLOCK",
+        Request::AddIdConstrained(_) => "SSH_AGENTC_ADD_ID_CONSTRAINED",
+        Request::AddSmartcardKeyConstrained(_) => "SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED",
+        Request::Extension(_) => "SSH_AGENTC_EXTENSION",
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use ssh_key::private::{Ed25519Keypair, KeypairData};
+    use testresult::TestResult;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::agent::{serve, Session};
+    use crate::client::Client;
+    use crate::keyring::KeyRing;
+    use crate::proto::{AddIdentity, Credential};
+
+    /// Fields recorded for a span or event, by name.
+    type Fields = Vec<(&'static str, String)>;
+
+    /// Subscriber keeping everything recorded, spans by their id.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<(&'static str, Fields)>>>,
+        events: Arc<Mutex<Vec<Fields>>>,
+    }
+
+    struct Visitor<'a>(&'a mut Fields);
+
+    impl Visit for Visitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push((field.name(), value.to_owned()));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push((field.name(), format!("{value:?}")));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = vec![];
+            span.record(&mut Visitor(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut Visitor(fields));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = vec![];
+            event.record(&mut Visitor(&mut fields));
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[tokio::test]
+    async fn requests_are_traced_without_secrets() -> TestResult {
+        const PASSPHRASE: &str = "correct horse battery staple";
+
+        let recorder = Recorder::default();
+        let guard = tracing::subscriber::set_default(recorder.clone());
+
+        let (stream, agent) = tokio::net::UnixStream::pair()?;
+        tokio::spawn(serve(KeyRing::new(), agent));
+        let mut client = Client::new(stream);
+        let seed = [7; 32];
+        client
+            .add_identity(AddIdentity {
+                credential: Credential::Key {
+                    privkey: KeypairData::Ed25519(Ed25519Keypair::from_seed(&seed)),
+                    comment: "traced".into(),
+                },
+            })
+            .await?;
+        client.lock(PASSPHRASE.to_owned()).await?;
+        client.request_identities().await?;
+        client.unlock(PASSPHRASE.to_owned()).await?;
+        drop(guard);
+
+        let spans = recorder.spans.lock().unwrap();
+        let mut names = vec![];
+        for (name, fields) in spans.iter() {
+            assert_eq!(*name, "ssh_agent_request");
+            let keys: Vec<_> = fields.iter().map(|(key, _)| *key).collect();
+            assert_eq!(keys, ["request", "response", "duration_us"]);
+            assert!(fields[2].1.parse::<u64>().is_ok());
+            names.push((fields[0].1.as_str(), fields[1].1.as_str()));
+        }
+        assert_eq!(
+            names,
+            [
+                ("SSH_AGENTC_ADD_IDENTITY", "SSH_AGENT_SUCCESS"),
+                ("SSH_AGENTC_LOCK", "SSH_AGENT_SUCCESS"),
+                (
+                    "SSH_AGENTC_REQUEST_IDENTITIES",
+                    "SSH_AGENT_IDENTITIES_ANSWER"
+                ),
+                ("SSH_AGENTC_UNLOCK", "SSH_AGENT_SUCCESS"),
+            ]
+        );
+
+        // neither the passphrase nor any part of the key shows up
+        let secrets = [
+            PASSPHRASE.to_owned(),
+            "traced".to_owned(),
+            format!("{seed:?}"),
+            "0707070707070707".to_owned(),
+        ];
+        let events = recorder.events.lock().unwrap();
+        for (_, value) in spans
+            .iter()
+            .flat_map(|(_, fields)| fields)
+            .chain(events.iter().flatten())
+        {
+            assert!(!secrets.iter().any(|secret| value.contains(secret.as_str())));
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,19 @@
 //! SSH agent protocol framing codec
 
+use std::fmt;
+use std::io;
 use std::marker::PhantomData;
-use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "timing")]
+use std::time::Instant;
 
-use byteorder::{BigEndian, ReadBytesExt};
-use ssh_encoding::{Decode, Encode};
+use ssh_encoding::{Decode, Encode, Writer};
 use tokio_util::bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
+use zeroize::Zeroize;
 
 use super::error::AgentError;
-use super::proto::ProtoError;
+use super::proto::{decode_frame, Frame, ProtoError};
 
 /// SSH framing codec.
 ///
@@ -18,12 +22,81 @@ use super::proto::ProtoError;
 ///
 /// The reverse transformation which appends the length of the encoded data
 /// is also implemented for the given `Output` type.
+///
+/// Incoming messages longer than the maximum length are rejected with
+/// [`ProtoError::MessageTooLong`] before their contents are buffered.
+///
+/// Frames are decoded with [`decode_frame`], which can also be used
+/// directly on byte buffers.
+///
+/// The raw frames passing through the codec can be recorded with
+/// [`Codec::with_capture`].
 #[derive(Debug)]
-pub struct Codec<Input, Output>(PhantomData<Input>, PhantomData<Output>)
+pub struct Codec<Input, Output>
 where
     Input: Decode,
     Output: Encode,
-    AgentError: From<Input::Error>;
+    AgentError: From<Input::Error>,
+{
+    max_length: usize,
+    capture: Option<Capture>,
+    /// When the first byte of the message being read arrived.
+    #[cfg(feature = "timing")]
+    first_byte: Option<Instant>,
+    _marker: PhantomData<(Input, Output)>,
+}
+
+/// Default maximum message length, matching the OpenSSH agent.
+pub const MAX_MESSAGE_LENGTH: usize = 256 * 1024;
+
+impl<Input, Output> Codec<Input, Output>
+where
+    Input: Decode,
+    Output: Encode,
+    AgentError: From<Input::Error>,
+{
+    /// Create a codec accepting messages of at most `max_length` bytes.
+    ///
+    /// The length does not include the `u32` length prefix.
+    pub fn with_max_length(max_length: usize) -> Self {
+        Self {
+            max_length,
+            capture: None,
+            #[cfg(feature = "timing")]
+            first_byte: None,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Write a copy of every frame to `writer`.
+    ///
+    /// Incoming frames are written before they are decoded, so frames
+    /// which fail to decode, e.g. of unknown extensions, are recorded as
+    /// well. Outgoing frames are written once they are encoded. Frames are
+    /// written whole, including their length prefix, so a capture can be
+    /// split up again with [`decode_frame`] and replayed byte for byte.
+    ///
+    /// The writer is flushed after every frame. Failing to write fails
+    /// the message being read or written with [`AgentError::IO`].
+    ///
+    /// **Warning: captures contain the private keys of added identities,
+    /// lock passphrases and smartcard PINs in plaintext.**
+    pub fn with_capture(self, writer: impl io::Write + Send + 'static) -> Self {
+        self.captured_by(Some(Capture::new(writer)))
+    }
+
+    pub(crate) fn captured_by(mut self, capture: Option<Capture>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// When the first byte read since the last call arrived, or rather
+    /// when it was first seen by the decoder.
+    #[cfg(feature = "timing")]
+    pub(crate) fn take_first_byte(&mut self) -> Option<Instant> {
+        self.first_byte.take()
+    }
+}
 
 impl<Input, Output> Default for Codec<Input, Output>
 where
@@ -32,13 +105,14 @@ where
     AgentError: From<Input::Error>,
 {
     fn default() -> Self {
-        Self(PhantomData, PhantomData)
+        Self::with_max_length(MAX_MESSAGE_LENGTH)
     }
 }
 
 impl<Input, Output> Decoder for Codec<Input, Output>
 where
     Input: Decode,
+    Input::Error: From<ProtoError>,
     Output: Encode,
     AgentError: From<Input::Error>,
 {
@@ -46,21 +120,47 @@ where
     type Error = AgentError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let mut bytes = &src[..];
-
-        if bytes.len() < size_of::<u32>() {
-            return Ok(None);
+        #[cfg(feature = "timing")]
+        if !src.is_empty() && self.first_byte.is_none() {
+            self.first_byte = Some(Instant::now());
         }
+        if let Some(capture) = &self.capture {
+            if let Some(frame) = complete_frame(src, self.max_length) {
+                capture.write(frame)?;
+            }
+        }
+        match decode_frame(src, self.max_length)? {
+            Frame::Complete { message, length } => {
+                // decoded frames may hold private keys and passphrases,
+                // which must not stay behind in the buffer
+                src[..length].zeroize();
+                src.advance(length);
+                Ok(Some(message))
+            }
+            Frame::Incomplete { .. } => Ok(None),
+        }
+    }
 
-        let length = bytes.read_u32::<BigEndian>()? as usize;
-
-        if bytes.len() < length {
+    /// Decode the remaining frames once the stream has ended.
+    ///
+    /// A stream ending between frames is a clean close, after which the
+    /// stream yields no more messages, while a stream ending within a
+    /// frame fails with [`ProtoError::Truncated`].
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(message) = self.decode(src)? {
+            return Ok(Some(message));
+        }
+        if src.is_empty() {
             return Ok(None);
         }
-
-        let message = Self::Item::decode(&mut bytes)?;
-        src.advance(size_of::<u32>() + length);
-        Ok(Some(message))
+        let received = src.len();
+        let needed = match decode_frame::<Input>(src, self.max_length)? {
+            Frame::Incomplete { needed } => needed,
+            Frame::Complete { .. } => unreachable!("complete frames are decoded above"),
+        };
+        // the partial frame must not be reported again
+        src.clear();
+        Err(ProtoError::Truncated { received, needed }.into())
     }
 }
 
@@ -73,14 +173,482 @@ where
     type Error = AgentError;
 
     fn encode(&mut self, item: Output, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut bytes = Vec::new();
+        let len = item.encoded_len().map_err(ProtoError::SshEncoding)?;
+        dst.reserve(4 + len);
+
+        // the message is written straight into the frame buffer, so large
+        // sign requests are not copied through a temporary buffer
+        let start = dst.len();
+        dst.put_u32(len as u32);
+        if let Err(e) = item.encode(&mut BufWriter(dst)) {
+            // a partial frame would corrupt the stream
+            dst.truncate(start);
+            return Err(ProtoError::SshEncoding(e).into());
+        }
+
+        if let Some(capture) = &self.capture {
+            if let Err(e) = capture.write(&dst[start..]) {
+                dst.truncate(start);
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Overwrite all of `buffer` with zeros and empty it.
+///
+/// This includes its spare capacity, as well as the bytes consumed from
+/// its start, e.g. frames already written to the stream, which are
+/// reclaimed first.
+#[cfg(feature = "agent")]
+pub(crate) fn wipe(buffer: &mut BytesMut) {
+    buffer.clear();
+    // only succeeds if bytes were consumed, then reclaiming all of them
+    let _ = buffer.try_reclaim(buffer.capacity() + 1);
+    // the spare capacity can only be overwritten once it is initialized,
+    // and is zeroed again in a way the compiler cannot optimize away
+    buffer.resize(buffer.capacity(), 0);
+    buffer.zeroize();
+    buffer.clear();
+}
+
+/// Bytes of the frame at the start of `src`, if all of them were read.
+fn complete_frame(src: &[u8], max_length: usize) -> Option<&[u8]> {
+    let prefix = src.get(..4)?.try_into().ok()?;
+    let length = u32::from_be_bytes(prefix) as usize;
+    if length > max_length {
+        return None;
+    }
+    src.get(..4 + length)
+}
+
+/// Writer shared by the codecs recording frames to it.
+#[derive(Clone)]
+pub(crate) struct Capture(Arc<Mutex<dyn io::Write + Send>>);
+
+impl Capture {
+    pub(crate) fn new(writer: impl io::Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+
+    fn write(&self, frame: &[u8]) -> io::Result<()> {
+        let mut writer = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(frame)?;
+        writer.flush()
+    }
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Capture").finish_non_exhaustive()
+    }
+}
+
+/// Writer appending to a [`BytesMut`].
+struct BufWriter<'a>(&'a mut BytesMut);
+
+impl Writer for BufWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> ssh_encoding::Result<()> {
+        self.0.put_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use signature::Signer;
+    use ssh_key::{
+        certificate::Builder,
+        private::{Ed25519Keypair, PrivateKey},
+        public::{Ed25519PublicKey, KeyData, OpaquePublicKey, RsaPublicKey, SkEd25519},
+        Algorithm, AlgorithmName, EcdsaCurve, HashAlg, Mpint, Signature,
+    };
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::proto::{
+        AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Credential, Extension,
+        Identity, KeyConstraint, PrivateKeyData, RemoveIdentity, Request, Response, SignRequest,
+        SmartcardKey, Unparsed,
+    };
+    use crate::test_util::SharedBuffer;
+
+    #[test]
+    fn oversized_message_is_rejected() -> TestResult {
+        let mut codec = Codec::<Request, Response>::with_max_length(16);
+
+        // only the length prefix is present, so nothing may be buffered yet
+        let mut src = BytesMut::from(&[0xff, 0xff, 0xff, 0xff][..]);
+        let error = codec.decode(&mut src).unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::Proto(ProtoError::MessageTooLong {
+                length: 0xffff_ffff,
+                max_length: 16,
+            })
+        ));
+
+        let mut src = BytesMut::from(&[0, 0, 0, 1, 11][..]);
+        assert_eq!(codec.decode(&mut src)?, Some(Request::RequestIdentities));
+
+        Ok(())
+    }
+
+    /// The first bytes of the 64 byte allocation of `buffer`, including
+    /// those consumed.
+    #[allow(unsafe_code)]
+    fn allocation(mut buffer: BytesMut, written: usize) -> Vec<u8> {
+        buffer.clear();
+        let _ = buffer.try_reclaim(buffer.capacity() + 1);
+        assert_eq!(buffer.capacity(), 64);
+        // SAFETY: the first `written` bytes were initialized before
+        unsafe { buffer.set_len(written) };
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn decoded_frames_are_wiped() -> TestResult {
+        let mut codec = Codec::<Request, Response>::default();
+        let mut src = BytesMut::with_capacity(64);
+        src.extend_from_slice(&[0, 0, 0, 9, 22, 0, 0, 0, 4]);
+        src.extend_from_slice(b"pass");
+        assert_eq!(
+            codec.decode(&mut src)?,
+            Some(Request::lock("pass".to_owned()))
+        );
+        assert_eq!(allocation(src, 13), [0; 13]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "agent")]
+    fn wiped_buffers_are_zeroed() {
+        let mut buffer = BytesMut::with_capacity(64);
+        buffer.extend_from_slice(b"written passphrase, then unwritten");
+        buffer.advance(18);
+        wipe(&mut buffer);
+        assert!(buffer.is_empty());
+        assert_eq!(allocation(buffer, 64), [0; 64]);
+    }
+
+    #[test]
+    fn messages_are_decoded_one_frame_at_a_time() -> TestResult {
+        let mut codec = Codec::<Request, Response>::default();
+
+        // a constrained smartcard add is read until the end of the
+        // message, which must not include the request that follows it
+        let mut src = BytesMut::new();
+        src.put_u32(18);
+        src.put_u8(26);
+        src.put(&hex_literal::hex!("00000004 74657374 00000000 01 0000001e")[..]);
+        src.put(&[0, 0, 0, 1, 11][..]);
+
+        let Some(Request::AddSmartcardKeyConstrained(add)) = codec.decode(&mut src)? else {
+            panic!("expected a constrained smartcard add");
+        };
+        assert_eq!(add.key.id, "test");
+        assert_eq!(
+            add.constraints,
+            vec![crate::proto::KeyConstraint::Lifetime(30)]
+        );
+        assert_eq!(codec.decode(&mut src)?, Some(Request::RequestIdentities));
+
+        Ok(())
+    }
+
+    #[test]
+    fn end_of_stream_within_a_frame_is_truncation() -> TestResult {
+        let mut codec = Codec::<Request, Response>::default();
+
+        // frames still buffered are decoded, then the stream ends cleanly
+        let mut src = BytesMut::from(&[0, 0, 0, 1, 11][..]);
+        assert_eq!(
+            codec.decode_eof(&mut src)?,
+            Some(Request::RequestIdentities)
+        );
+        assert_eq!(codec.decode_eof(&mut src)?, None);
+
+        let mut src = BytesMut::from(&[0, 0, 0, 5, 13, 0][..]);
+        let error = codec.decode_eof(&mut src).unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::Proto(ProtoError::Truncated {
+                received: 6,
+                needed: 3,
+            })
+        ));
+        assert!(src.is_empty());
+
+        let mut src = BytesMut::from(&[0, 0][..]);
+        let error = codec.decode_eof(&mut src).unwrap_err();
+        assert!(matches!(
+            error,
+            AgentError::Proto(ProtoError::Truncated {
+                received: 2,
+                needed: 2,
+            })
+        ));
+
+        Ok(())
+    }
+
+    /// Longest string generated, small enough for several of them to fit
+    /// into one message.
+    const LONG_STRING: usize = MAX_MESSAGE_LENGTH / 16;
+
+    fn bytes() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            Just(vec![]),
+            vec(any::<u8>(), 1..256),
+            Just(vec![0xff; LONG_STRING]),
+        ]
+    }
+
+    fn text() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just(String::new()),
+            any::<String>(),
+            Just("\u{1f511}".repeat(LONG_STRING / 4)),
+        ]
+    }
+
+    fn unknown_algorithm() -> impl Strategy<Value = Algorithm> {
+        "[a-z]{1,16}@example\\.com"
+            .prop_map(|name| Algorithm::Other(AlgorithmName::new(name).unwrap()))
+    }
+
+    fn private_key() -> impl Strategy<Value = PrivateKey> {
+        let algorithm = prop_oneof![
+            Just(Algorithm::Ed25519),
+            Just(Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256
+            }),
+        ];
+        (algorithm, any::<u64>()).prop_map(|(algorithm, seed)| {
+            PrivateKey::random(&mut StdRng::seed_from_u64(seed), algorithm).unwrap()
+        })
+    }
+
+    fn public_key() -> impl Strategy<Value = KeyData> {
+        prop_oneof![
+            private_key().prop_map(|key| key.public_key().key_data().clone()),
+            (any::<[u8; 32]>(), text()).prop_map(|(key, application)| {
+                KeyData::SkEd25519(SkEd25519::new(Ed25519PublicKey(key), application))
+            }),
+            (bytes(), bytes()).prop_map(|(e, n)| {
+                KeyData::Rsa(RsaPublicKey {
+                    e: Mpint::from_positive_bytes(&e).unwrap(),
+                    n: Mpint::from_positive_bytes(&n).unwrap(),
+                })
+            }),
+            (unknown_algorithm(), bytes())
+                .prop_map(|(algorithm, key)| KeyData::Other(OpaquePublicKey::new(key, algorithm))),
+        ]
+    }
+
+    fn signature() -> impl Strategy<Value = Signature> {
+        let hash = prop_oneof![Just(HashAlg::Sha256), Just(HashAlg::Sha512)];
+        prop_oneof![
+            (private_key(), bytes()).prop_map(|(key, data)| key.try_sign(&data).unwrap()),
+            (hash, bytes()).prop_map(|(hash, signature)| {
+                Signature::new(Algorithm::Rsa { hash: Some(hash) }, signature).unwrap()
+            }),
+            // the signature proper followed by the flags and the counter
+            vec(any::<u8>(), 69).prop_map(|signature| Signature::new(
+                Algorithm::SkEd25519,
+                signature
+            )
+            .unwrap()),
+            (unknown_algorithm(), vec(any::<u8>(), 1..256))
+                .prop_map(|(algorithm, signature)| Signature::new(algorithm, signature).unwrap()),
+        ]
+    }
+
+    fn credential() -> impl Strategy<Value = Credential> {
+        prop_oneof![
+            (private_key(), text()).prop_map(|(key, comment)| Credential::Key {
+                privkey: key.key_data().clone(),
+                comment,
+            }),
+            (any::<[u8; 32]>(), any::<u64>(), text(), text()).prop_map(
+                |(seed, ca, principal, comment)| {
+                    let key = Ed25519Keypair::from_seed(&seed);
+                    let ca = PrivateKey::random(&mut StdRng::seed_from_u64(ca), Algorithm::Ed25519)
+                        .unwrap();
+                    let mut builder =
+                        Builder::new([0; 16], key.public, 0, i64::MAX as u64).unwrap();
+                    builder.valid_principal(principal).unwrap();
+                    Credential::Cert {
+                        algorithm: Algorithm::Ed25519,
+                        certificate: builder.sign(&ca).unwrap(),
+                        privkey: PrivateKeyData::Ed25519(key),
+                        comment,
+                    }
+                }
+            ),
+        ]
+    }
+
+    fn extension() -> impl Strategy<Value = Extension> {
+        (text(), bytes()).prop_map(|(name, details)| Extension {
+            name,
+            details: Unparsed::from(details),
+        })
+    }
+
+    fn constraints() -> impl Strategy<Value = Vec<KeyConstraint>> {
+        let constraint = prop_oneof![
+            any::<u32>().prop_map(KeyConstraint::Lifetime),
+            Just(KeyConstraint::Confirm),
+            any::<u32>().prop_map(KeyConstraint::MaxSign),
+            extension().prop_map(KeyConstraint::Extension),
+        ];
+        vec(constraint, 0..4)
+    }
+
+    fn smartcard_key() -> impl Strategy<Value = SmartcardKey> {
+        (text(), text()).prop_map(|(id, pin)| SmartcardKey {
+            id,
+            pin: pin.into(),
+            options: vec![],
+        })
+    }
+
+    /// Smartcard keys with options, which only constrained adds carry.
+    fn smartcard_key_with_options() -> impl Strategy<Value = SmartcardKey> {
+        (smartcard_key(), vec((text(), text()), 0..4))
+            .prop_map(|(key, options)| SmartcardKey { options, ..key })
+    }
+
+    fn request() -> impl Strategy<Value = Request> {
+        prop_oneof![
+            Just(Request::RequestIdentities),
+            (public_key(), bytes(), any::<u32>()).prop_map(|(pubkey, data, flags)| {
+                Request::SignRequest(SignRequest {
+                    pubkey,
+                    certificate: None,
+                    data: data.into(),
+                    flags,
+                })
+            }),
+            credential().prop_map(|credential| Request::AddIdentity(AddIdentity { credential })),
+            public_key().prop_map(|pubkey| Request::RemoveIdentity(RemoveIdentity { pubkey })),
+            Just(Request::RemoveAllIdentities),
+            smartcard_key().prop_map(Request::AddSmartcardKey),
+            smartcard_key().prop_map(Request::RemoveSmartcardKey),
+            text().prop_map(|passphrase| Request::Lock(passphrase.into())),
+            text().prop_map(|passphrase| Request::Unlock(passphrase.into())),
+            (credential(), constraints()).prop_map(|(credential, constraints)| {
+                Request::AddIdConstrained(AddIdentityConstrained {
+                    identity: AddIdentity { credential },
+                    constraints,
+                })
+            }),
+            (smartcard_key_with_options(), constraints()).prop_map(|(key, constraints)| {
+                Request::AddSmartcardKeyConstrained(AddSmartcardKeyConstrained { key, constraints })
+            }),
+            extension().prop_map(Request::Extension),
+        ]
+    }
+
+    fn response() -> impl Strategy<Value = Response> {
+        let identity =
+            (public_key(), bytes()).prop_map(|(pubkey, comment)| Identity { pubkey, comment });
+        prop_oneof![
+            Just(Response::Failure),
+            Just(Response::Success),
+            vec(identity, 0..4).prop_map(Response::IdentitiesAnswer),
+            signature().prop_map(Response::SignResponse),
+            Just(Response::ExtensionFailure),
+            extension().prop_map(Response::ExtensionResponse),
+        ]
+    }
+
+    /// Encode `message` with [`Codec`] and decode it again.
+    fn round_trip<T>(message: T) -> Result<T, AgentError>
+    where
+        T: Decode<Error = ProtoError> + Encode,
+    {
+        let mut codec = Codec::<T, T>::default();
+        let mut bytes = BytesMut::new();
+        codec.encode(message, &mut bytes)?;
+        let decoded = codec.decode(&mut bytes)?.expect("a complete frame");
+        assert!(bytes.is_empty(), "{} bytes left over", bytes.len());
+        Ok(decoded)
+    }
+
+    proptest! {
+        #[test]
+        fn requests_round_trip(request in request()) {
+            prop_assert_eq!(round_trip(request.clone()).unwrap(), request);
+        }
+
+        #[test]
+        fn responses_round_trip(response in response()) {
+            prop_assert_eq!(round_trip(response.clone()).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn large_sign_request_is_framed() -> TestResult {
+        let key = KeyData::Ed25519(Ed25519PublicKey([7; 32]));
+        let request =
+            Request::SignRequest(SignRequest::new(key, vec![0x5a; MAX_MESSAGE_LENGTH / 2]));
+
+        let mut codec = Codec::<Request, Request>::default();
+        let mut bytes = BytesMut::new();
+        codec.encode(request.clone(), &mut bytes)?;
+        let length = u32::from_be_bytes(bytes[..4].try_into()?) as usize;
+        assert_eq!(length, request.encoded_len()?);
+        assert_eq!(bytes.len(), 4 + length);
+
+        assert_eq!(codec.decode(&mut bytes)?, Some(request));
+        Ok(())
+    }
+
+    #[test]
+    fn longest_message_round_trips() -> TestResult {
+        // message type and string length leave the rest for the passphrase
+        let longest = Request::Lock("x".repeat(MAX_MESSAGE_LENGTH - 5).into());
+        assert_eq!(round_trip(longest.clone())?, longest);
+
+        let too_long = Request::Lock("x".repeat(MAX_MESSAGE_LENGTH - 4).into());
+        assert!(matches!(
+            round_trip(too_long),
+            Err(AgentError::Proto(ProtoError::MessageTooLong { .. }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn frames_are_captured_with_their_length() -> TestResult {
+        let captured = SharedBuffer::default();
+        let mut codec = Codec::<Response, Request>::default().with_capture(captured.clone());
+
+        let mut outgoing = BytesMut::new();
+        codec.encode(Request::RequestIdentities, &mut outgoing)?;
+        assert_eq!(&outgoing[..], [0, 0, 0, 1, 11]);
 
-        let len = item.encoded_len().unwrap() as u32;
-        len.encode(&mut bytes).map_err(ProtoError::SshEncoding)?;
+        // an incomplete frame is captured once it is complete
+        let mut incoming = BytesMut::from(&[0, 0, 0, 1][..]);
+        assert_eq!(codec.decode(&mut incoming)?, None);
+        incoming.extend_from_slice(&[6]);
+        assert_eq!(codec.decode(&mut incoming)?, Some(Response::Success));
 
-        item.encode(&mut bytes).map_err(ProtoError::SshEncoding)?;
-        dst.put(&*bytes);
+        // unknown messages are captured before failing to decode
+        let mut incoming = BytesMut::from(&[0, 0, 0, 2, 0xee, 0][..]);
+        assert!(codec.decode(&mut incoming).is_err());
 
+        assert_eq!(
+            captured.contents(),
+            [0, 0, 0, 1, 11, 0, 0, 0, 1, 6, 0, 0, 0, 2, 0xee, 0]
+        );
         Ok(())
     }
 }
@@ -2,6 +2,7 @@
 
 use std::io;
 
+use ssh_key::{Algorithm, HashAlg};
 use thiserror::Error;
 
 use crate::proto::ProtoError;
@@ -28,6 +29,101 @@ pub enum AgentError {
     /// Generic agent failure
     #[error("Generic agent failure")]
     Failure,
+
+    /// The agent answered a request with a failure message.
+    ///
+    /// The agent protocol does not define any details for failures,
+    /// so only the kind of the failure message is available.
+    #[error("Agent: Request failed (message {code})")]
+    AgentFailure {
+        /// Message number of the failure response:
+        /// [`SSH_AGENT_FAILURE`](crate::proto::SSH_AGENT_FAILURE) or
+        /// [`SSH_AGENT_EXTENSION_FAILURE`](crate::proto::SSH_AGENT_EXTENSION_FAILURE).
+        code: u8,
+    },
+
+    /// The agent returned a signature made with a different algorithm
+    /// than the one requested.
+    #[error("Agent: Requested a {requested} signature, but got {returned}")]
+    AlgorithmMismatch {
+        /// Algorithm implied by the key and flags of the sign request.
+        requested: Algorithm,
+        /// Algorithm of the signature returned by the agent.
+        returned: Algorithm,
+    },
+
+    /// The key cannot sign with the requested hash.
+    #[error("Agent: {algorithm} keys cannot sign with {hash}")]
+    HashUnsupported {
+        /// Algorithm of the key.
+        algorithm: Algorithm,
+        /// Requested hash.
+        hash: HashAlg,
+    },
+
+    /// The agent does not hold the key a signature was requested for.
+    ///
+    /// Only reported by clients which check for keys before signing.
+    #[error("Agent: The requested key is not held by the agent")]
+    KeyNotFound,
+
+    /// The private key is encrypted, but no passphrase was given.
+    #[error("Agent: Private key is encrypted, a passphrase is required")]
+    KeyEncrypted,
+
+    /// The agent did not add the identity.
+    ///
+    /// Reported instead of a plain failure by clients talking to
+    /// `gpg-agent`, which fails to add keys it cannot protect with a
+    /// passphrase, e.g. without a pinentry program. See
+    /// [`Client`](crate::client::Client#gpg-agent).
+    #[error("Agent: The agent did not add the identity")]
+    KeyNotAdded,
+
+    /// The request could not be queued without waiting.
+    #[error("Agent: Too many requests are queued")]
+    Busy,
+
+    /// The agent answered a request with a response of the wrong kind.
+    ///
+    /// Responses are named like the variants of
+    /// [`Response`](crate::proto::Response), e.g. `IdentitiesAnswer`
+    /// for an agent listing its keys instead of signing.
+    #[error("Agent: Expected {expected} response, but got {got}")]
+    UnexpectedResponse {
+        /// Response the request should have been answered with.
+        expected: &'static str,
+        /// Response the agent sent.
+        got: &'static str,
+    },
+
+    /// The agent did not respond in time.
+    #[error("Agent: Request timed out")]
+    Timeout,
+
+    /// The agent is locked, or thought to be.
+    ///
+    /// Sessions which know their lock state, like
+    /// [`KeyRing`](crate::keyring::KeyRing), return this for requests
+    /// they reject while locked. The agent protocol has no such failure,
+    /// so the [client](crate::client::Client) can only guess it: it
+    /// reports a failed sign request as locked if the agent listed no
+    /// identities last time, or this client locked it. An agent which
+    /// really holds no keys, or was unlocked by another client since,
+    /// is reported as locked as well. Use
+    /// [`Client::is_locked`](crate::client::Client::is_locked) to narrow it down.
+    #[error("Agent: The agent is locked")]
+    Locked,
+
+    /// The agent closed the connection before answering.
+    ///
+    /// The agent ended the stream cleanly between messages, e.g. because
+    /// it is shutting down or does not serve more than one request per
+    /// connection. The request may be repeated after reconnecting. An
+    /// agent closing the connection in the middle of a response is
+    /// reported as [`ProtoError::Truncated`] instead.
+    #[error("Agent: The agent closed the connection")]
+    Disconnected,
 }
 
 impl AgentError {
@@ -36,3 +132,51 @@ impl AgentError {
         Self::Other(Box::new(error))
     }
 }
+
+/// Error connecting to an agent.
+///
+/// This requires the `agent` feature.
+///
+/// The kinds of I/O errors that usually have a simple explanation are
+/// told apart, so a program can suggest a fix to its user. Errors of
+/// connecting a socket outside of this library, e.g. when converting a
+/// [`Binding`](service_binding::Binding) into a stream, can be classified
+/// the same way with [`ConnectError::from`].
+#[cfg(feature = "agent")]
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    /// The socket or pipe does not exist, usually because no agent was
+    /// started or `SSH_AUTH_SOCK` is out of date.
+    #[error("Agent: Socket not found: {0}")]
+    NotFound(#[source] io::Error),
+
+    /// The socket or pipe belongs to another user.
+    #[error("Agent: Permission denied: {0}")]
+    PermissionDenied(#[source] io::Error),
+
+    /// The socket exists, but no agent is listening on it anymore.
+    #[error("Agent: Connection refused: {0}")]
+    ConnectionRefused(#[source] io::Error),
+
+    /// Any other input/output error.
+    #[error("Agent: I/O error: {0}")]
+    Io(#[source] io::Error),
+
+    /// The `SSH_AUTH_SOCK` environment variable, which names the socket
+    /// of the agent, is not set, or the agent was disabled in a
+    /// configuration file.
+    #[error("Agent: SSH_AUTH_SOCK is not set or the agent is disabled, no agent to connect to")]
+    NotConfigured,
+}
+
+#[cfg(feature = "agent")]
+impl From<io::Error> for ConnectError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(error),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(error),
+            io::ErrorKind::ConnectionRefused => Self::ConnectionRefused(error),
+            _ => Self::Io(error),
+        }
+    }
+}
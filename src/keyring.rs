@@ -0,0 +1,767 @@
+//! In-memory software keyring.
+//!
+//! [`KeyRing`] is a ready-made [`Session`] which keeps private keys in
+//! memory and signs with them using `ssh-key`. It is meant for tests and
+//! simple agents which do not need to store keys anywhere else.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use signature::Signer;
+use ssh_key::{public::KeyData, HashAlg, PrivateKey, Signature};
+use subtle::ConstantTimeEq;
+
+use crate::agent::{accept_connections, Agent, ListeningSocket, Session};
+use crate::error::AgentError;
+use crate::proto::{
+    signature::RSA_SHA2_512, AddIdentity, AddIdentityConstrained, Bytes, Credential, Identity,
+    KeyConstraint, ProtoError, RemoveIdentity, SignRequest, Zeroizing,
+};
+
+/// Callback deciding whether a key with the confirm constraint may be used.
+type Confirm = Arc<dyn Fn(ConfirmRequest) -> BoxFuture<'static, bool> + Send + Sync>;
+
+/// Signature waiting for the user to confirm the use of a key.
+///
+/// Passed to the callback set with [`KeyRing::with_async_confirmation`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ConfirmRequest {
+    /// Key asked to sign, with its comment.
+    pub identity: Identity,
+
+    /// Data to be signed.
+    pub data: Bytes,
+
+    /// [Signature flags](crate::proto::signature) of the request.
+    pub flags: u32,
+}
+
+impl ConfirmRequest {
+    /// Fingerprint of the key, as shown by `ssh-add -l`.
+    pub fn key_fingerprint(&self) -> String {
+        self.identity
+            .pubkey
+            .fingerprint(HashAlg::Sha256)
+            .to_string()
+    }
+
+    /// Digest of the data to be signed, which is shorter to show than
+    /// the data itself.
+    pub fn data_digest(&self, hash: HashAlg) -> Vec<u8> {
+        hash.digest(&self.data)
+    }
+}
+
+/// Agent storing keys in memory.
+///
+/// Cloning a keyring yields a handle to the same keys, and all sessions
+/// accepted by [`Agent::listen`] share them:
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use ssh_agent_lib::{keyring::KeyRing, Agent};
+///
+/// let listener = tokio::net::UnixListener::bind("ssh-agent.sock")?;
+/// KeyRing::new().listen(listener).await?;
+/// # Ok(()) }
+/// ```
+///
+/// Keys added with a lifetime constraint are removed once it expires.
+/// Keys added with the confirm constraint can only be used for signing
+/// if a callback set with [`KeyRing::with_confirmation`] or
+/// [`KeyRing::with_async_confirmation`] approves it.
+/// Keys added with the maxsign constraint stop signing once they made
+/// as many signatures as it allows, but stay listed.
+///
+/// RSA keys sign with SHA-512 only, as supported by `ssh-key`, so
+/// requests without the [`RSA_SHA2_512`] flag are rejected.
+///
+/// Keys stay in memory until the process exits, unless
+/// [`KeyRing::wipe_on_shutdown`] or [`KeyRing::wipe_on`] is used. Private keys are kept
+/// encoded in buffers which are overwritten with zeroes as soon as the key
+/// is removed, expires or is [wiped](KeyRing::wipe_identity). Each
+/// signature is made with a copy decoded from the buffer, which `ssh-key`
+/// zeroizes once the signature is made.
+#[derive(Clone)]
+pub struct KeyRing {
+    state: Arc<Mutex<State>>,
+    confirm: Option<Confirm>,
+    shutdown: Option<Shutdown>,
+}
+
+/// When to remove all keys and stop listening.
+#[derive(Clone)]
+enum Shutdown {
+    /// Once the process is asked to terminate.
+    Signals,
+    /// Once the future completes, which the first listener takes.
+    Future(Arc<Mutex<Option<BoxFuture<'static, ()>>>>),
+}
+
+#[derive(Default)]
+struct State {
+    keys: Vec<Key>,
+    passphrase: Option<Zeroizing<String>>,
+}
+
+struct Key {
+    public: KeyData,
+    /// The private key in the OpenSSH format, zeroized when dropped.
+    private: Zeroizing<Vec<u8>>,
+    comment: String,
+    expires: Option<Instant>,
+    confirm: bool,
+    signatures_left: Option<u32>,
+}
+
+impl Key {
+    fn identity(&self) -> Identity {
+        Identity {
+            pubkey: self.public.clone(),
+            comment: self.comment.clone().into(),
+        }
+    }
+}
+
+impl State {
+    /// Fail if the keyring is locked.
+    fn unlocked(&self) -> Result<(), AgentError> {
+        if self.passphrase.is_some() {
+            Err(AgentError::Locked)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        self.keys
+            .retain(|key| key.expires.map_or(true, |expires| expires > now));
+    }
+
+    fn find(&mut self, pubkey: &KeyData) -> Option<&mut Key> {
+        self.keys.iter_mut().find(|key| key.public == *pubkey)
+    }
+
+    /// Find a key which can make another signature.
+    fn usable(&mut self, pubkey: &KeyData) -> Result<&mut Key, AgentError> {
+        let key = self
+            .find(pubkey)
+            .ok_or_else(|| io::Error::other("identity not found"))?;
+        if key.signatures_left == Some(0) {
+            return Err(io::Error::other("key reached its maximum number of signatures").into());
+        }
+        Ok(key)
+    }
+
+    fn add(
+        &mut self,
+        credential: Credential,
+        constraints: Vec<KeyConstraint>,
+    ) -> Result<(), AgentError> {
+        self.unlocked()?;
+
+        let Credential::Key { privkey, comment } = credential else {
+            return Err(io::Error::other("certificates are not supported").into());
+        };
+        let private = PrivateKey::new(privkey, &comment).map_err(ProtoError::from)?;
+
+        let mut key = Key {
+            public: private.public_key().key_data().clone(),
+            private: private.to_bytes().map_err(ProtoError::from)?,
+            comment,
+            expires: None,
+            confirm: false,
+            signatures_left: None,
+        };
+        for constraint in constraints {
+            match constraint {
+                KeyConstraint::Lifetime(seconds) => {
+                    key.expires = Some(Instant::now() + Duration::from_secs(seconds.into()));
+                }
+                KeyConstraint::Confirm => key.confirm = true,
+                KeyConstraint::MaxSign(signatures) => key.signatures_left = Some(signatures),
+                KeyConstraint::Extension(extension) => {
+                    return Err(io::Error::other(format!(
+                        "unsupported key constraint: {}",
+                        extension.name
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        // adding a key again replaces its comment and constraints
+        self.keys.retain(|existing| existing.public != key.public);
+        self.keys.push(key);
+        Ok(())
+    }
+}
+
+impl KeyRing {
+    /// Create an empty keyring.
+    // `Default` is not implemented on purpose: the blanket `Agent`
+    // implementation would then give every session its own keyring.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self {
+            state: Default::default(),
+            confirm: None,
+            shutdown: None,
+        }
+    }
+
+    /// Set the callback asked to approve each use of a key which was
+    /// added with the confirm constraint.
+    ///
+    /// Without a callback such keys cannot be used for signing.
+    pub fn with_confirmation(
+        self,
+        confirm: impl Fn(&Identity) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.with_async_confirmation(move |request| {
+            let confirmed = confirm(&request.identity);
+            async move { confirmed }
+        })
+    }
+
+    /// Set an asynchronous callback asked to approve each use of a key
+    /// which was added with the confirm constraint.
+    ///
+    /// The callback can wait for the user to answer a prompt, showing
+    /// the key and a digest of the data to be signed:
+    ///
+    /// ```
+    /// use ssh_agent_lib::keyring::KeyRing;
+    /// use ssh_key::HashAlg;
+    ///
+    /// let keyring = KeyRing::new().with_async_confirmation(|request| async move {
+    ///     let digest = request.data_digest(HashAlg::Sha256);
+    ///     println!("Allow signing with {}?", request.key_fingerprint());
+    ///     println!("Data digest: {digest:02x?}");
+    ///     // ask the user, e.g. with a desktop notification
+    ///     false
+    /// });
+    /// ```
+    ///
+    /// The keyring is not blocked while waiting, so requests from other
+    /// connections are answered in the meantime. If the key is removed
+    /// or the keyring locked before the answer, the signature is not
+    /// made.
+    pub fn with_async_confirmation<F, Fut>(mut self, confirm: F) -> Self
+    where
+        F: Fn(ConfirmRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.confirm = Some(Arc::new(move |request| confirm(request).boxed()));
+        self
+    }
+
+    /// Remove all keys and stop listening when the process is asked to
+    /// terminate.
+    ///
+    /// When enabled, [`Agent::listen`] returns once the process receives
+    /// `SIGTERM`, `SIGHUP` or `SIGINT` (Ctrl-C on Windows), after removing
+    /// all keys, so that they do not outlive the agent even though their
+    /// lifetime has not expired yet. Connections which are still open are
+    /// answered as if the keyring was empty.
+    ///
+    /// This replaces a future set with [`KeyRing::wipe_on`].
+    pub fn wipe_on_shutdown(mut self, enabled: bool) -> Self {
+        self.shutdown = enabled.then_some(Shutdown::Signals);
+        self
+    }
+
+    /// Remove all keys and stop listening once `shutdown` completes.
+    ///
+    /// Like [`KeyRing::wipe_on_shutdown`], which waits for termination
+    /// signals instead, this makes [`Agent::listen`] return after removing
+    /// all keys. It suits agents handling signals by themselves or
+    /// embedded in a larger program, e.g. with a channel:
+    ///
+    /// ```no_run
+    /// # #[cfg(unix)]
+    /// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+    /// use ssh_agent_lib::agent::Agent;
+    /// use ssh_agent_lib::keyring::KeyRing;
+    ///
+    /// let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    /// let keyring = KeyRing::new().wipe_on(async move {
+    ///     let _ = stopped.await;
+    /// });
+    /// let listener = tokio::net::UnixListener::bind("agent.sock")?;
+    /// tokio::spawn(keyring.listen(listener));
+    /// // ... once the program is done ...
+    /// let _ = stop.send(());
+    /// # Ok(()) }
+    /// ```
+    ///
+    /// Only the first call to `listen` on the keyring or its clones waits
+    /// for `shutdown`; later ones listen until they fail.
+    pub fn wipe_on(mut self, shutdown: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(Shutdown::Future(Arc::new(Mutex::new(Some(
+            shutdown.boxed(),
+        )))));
+        self
+    }
+
+    /// Remove the key `pubkey` right away, returning whether it was held.
+    ///
+    /// Unlike [removing](Session::remove_identity) it, this also works
+    /// while the keyring is locked, e.g. to get rid of a key thought to
+    /// be compromised without knowing the passphrase. Its private key is
+    /// overwritten with zeroes before it is freed.
+    pub fn wipe_identity(&self, pubkey: &KeyData) -> bool {
+        let mut state = self.state();
+        let count = state.keys.len();
+        state.keys.retain(|key| key.public != *pubkey);
+        state.keys.len() != count
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.remove_expired();
+        state
+    }
+}
+
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state();
+        f.debug_struct("KeyRing")
+            .field("keys", &state.keys.len())
+            .field("locked", &state.passphrase.is_some())
+            .field("wipe_on_shutdown", &self.shutdown.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Wait until the process is asked to terminate.
+fn shutdown_signal() -> io::Result<impl Future<Output = ()>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = [
+            signal(SignalKind::terminate())?,
+            signal(SignalKind::hangup())?,
+            signal(SignalKind::interrupt())?,
+        ];
+        Ok(async move {
+            let received = signals.iter_mut().map(|signal| signal.recv().boxed());
+            futures::future::select_all(received).await;
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+    }
+}
+
+#[crate::async_trait]
+impl Agent for KeyRing {
+    fn new_session(&mut self) -> impl Session {
+        self.clone()
+    }
+
+    async fn listen<S>(mut self, socket: S) -> Result<(), AgentError>
+    where
+        S: ListeningSocket + fmt::Debug + Send,
+    {
+        let shutdown = match &self.shutdown {
+            Some(Shutdown::Signals) => Some(shutdown_signal()?.boxed()),
+            Some(Shutdown::Future(future)) => {
+                future.lock().unwrap_or_else(|e| e.into_inner()).take()
+            }
+            None => None,
+        };
+        let Some(shutdown) = shutdown else {
+            return accept_connections(&mut self, socket).await;
+        };
+
+        let keyring = self.clone();
+        futures::select! {
+            result = accept_connections(&mut self, socket).fuse() => result,
+            () = shutdown.fuse() => {
+                log::info!("Shutting down, removing all keys");
+                keyring.state().keys.clear();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[crate::async_trait]
+impl Session for KeyRing {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        let state = self.state();
+        if state.passphrase.is_some() {
+            // a locked agent pretends to hold no keys
+            return Ok(vec![]);
+        }
+        Ok(state.keys.iter().map(Key::identity).collect())
+    }
+
+    async fn count_identities(&mut self) -> Result<usize, AgentError> {
+        let state = self.state();
+        Ok(if state.passphrase.is_some() {
+            0
+        } else {
+            state.keys.len()
+        })
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        if let KeyData::Rsa(_) = request.pubkey {
+            if request.flags & RSA_SHA2_512 == 0 {
+                return Err(io::Error::other("only rsa-sha2-512 signatures are supported").into());
+            }
+        }
+
+        let confirm = {
+            let mut state = self.state();
+            state.unlocked()?;
+            let key = state.usable(&request.pubkey)?;
+            key.confirm.then(|| key.identity())
+        };
+        if let Some(identity) = confirm {
+            // the state is not locked while waiting for the user
+            let confirmed = match &self.confirm {
+                Some(confirm) => {
+                    confirm(ConfirmRequest {
+                        identity,
+                        data: request.data.clone(),
+                        flags: request.flags,
+                    })
+                    .await
+                }
+                None => false,
+            };
+            if !confirmed {
+                return Err(io::Error::other("use of the key was not confirmed").into());
+            }
+        }
+
+        let mut state = self.state();
+        state.unlocked()?;
+        let key = state.usable(&request.pubkey)?;
+        let signature = PrivateKey::from_bytes(&key.private)
+            .map_err(ProtoError::from)?
+            .try_sign(&request.data)
+            .map_err(ProtoError::from)?;
+        if let Some(left) = &mut key.signatures_left {
+            *left -= 1;
+        }
+        Ok(signature)
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.state().add(identity.credential, vec![])
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.state()
+            .add(identity.identity.credential, identity.constraints)
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        let mut state = self.state();
+        state.unlocked()?;
+
+        let count = state.keys.len();
+        state.keys.retain(|key| key.public != identity.pubkey);
+        if state.keys.len() == count {
+            return Err(io::Error::other("identity not found").into());
+        }
+        Ok(())
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        let mut state = self.state();
+        state.unlocked()?;
+        state.keys.clear();
+        Ok(())
+    }
+
+    async fn lock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        let mut state = self.state();
+        state.unlocked()?;
+        state.passphrase = Some(key);
+        Ok(())
+    }
+
+    async fn unlock(&mut self, key: Zeroizing<String>) -> Result<(), AgentError> {
+        let mut state = self.state();
+        // compare in constant time, so that the time taken to reject a
+        // passphrase does not tell how many of its leading bytes matched
+        // (only the length of the passphrase is not hidden)
+        let matches = state
+            .passphrase
+            .as_ref()
+            .is_some_and(|passphrase| bool::from(passphrase.as_bytes().ct_eq(key.as_bytes())));
+        if !matches {
+            return Err(io::Error::other("keyring is not locked with this passphrase").into());
+        }
+        state.passphrase = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use signature::Verifier;
+    use ssh_key::Algorithm;
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::proto::KeyConstraints;
+
+    fn ed25519_key() -> Result<PrivateKey, ssh_key::Error> {
+        PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519)
+    }
+
+    fn add(key: &PrivateKey, constraints: KeyConstraints) -> AddIdentityConstrained {
+        AddIdentityConstrained {
+            identity: AddIdentity {
+                credential: Credential::Key {
+                    privkey: key.key_data().clone(),
+                    comment: "test".into(),
+                },
+            },
+            constraints: constraints.build(),
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_and_remove() -> TestResult {
+        let mut keyring = KeyRing::new();
+        let key = ed25519_key()?;
+        keyring
+            .add_identity_constrained(add(&key, KeyConstraints::new()))
+            .await?;
+
+        let identities = keyring.request_identities().await?;
+        assert_eq!(identities.len(), 1);
+        assert_eq!(&identities[0].pubkey, key.public_key().key_data());
+
+        let signature = keyring
+            .sign_ssh_data(key.public_key(), b"data to be signed")
+            .await?;
+        key.public_key()
+            .key_data()
+            .verify(b"data to be signed", &signature)?;
+
+        keyring
+            .remove_identity(RemoveIdentity {
+                pubkey: key.public_key().key_data().clone(),
+            })
+            .await?;
+        assert!(keyring.request_identities().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sign_rsa_with_builder_defaults() -> TestResult {
+        use ssh_encoding::Decode;
+
+        use crate::proto::{Request, SignRequest};
+
+        let message = include_bytes!("../tests/messages/req-add-identity.bin");
+        let Request::AddIdentity(identity) = Request::decode(&mut &message[..])? else {
+            panic!("expected an identity");
+        };
+        let mut keyring = KeyRing::new();
+        keyring.add_identity(identity).await?;
+        let key = ssh_key::PublicKey::from(keyring.request_identities().await?[0].pubkey.clone());
+
+        // the keyring only makes rsa-sha2-512 signatures
+        let request = SignRequest::builder()
+            .key(&key)
+            .data(b"data".to_vec())
+            .build()?;
+        let signature = keyring.sign(request).await?;
+        assert_eq!(
+            signature.algorithm(),
+            Algorithm::Rsa {
+                hash: Some(ssh_key::HashAlg::Sha512)
+            }
+        );
+        key.key_data().verify(b"data", &signature)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lock_and_unlock() -> TestResult {
+        let mut keyring = KeyRing::new();
+        let key = ed25519_key()?;
+        keyring
+            .add_identity_constrained(add(&key, KeyConstraints::new()))
+            .await?;
+
+        assert_eq!(keyring.count_identities().await?, 1);
+
+        keyring.lock("secret".to_owned().into()).await?;
+        assert!(keyring.request_identities().await?.is_empty());
+        assert_eq!(keyring.count_identities().await?, 0);
+        assert!(matches!(
+            keyring.sign_ssh_data(key.public_key(), b"data").await,
+            Err(AgentError::Locked)
+        ));
+        assert!(keyring.unlock("wrong".to_owned().into()).await.is_err());
+
+        keyring.unlock("secret".to_owned().into()).await?;
+        assert_eq!(keyring.request_identities().await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wipe_while_locked() -> TestResult {
+        let mut keyring = KeyRing::new();
+        let (wiped, kept) = (ed25519_key()?, ed25519_key()?);
+        for key in [&wiped, &kept] {
+            keyring
+                .add_identity_constrained(add(key, KeyConstraints::new()))
+                .await?;
+        }
+
+        keyring.lock("secret".to_owned().into()).await?;
+        assert!(keyring.wipe_identity(wiped.public_key().key_data()));
+        assert!(!keyring.wipe_identity(wiped.public_key().key_data()));
+
+        keyring.unlock("secret".to_owned().into()).await?;
+        let identities = keyring.request_identities().await?;
+        assert_eq!(identities.len(), 1);
+        assert_eq!(&identities[0].pubkey, kept.public_key().key_data());
+        keyring.sign_ssh_data(kept.public_key(), b"data").await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn constraints() -> TestResult {
+        let mut keyring = KeyRing::new().with_confirmation(|identity| identity.comment == b"test");
+
+        let expiring = ed25519_key()?;
+        keyring
+            .add_identity_constrained(add(
+                &expiring,
+                KeyConstraints::new().lifetime(Duration::ZERO)?,
+            ))
+            .await?;
+        assert!(keyring.request_identities().await?.is_empty());
+
+        let confirmed = ed25519_key()?;
+        keyring
+            .add_identity_constrained(add(&confirmed, KeyConstraints::new().confirm()))
+            .await?;
+        keyring
+            .sign_ssh_data(confirmed.public_key(), b"data")
+            .await?;
+
+        // without a confirmation callback the key cannot be used
+        let mut keyring = KeyRing::new();
+        keyring
+            .add_identity_constrained(add(&confirmed, KeyConstraints::new().confirm()))
+            .await?;
+        assert!(keyring
+            .sign_ssh_data(confirmed.public_key(), b"data")
+            .await
+            .is_err());
+
+        let limited = ed25519_key()?;
+        keyring
+            .add_identity_constrained(add(&limited, KeyConstraints::new().maxsign(2)))
+            .await?;
+        for _ in 0..2 {
+            keyring.sign_ssh_data(limited.public_key(), b"data").await?;
+        }
+        assert!(keyring
+            .sign_ssh_data(limited.public_key(), b"data")
+            .await
+            .is_err());
+        assert_eq!(keyring.request_identities().await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn asynchronous_confirmation() -> TestResult {
+        let (asked, mut requests) = futures::channel::mpsc::unbounded();
+        let keyring = KeyRing::new().with_async_confirmation(move |request| {
+            let (answer, answered) = futures::channel::oneshot::channel();
+            asked.unbounded_send((request, answer)).unwrap();
+            async move { answered.await.unwrap_or(false) }
+        });
+
+        let key = ed25519_key()?;
+        let mut session = keyring.clone();
+        session
+            .add_identity_constrained(add(&key, KeyConstraints::new().confirm()))
+            .await?;
+        let public = key.public_key().clone();
+        let sign = tokio::spawn(async move { session.sign_ssh_data(&public, b"data").await });
+
+        let (request, answer) = futures::StreamExt::next(&mut requests).await.unwrap();
+        assert_eq!(&request.identity.pubkey, key.public_key().key_data());
+        assert_eq!(
+            request.data_digest(HashAlg::Sha256),
+            HashAlg::Sha256.digest(b"data")
+        );
+        assert_eq!(
+            request.key_fingerprint(),
+            key.fingerprint(HashAlg::Sha256).to_string()
+        );
+
+        // the keyring keeps answering while the user is asked
+        assert_eq!(keyring.clone().request_identities().await?.len(), 1);
+        answer.send(true).unwrap();
+        sign.await??;
+
+        let mut session = keyring.clone();
+        let public = key.public_key().clone();
+        let sign = tokio::spawn(async move { session.sign_ssh_data(&public, b"data").await });
+        let (_, answer) = futures::StreamExt::next(&mut requests).await.unwrap();
+        answer.send(false).unwrap();
+        assert!(sign.await?.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wipe_on_shutdown() -> TestResult {
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let mut keyring = KeyRing::new().wipe_on(async move {
+            let _ = stopped.await;
+        });
+        keyring
+            .add_identity_constrained(add(&ed25519_key()?, KeyConstraints::new()))
+            .await?;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let listening = tokio::spawn(keyring.clone().listen(listener));
+        tokio::task::yield_now().await;
+        assert!(!listening.is_finished());
+        assert_eq!(keyring.request_identities().await?.len(), 1);
+
+        let _ = stop.send(());
+        listening.await??;
+        assert!(keyring.request_identities().await?.is_empty());
+
+        Ok(())
+    }
+}
@@ -12,6 +12,12 @@ pub mod client;
 #[cfg(feature = "codec")]
 pub mod codec;
 pub mod error;
+#[cfg(feature = "agent")]
+pub mod keyring;
+#[cfg(feature = "test-util")]
+pub mod mock;
+#[cfg(all(test, feature = "codec"))]
+mod test_util;
 
 #[cfg(feature = "agent")]
 pub use async_trait::async_trait;
@@ -0,0 +1,230 @@
+//! Scripted agent for testing code which talks to an SSH agent.
+//!
+//! [`MockAgent`] answers requests with responses queued in advance and
+//! records every request it receives, so programs using the
+//! [client](crate::client) can be tested without a real agent:
+//!
+//! ```
+//! # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+//! use ssh_agent_lib::{
+//!     agent::Session,
+//!     client::Client,
+//!     mock::MockAgent,
+//!     proto::{Request, Response},
+//! };
+//!
+//! let agent = MockAgent::new();
+//! agent.expect(
+//!     Request::RequestIdentities,
+//!     Response::IdentitiesAnswer(vec![]),
+//! );
+//!
+//! let mut client = Client::new(agent.duplex());
+//! assert!(client.request_identities().await?.is_empty());
+//!
+//! agent.verify();
+//! # Ok(()) }
+//! ```
+//!
+//! This module requires the `test-util` feature.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::io::DuplexStream;
+
+use crate::agent::{serve, Agent, Session};
+use crate::error::AgentError;
+use crate::proto::{Request, Response};
+
+/// Buffer size of the streams returned by [`MockAgent::duplex`].
+const DUPLEX_BUFFER: usize = 64 * 1024;
+
+/// Agent answering requests with scripted responses.
+///
+/// Responses are sent in the order they were queued with
+/// [`MockAgent::expect`] or [`MockAgent::respond`], regardless of the
+/// connection the request arrives on. Requests which do not match the
+/// next expectation, or arrive after all responses were used, are
+/// answered with a failure and reported by [`MockAgent::verify`].
+///
+/// Clones share the same script, so a clone can be moved into a server
+/// task while the original is kept for verification. Besides
+/// [`MockAgent::duplex`], the agent can [listen](Agent::listen) on any
+/// socket, e.g. a Unix socket for programs which read `SSH_AUTH_SOCK`:
+///
+/// ```no_run
+/// # #[cfg(unix)]
+/// # async fn example() -> Result<(), ssh_agent_lib::error::AgentError> {
+/// use ssh_agent_lib::{agent::Agent, mock::MockAgent};
+///
+/// let agent = MockAgent::new();
+/// let listener = tokio::net::UnixListener::bind("mock-agent.sock")?;
+/// tokio::spawn(agent.clone().listen(listener));
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MockAgent {
+    script: Arc<Mutex<Script>>,
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    expected: VecDeque<(Option<Request>, Response)>,
+    received: Vec<Request>,
+    unexpected: Vec<String>,
+}
+
+impl MockAgent {
+    /// Create an agent without any queued responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer the next request with `response` if it equals `request`.
+    pub fn expect(&self, request: Request, response: Response) -> &Self {
+        self.script().expected.push_back((Some(request), response));
+        self
+    }
+
+    /// Answer the next request with `response`, whatever it is.
+    pub fn respond(&self, response: Response) -> &Self {
+        self.script().expected.push_back((None, response));
+        self
+    }
+
+    /// Serve a new connection to the agent over an in-memory stream.
+    ///
+    /// The returned stream is the client end of the connection, to be
+    /// wrapped in a [`Client`](crate::client::Client). This must be
+    /// called from within a Tokio runtime.
+    pub fn duplex(&self) -> DuplexStream {
+        let (client, agent) = tokio::io::duplex(DUPLEX_BUFFER);
+        let session = self.session();
+        tokio::spawn(async move {
+            if let Err(e) = serve(session, agent).await {
+                log::error!("Mock agent connection failed: {e:?}");
+            }
+        });
+        client
+    }
+
+    /// All requests received so far, in order.
+    pub fn received(&self) -> Vec<Request> {
+        self.script().received.clone()
+    }
+
+    /// Check that every queued response was used by a matching request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a request did not match its expectation, arrived after
+    /// all responses were used, or if responses are left over.
+    #[track_caller]
+    pub fn verify(&self) {
+        let script = self.script();
+        let mut problems = script.unexpected.clone();
+        problems.extend(script.expected.iter().map(|(request, _)| match request {
+            Some(request) => format!("expected request {request:?} was not received"),
+            None => "a response was not requested".to_owned(),
+        }));
+        if !problems.is_empty() {
+            panic!("Mock agent script not followed:\n{}", problems.join("\n"));
+        }
+    }
+
+    fn session(&self) -> MockSession {
+        MockSession {
+            script: Arc::clone(&self.script),
+        }
+    }
+
+    fn script(&self) -> MutexGuard<'_, Script> {
+        // a panicking test must not hide its script from other clones
+        self.script.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Agent for MockAgent {
+    fn new_session(&mut self) -> impl Session {
+        self.session()
+    }
+}
+
+struct MockSession {
+    script: Arc<Mutex<Script>>,
+}
+
+#[async_trait::async_trait]
+impl Session for MockSession {
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        let mut script = self.script.lock().unwrap_or_else(|e| e.into_inner());
+        script.received.push(message.clone());
+        match script.expected.pop_front() {
+            Some((None, response)) => Ok(response),
+            Some((Some(request), response)) if request == message => Ok(response),
+            Some((Some(request), _)) => {
+                script
+                    .unexpected
+                    .push(format!("expected request {request:?}, got {message:?}"));
+                Err(AgentError::Failure)
+            }
+            None => {
+                script
+                    .unexpected
+                    .push(format!("unexpected request {message:?}"));
+                Err(AgentError::Failure)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::client::Client;
+    use crate::proto::SSH_AGENT_FAILURE;
+
+    #[tokio::test]
+    async fn scripted_responses_are_returned_in_order() -> TestResult {
+        let agent = MockAgent::new();
+        agent
+            .expect(Request::RemoveAllIdentities, Response::Success)
+            .respond(Response::Failure);
+
+        let mut client = Client::new(agent.duplex());
+        client.remove_all_identities().await?;
+        assert!(client.lock("passphrase".to_owned()).await.is_err());
+
+        assert_eq!(
+            agent.received(),
+            [
+                Request::RemoveAllIdentities,
+                Request::Lock("passphrase".to_owned().into())
+            ]
+        );
+        agent.verify();
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "got RemoveAllIdentities")]
+    async fn mismatched_requests_fail_verification() {
+        let agent = MockAgent::new();
+        agent.expect(
+            Request::RequestIdentities,
+            Response::IdentitiesAnswer(vec![]),
+        );
+
+        let mut client = Client::new(agent.duplex());
+        assert!(matches!(
+            client.remove_all_identities().await,
+            Err(AgentError::AgentFailure {
+                code: SSH_AGENT_FAILURE
+            })
+        ));
+        agent.verify();
+    }
+}
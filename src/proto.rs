@@ -2,11 +2,18 @@
 
 pub mod error;
 pub mod extension;
+pub mod frame;
 pub mod message;
 pub mod privatekey;
+#[cfg(feature = "serde")]
+mod serialize;
 pub mod signature;
 
+pub use bytes::Bytes;
+pub use zeroize::Zeroizing;
+
 pub use self::error::*;
+pub use self::frame::*;
 pub use self::message::*;
 pub use self::privatekey::*;
 pub use self::signature::*;
@@ -34,9 +34,92 @@ pub enum ProtoError {
         command: u8,
     },
 
-    /// The client expected a different response.
+    /// Key lifetime does not fit in the 32-bit seconds field.
+    #[error("Key lifetime out of range ({seconds} seconds)")]
+    LifetimeOutOfRange {
+        /// Requested lifetime in seconds.
+        seconds: u64,
+    },
+
+    /// Message length exceeds the maximum accepted by the codec.
+    #[error("Message too long ({length} bytes, maximum is {max_length})")]
+    MessageTooLong {
+        /// Length announced by the message prefix.
+        length: usize,
+
+        /// Maximum accepted length.
+        max_length: usize,
+    },
+
+    /// A message ended before the frame announced by its length prefix.
+    ///
+    /// The peer does not agree with us on where messages begin, so none
+    /// of the following data can be trusted.
+    #[error("Protocol desynchronized ({unread} of {length} bytes left after the message)")]
+    Desync {
+        /// Length announced by the message prefix.
+        length: usize,
+
+        /// Bytes of the frame which were not part of the message.
+        unread: usize,
+    },
+
+    /// The stream ended in the middle of a message.
+    ///
+    /// The peer closed the connection or crashed while writing, so the
+    /// message is lost. A stream ending between messages is not an error.
+    #[error("Message truncated ({received} bytes received, {needed} more expected)")]
+    Truncated {
+        /// Bytes of the message received before the end of the stream,
+        /// including the length prefix.
+        received: usize,
+
+        /// Bytes still missing. If the stream ended within the length
+        /// prefix, this only counts the rest of the prefix.
+        needed: usize,
+    },
+
+    /// The agent returned a legacy `ssh-rsa` signature, which uses SHA-1.
+    ///
+    /// Such signatures cannot be represented by [`ssh_key::Signature`]
+    /// and are only made by agents asked for them explicitly or ignoring
+    /// the [signature flags](super::signature) of the request.
+    #[error("Legacy ssh-rsa (SHA-1) signature received")]
+    LegacySha1Signature,
+
+    /// A [`SignRequestBuilder`](super::SignRequestBuilder) was built
+    /// without a key.
+    #[error("Sign request without a key")]
+    MissingKey,
+
+    /// Signature flags were requested for a key which does not use them.
+    ///
+    /// The SHA-2 [signature flags](super::signature) only apply to RSA keys.
+    #[error("Signature flags {flags:#x} not supported for {algorithm} keys")]
+    UnsupportedSignatureFlags {
+        /// Algorithm of the key.
+        algorithm: ssh_key::Algorithm,
+
+        /// Flags of the request.
+        flags: u32,
+    },
+
+    /// An extension response had contents, but the extension defines none.
+    ///
+    /// Only decoding [`NoResponse`](super::extension::NoResponse) fails
+    /// this way. Responses of the wrong kind are reported as
+    /// [`AgentError::UnexpectedResponse`](crate::error::AgentError::UnexpectedResponse),
+    /// which names the responses involved.
     #[error("Unexpected response received")]
     UnexpectedResponse,
+
+    /// A [`SessionBind`](super::extension::SessionBind) was signed by a
+    /// host key which is not trusted.
+    ///
+    /// The signature verified, so the client did talk to the holder of
+    /// the key, but the key is not one of the known hosts.
+    #[error("Session bound to an unknown host key")]
+    UnknownHostKey,
 }
 
 /// Protocol result.
@@ -1,5 +1,9 @@
 //! SSH agent extension structures (messages & key constraints)
 
+use ssh_encoding::{Decode, Encode, Reader};
+
+use super::ProtoError;
+
 pub mod constraint;
 pub mod message;
 
@@ -17,6 +21,33 @@ pub trait MessageExtension: 'static {
     const NAME: &'static str;
 }
 
+/// Message extension request with a typed response.
+///
+/// Implemented by extension requests which can be sent with
+/// [`Session::extension_typed`](crate::agent::Session::extension_typed).
+/// The request is encoded as the extension contents, and the contents of
+/// an `SSH_AGENT_EXTENSION_RESPONSE` with the same extension name are
+/// decoded as the [`Response`](Self::Response).
+pub trait ExtensionRequest: MessageExtension + Encode {
+    /// Contents of the response to this request.
+    ///
+    /// Extensions which the agent answers with `SSH_AGENT_SUCCESS` only use
+    /// [`NoResponse`].
+    type Response: Decode<Error = ProtoError>;
+}
+
+/// Response type of extensions answered without contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoResponse {}
+
+impl Decode for NoResponse {
+    type Error = ProtoError;
+
+    fn decode(_reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Err(ProtoError::UnexpectedResponse)
+    }
+}
+
 /// SSH agent protocol key constraint extension
 ///
 /// Described in [draft-miller-ssh-agent-14 § 3.2.7.3](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.2.7.3)
@@ -4,9 +4,10 @@
 //! - [OpenSSH `PROTOCOL.agent`](https://github.com/openssh/openssh-portable/blob/cbbdf868bce431a59e2fa36ca244d5739429408d/PROTOCOL.agent)
 
 use ssh_encoding::{CheckedSum, Decode, Encode, Error as EncodingError, Reader, Writer};
-use ssh_key::public::KeyData;
+use ssh_key::{certificate::Certificate, public::KeyData};
 
 use super::KeyConstraintExtension;
+use crate::proto::{Extension, ProtoError, Unparsed};
 
 // Reserved fields are marked with an empty string
 const RESERVED_FIELD: &str = "";
@@ -27,7 +28,7 @@ pub struct RestrictDestination {
 }
 
 impl Decode for RestrictDestination {
-    type Error = crate::proto::error::ProtoError;
+    type Error = ProtoError;
 
     fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
         let mut constraints = Vec::new();
@@ -76,7 +77,7 @@ pub struct HostTuple {
 }
 
 impl Decode for HostTuple {
-    type Error = crate::proto::error::ProtoError;
+    type Error = ProtoError;
 
     fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
         let username = String::decode(reader)?;
@@ -139,7 +140,7 @@ pub struct DestinationConstraint {
 }
 
 impl Decode for DestinationConstraint {
-    type Error = crate::proto::error::ProtoError;
+    type Error = ProtoError;
 
     fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
         let from = reader.read_prefixed(HostTuple::decode)?;
@@ -187,7 +188,7 @@ pub struct KeySpec {
 }
 
 impl Decode for KeySpec {
-    type Error = crate::proto::error::ProtoError;
+    type Error = ProtoError;
 
     fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
         let keyblob = reader.read_prefixed(KeyData::decode)?;
@@ -215,13 +216,210 @@ impl Encode for KeySpec {
     }
 }
 
+/// `provider-options@metacode.biz` key constraint extension.
+///
+/// Carries provider-specific options for a smartcard key, as key-value
+/// pairs. The options of [`SmartcardKey`](crate::proto::SmartcardKey)
+/// are sent in this constraint, see there for details.
+///
+/// The extension consists of pairs of strings, the name of an option
+/// followed by its value, until the end of the constraint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProviderOptions {
+    /// Option names and values.
+    pub options: Vec<(String, String)>,
+}
+
+impl Decode for ProviderOptions {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let mut options = Vec::new();
+        while !reader.is_finished() {
+            options.push((String::decode(reader)?, String::decode(reader)?));
+        }
+        Ok(Self { options })
+    }
+}
+
+impl Encode for ProviderOptions {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        self.options.iter().try_fold(0, |acc, (name, value)| {
+            [acc, name.encoded_len()?, value.encoded_len()?].checked_sum()
+        })
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        for (name, value) in &self.options {
+            name.encode(writer)?;
+            value.encode(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyConstraintExtension for ProviderOptions {
+    const NAME: &'static str = "provider-options@metacode.biz";
+}
+
+/// `associated-certs-v01@openssh.com` key constraint extension.
+///
+/// Attaches certificates to a key added from a PKCS#11 token, whose
+/// certificates are not stored on the token itself. The agent offers the
+/// certificates as identities next to the plain key, or instead of it
+/// if `certs_only` is set.
+///
+/// Unlike the other extensions, the details of this one are not wrapped in
+/// a string on the wire. [`KeyConstraint`](crate::proto::KeyConstraint)
+/// takes care of that, so [`Extension::details`] hold the fields below as
+/// for any other extension.
+///
+/// *Note*: This is an OpenSSH-specific extension to the agent protocol.
+///
+/// Described in [OpenSSH PROTOCOL.agent](https://github.com/openssh/openssh-portable/blob/V_9_8_P1/PROTOCOL.agent)
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssociatedCerts {
+    /// Only offer the certificates, not the plain key.
+    pub certs_only: bool,
+
+    /// Certificates of the key.
+    pub certificates: Vec<Certificate>,
+}
+
+impl AssociatedCerts {
+    fn certificates_len(&self) -> ssh_encoding::Result<usize> {
+        self.certificates.iter().try_fold(0, |acc, certificate| {
+            [acc, certificate.encoded_len_prefixed()?].checked_sum()
+        })
+    }
+}
+
+impl Decode for AssociatedCerts {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let certs_only = u8::decode(reader)? != 0;
+        let certificates = reader.read_prefixed(|reader| {
+            let mut certificates = Vec::new();
+            while !reader.is_finished() {
+                certificates.push(reader.read_prefixed(Certificate::decode)?);
+            }
+            Ok::<_, ProtoError>(certificates)
+        })?;
+        Ok(Self {
+            certs_only,
+            certificates,
+        })
+    }
+}
+
+impl Encode for AssociatedCerts {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        [
+            1u8.encoded_len()?,
+            self.certificates_len()?.encoded_len_prefixed()?,
+        ]
+        .checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        u8::from(self.certs_only).encode(writer)?;
+        self.certificates_len()?.encode(writer)?;
+        for certificate in &self.certificates {
+            certificate.encode_prefixed(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl KeyConstraintExtension for AssociatedCerts {
+    const NAME: &'static str = "associated-certs-v01@openssh.com";
+}
+
+/// Key constraint extension parsed by its name.
+///
+/// Agents receive constraint extensions as an [`Extension`] with unparsed
+/// details. Converting it into this enum decodes the extensions known to
+/// this crate and keeps any other as [`ConstraintExtension::Unknown`], so
+/// that it can be rejected by name:
+///
+/// ```
+/// use ssh_agent_lib::proto::{extension::ConstraintExtension, Extension};
+///
+/// let extension = Extension {
+///     name: "sk-provider@openssh.com".into(),
+///     details: b"internal".to_vec().into(),
+/// };
+/// match ConstraintExtension::try_from(&extension)? {
+///     ConstraintExtension::RestrictDestination(destination) => { /* ... */ }
+///     ConstraintExtension::Unknown { name, .. } => assert_eq!(name, "sk-provider@openssh.com"),
+///     _ => unreachable!(),
+/// }
+/// # Ok::<(), ssh_agent_lib::proto::ProtoError>(())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ConstraintExtension {
+    /// `restrict-destination-v00@openssh.com`
+    RestrictDestination(RestrictDestination),
+
+    /// `associated-certs-v01@openssh.com`
+    AssociatedCerts(AssociatedCerts),
+
+    /// `provider-options@metacode.biz`
+    ProviderOptions(ProviderOptions),
+
+    /// Any other extension.
+    Unknown {
+        /// Extension name.
+        name: String,
+
+        /// Extension-specific details, as in [`Extension::details`].
+        data: Vec<u8>,
+    },
+}
+
+impl TryFrom<&Extension> for ConstraintExtension {
+    type Error = ProtoError;
+
+    fn try_from(extension: &Extension) -> Result<Self, Self::Error> {
+        Ok(match extension.name.as_str() {
+            RestrictDestination::NAME => Self::RestrictDestination(extension.details.parse()?),
+            AssociatedCerts::NAME => Self::AssociatedCerts(extension.details.parse()?),
+            ProviderOptions::NAME => Self::ProviderOptions(extension.details.parse()?),
+            _ => Self::Unknown {
+                name: extension.name.clone(),
+                data: extension.details.0.clone(),
+            },
+        })
+    }
+}
+
+impl TryFrom<ConstraintExtension> for Extension {
+    type Error = ProtoError;
+
+    fn try_from(extension: ConstraintExtension) -> Result<Self, Self::Error> {
+        match extension {
+            ConstraintExtension::RestrictDestination(destination) => {
+                Extension::new_key_constraint(destination)
+            }
+            ConstraintExtension::AssociatedCerts(certs) => Extension::new_key_constraint(certs),
+            ConstraintExtension::ProviderOptions(options) => Extension::new_key_constraint(options),
+            ConstraintExtension::Unknown { name, data } => Ok(Extension {
+                name,
+                details: Unparsed(data),
+            }),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use hex_literal::hex;
     use testresult::TestResult;
 
     use super::*;
-    use crate::proto::ProtoError;
+    use crate::proto::{Credential, KeyConstraint, Request};
 
     fn round_trip<T>(msg: T) -> TestResult
     where
@@ -329,4 +527,76 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn parse_known_constraint_extensions() -> TestResult {
+        let msg = include_bytes!(
+            "../../../tests/messages/req-add-identity-constrained-multiple-extensions.bin"
+        );
+        let Request::AddIdConstrained(request) = Decode::decode(&mut &msg[..])? else {
+            panic!("expected an add identity constrained request");
+        };
+
+        let mut extensions = vec![];
+        for constraint in &request.constraints {
+            if let KeyConstraint::Extension(extension) = constraint {
+                let parsed = ConstraintExtension::try_from(extension)?;
+                assert_eq!(&Extension::try_from(parsed.clone())?, extension);
+                extensions.push(parsed);
+            }
+        }
+
+        let [sk_provider, ConstraintExtension::RestrictDestination(destination)] = &extensions[..]
+        else {
+            panic!("unexpected extensions: {extensions:?}");
+        };
+        // not known to this crate
+        assert_eq!(
+            sk_provider,
+            &ConstraintExtension::Unknown {
+                name: "sk-provider@openssh.com".into(),
+                data: b"testsk".to_vec(),
+            }
+        );
+        assert_eq!(destination.constraints.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_associated_certs() -> TestResult {
+        let msg = include_bytes!("../../../tests/messages/req-add-identity-with-cert.bin");
+        let Request::AddIdConstrained(request) = Decode::decode(&mut &msg[..])? else {
+            panic!("expected an add identity constrained request");
+        };
+        let Credential::Cert { certificate, .. } = request.identity.credential else {
+            panic!("expected a certificate");
+        };
+        let mut blob = vec![];
+        certificate.encode_prefixed(&mut blob)?;
+
+        // as written by OpenSSH 9.8 `encode_constraints`
+        let mut constraint = vec![255];
+        AssociatedCerts::NAME.encode(&mut constraint)?;
+        constraint.push(1);
+        blob.encode(&mut constraint)?;
+
+        let decoded = KeyConstraint::decode(&mut &constraint[..])?;
+        let KeyConstraint::Extension(extension) = &decoded else {
+            panic!("expected an extension constraint");
+        };
+        assert_eq!(
+            ConstraintExtension::try_from(extension)?,
+            ConstraintExtension::AssociatedCerts(AssociatedCerts {
+                certs_only: true,
+                certificates: vec![certificate],
+            })
+        );
+
+        let mut encoded = vec![];
+        decoded.encode(&mut encoded)?;
+        assert_eq!(encoded, constraint);
+
+        Ok(())
+    }
 }
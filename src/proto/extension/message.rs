@@ -8,13 +8,48 @@ use signature::Verifier;
 use ssh_encoding::{CheckedSum, Decode, Encode, Error as EncodingError, Reader, Writer};
 use ssh_key::{public::KeyData, Signature};
 
-use super::MessageExtension;
-use crate::proto::ProtoError;
+use super::{ExtensionRequest, MessageExtension, NoResponse};
+use crate::proto::{ProtoError, Response};
 
-/// `query` message extension.
+/// `query` message extension request.
 ///
 /// An optional extension request "query" is defined to allow a
 /// client to query which, if any, extensions are supported by an agent.
+/// The request does not carry any extension-specific content.
+///
+/// Described in [draft-miller-ssh-agent-14 § 3.8.1](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.8.1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query;
+
+impl Encode for Query {
+    fn encoded_len(&self) -> Result<usize, EncodingError> {
+        Ok(0)
+    }
+
+    fn encode(&self, _writer: &mut impl Writer) -> Result<(), EncodingError> {
+        Ok(())
+    }
+}
+
+impl Decode for Query {
+    type Error = ProtoError;
+
+    fn decode(_reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self)
+    }
+}
+
+impl MessageExtension for Query {
+    const NAME: &'static str = "query";
+}
+
+impl ExtensionRequest for Query {
+    type Response = QueryResponse;
+}
+
+/// `query` message extension response.
+///
+/// Sent by the agent in response to a [`Query`] request.
 ///
 /// Described in [draft-miller-ssh-agent-14 § 3.8.1](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.8.1)
 #[derive(Debug, Clone, PartialEq)]
@@ -23,6 +58,36 @@ pub struct QueryResponse {
     pub extensions: Vec<String>,
 }
 
+impl QueryResponse {
+    /// Decode the extension names as listed by Pageant.
+    ///
+    /// Pageant answers the [`Query`] request with `SSH_AGENT_SUCCESS`
+    /// followed by the names, one string each until the end of the
+    /// message, see [`Response::SuccessWithData`].
+    pub fn decode_pageant(reader: &mut impl Reader) -> Result<Self, ProtoError> {
+        let mut extensions = Vec::new();
+        while !reader.is_finished() {
+            extensions.push(String::decode(reader)?);
+        }
+        Ok(Self { extensions })
+    }
+
+    /// Read the extension names from the answer to a [`Query`] request.
+    ///
+    /// Besides the `query` extension response, this understands the form
+    /// sent by Pageant. Agents answering with a failure do not implement
+    /// `query` and are taken to support no extensions. Returns `None` for
+    /// any other response.
+    pub fn from_answer(response: &Response) -> Result<Option<Self>, ProtoError> {
+        match response {
+            Response::ExtensionResponse(response) => response.parse_message(),
+            Response::SuccessWithData(data) => Self::decode_pageant(&mut &data.0[..]).map(Some),
+            Response::Failure | Response::ExtensionFailure => Ok(Some(Self { extensions: vec![] })),
+            _ => Ok(None),
+        }
+    }
+}
+
 impl Encode for QueryResponse {
     fn encoded_len(&self) -> Result<usize, EncodingError> {
         self.extensions.encoded_len()
@@ -123,17 +188,60 @@ impl SessionBind {
             .verify(self.session_id.as_slice(), &self.signature)?;
         Ok(())
     }
+
+    /// Verify the signature of the session identifier and that the
+    /// host key is one of `known_hosts`, returning the host key.
+    ///
+    /// Agents can restrict where keys are used or forwarded to, e.g. by
+    /// comparing [`is_forwarding`](Self::is_forwarding) bindings with the
+    /// hosts allowed to use the agent:
+    ///
+    /// ```
+    /// use ssh_agent_lib::proto::{extension::SessionBind, Extension, ProtoError};
+    /// use ssh_key::public::KeyData;
+    ///
+    /// fn check(extension: &Extension, allowed: &[KeyData]) -> Result<(), ProtoError> {
+    ///     if let Some(bind) = extension.parse_message::<SessionBind>()? {
+    ///         match bind.verify(allowed) {
+    ///             Ok(host_key) => eprintln!("bound to {}", host_key.fingerprint(Default::default())),
+    ///             Err(ProtoError::UnknownHostKey) if !bind.is_forwarding => {}
+    ///             Err(e) => return Err(e),
+    ///         }
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// A signature which does not verify fails with
+    /// [`ProtoError::SshSignature`], a valid one made by a key which is
+    /// not known with [`ProtoError::UnknownHostKey`].
+    pub fn verify<'k>(
+        &self,
+        known_hosts: impl IntoIterator<Item = &'k KeyData>,
+    ) -> Result<&KeyData, ProtoError> {
+        self.verify_signature()?;
+        if known_hosts.into_iter().any(|key| *key == self.host_key) {
+            Ok(&self.host_key)
+        } else {
+            Err(ProtoError::UnknownHostKey)
+        }
+    }
 }
 
 impl MessageExtension for SessionBind {
     const NAME: &'static str = "session-bind@openssh.com";
 }
 
+impl ExtensionRequest for SessionBind {
+    type Response = NoResponse;
+}
+
 #[cfg(test)]
 mod tests {
     use testresult::TestResult;
 
     use super::*;
+    use crate::proto::{Extension, Request};
 
     fn round_trip<T>(msg: T) -> TestResult
     where
@@ -149,6 +257,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn parse_query() -> TestResult {
+        let mut buffer: &[u8] = &[
+            0, 0, 0, 37, 0, 0, 0, 5, 113, 117, 101, 114, 121, 0, 0, 0, 24, 115, 101, 115, 115, 105,
+            111, 110, 45, 98, 105, 110, 100, 64, 111, 112, 101, 110, 115, 115, 104, 46, 99, 111,
+            109,
+        ];
+        let response = QueryResponse::decode(&mut buffer)?;
+        assert_eq!(
+            response.extensions,
+            vec!["query".to_string(), "session-bind@openssh.com".to_string()]
+        );
+
+        round_trip(response)?;
+        round_trip(Query)?;
+
+        Ok(())
+    }
+
     #[test]
     fn parse_bind() -> TestResult {
         let mut buffer: &[u8] = &[
@@ -162,12 +289,39 @@ mod tests {
             77, 55, 235, 9, 77, 160, 32, 76, 11, 227, 240, 235, 122, 178, 80, 133, 183, 91, 89, 89,
             142, 115, 145, 15, 78, 112, 139, 28, 201, 8, 197, 222, 117, 141, 88, 5, 0,
         ];
+        let serialized = buffer.to_vec();
         let bind = SessionBind::decode(&mut buffer)?;
         eprintln!("Bind: {bind:#?}");
 
         // Check `signature` (of `session_id`) against
         // server public-key `host_key`
         bind.verify_signature()?;
+        assert_eq!(bind.verify([&bind.host_key])?, &bind.host_key);
+        assert!(matches!(bind.verify([]), Err(ProtoError::UnknownHostKey)));
+        let mut forged = bind.clone();
+        forged.session_id[0] ^= 1;
+        assert!(matches!(
+            forged.verify([&bind.host_key]),
+            Err(ProtoError::SshSignature(_))
+        ));
+
+        // The extension request must match OpenSSH's wire format
+        let request = Request::Extension(Extension::new_message(bind.clone())?);
+        let mut encoded = vec![];
+        request.encode(&mut encoded)?;
+        assert_eq!(request.encoded_len()?, encoded.len());
+        let mut expected = vec![27];
+        SessionBind::NAME.encode(&mut expected)?;
+        expected.extend_from_slice(&serialized);
+        assert_eq!(encoded, expected);
+
+        let Request::Extension(extension) = Request::decode(&mut &encoded[..])? else {
+            panic!("expected an extension request");
+        };
+        assert_eq!(
+            extension.parse_message::<SessionBind>()?,
+            Some(bind.clone())
+        );
 
         round_trip(bind)?;
 
@@ -0,0 +1,133 @@
+//! Length-prefixed message framing.
+//!
+//! Every agent message is preceded by its length as an `u32`. The
+//! functions here decode such frames from plain byte buffers, without
+//! any I/O, which makes them usable for fuzzing and custom transports.
+//! [`Codec`](crate::codec::Codec) is built on top of them.
+
+use std::mem::size_of;
+
+use ssh_encoding::Decode;
+
+use super::ProtoError;
+
+/// Outcome of [`decode_frame`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame<T> {
+    /// A complete message was decoded.
+    Complete {
+        /// The decoded message.
+        message: T,
+
+        /// Number of bytes occupied by the frame, including the length prefix.
+        length: usize,
+    },
+
+    /// The buffer ends before the frame does.
+    ///
+    /// This is not an error: the frame may be decoded once more bytes
+    /// are available.
+    Incomplete {
+        /// Minimum number of bytes that must be appended to the buffer.
+        needed: usize,
+    },
+}
+
+/// Decode the frame at the start of `bytes`.
+///
+/// Frames whose announced length exceeds `max_length` are rejected with
+/// [`ProtoError::MessageTooLong`] as soon as the length prefix is
+/// available. Messages are decoded from the frame only, so malformed
+/// messages never consume bytes of the frames following them. A message
+/// which ends before its frame does is rejected with
+/// [`ProtoError::Desync`].
+pub fn decode_frame<T>(bytes: &[u8], max_length: usize) -> Result<Frame<T>, T::Error>
+where
+    T: Decode,
+    T::Error: From<ProtoError>,
+{
+    const PREFIX: usize = size_of::<u32>();
+
+    let Some(prefix) = bytes.get(..PREFIX) else {
+        return Ok(Frame::Incomplete {
+            needed: PREFIX - bytes.len(),
+        });
+    };
+    let mut length = [0; PREFIX];
+    length.copy_from_slice(prefix);
+    let length = u32::from_be_bytes(length) as usize;
+
+    if length > max_length {
+        return Err(ProtoError::MessageTooLong { length, max_length }.into());
+    }
+
+    let Some(mut message) = bytes[PREFIX..].get(..length) else {
+        return Ok(Frame::Incomplete {
+            needed: PREFIX + length - bytes.len(),
+        });
+    };
+    let decoded = T::decode(&mut message)?;
+    if !message.is_empty() {
+        return Err(ProtoError::Desync {
+            length,
+            unread: message.len(),
+        }
+        .into());
+    }
+    Ok(Frame::Complete {
+        message: decoded,
+        length: PREFIX + length,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+
+    use super::*;
+    use crate::proto::{Request, Response};
+
+    #[test]
+    fn truncated_frames_are_incomplete() -> TestResult {
+        let frame = [0, 0, 0, 1, 11];
+        for end in 0..frame.len() {
+            // before the prefix is complete only its own length is known
+            let needed = if end < 4 { 4 - end } else { frame.len() - end };
+            assert_eq!(
+                decode_frame::<Request>(&frame[..end], 16)?,
+                Frame::Incomplete { needed }
+            );
+        }
+        assert_eq!(
+            decode_frame::<Request>(&frame, 16)?,
+            Frame::Complete {
+                message: Request::RequestIdentities,
+                length: 5,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_frames_are_errors() {
+        // unknown message type
+        assert!(decode_frame::<Response>(&[0, 0, 0, 1, 0xff], 16).is_err());
+        // the identities answer claims one identity which is missing
+        assert!(decode_frame::<Response>(&[0, 0, 0, 5, 12, 0, 0, 0, 1], 16).is_err());
+        // a success response followed by a byte which is not part of it
+        assert!(matches!(
+            decode_frame::<Response>(&[0, 0, 0, 2, 6, 0], 16),
+            Err(ProtoError::Desync {
+                length: 2,
+                unread: 1
+            })
+        ));
+        assert!(matches!(
+            decode_frame::<Request>(&[0, 0, 1, 0], 16),
+            Err(ProtoError::MessageTooLong {
+                length: 256,
+                max_length: 16
+            })
+        ));
+    }
+}
@@ -1,37 +1,76 @@
 //! Agent protocol message structures.
 
 use core::str::FromStr;
+use core::time::Duration;
+use std::borrow::Cow;
 
+use bytes::Bytes;
 use ssh_encoding::{CheckedSum, Decode, Encode, Error as EncodingError, Reader, Writer};
 use ssh_key::{
-    certificate::Certificate, private::KeypairData, public::KeyData, Algorithm, Error, Signature,
+    certificate::Certificate, private::KeypairData, public::KeyData, Algorithm, Error, PrivateKey,
+    PublicKey, Signature,
 };
+use zeroize::Zeroizing;
 
 use super::{
-    extension::{KeyConstraintExtension, MessageExtension},
+    extension::{AssociatedCerts, KeyConstraintExtension, MessageExtension, ProviderOptions},
+    signature::{RSA_SHA2_256, RSA_SHA2_512},
     PrivateKeyData, ProtoError,
 };
 
 type Result<T> = core::result::Result<T, ProtoError>;
 
+/// Message number of `SSH_AGENT_FAILURE`, see [`Response::Failure`].
+pub const SSH_AGENT_FAILURE: u8 = 5;
+
+/// Message number of `SSH_AGENT_EXTENSION_FAILURE`, see
+/// [`Response::ExtensionFailure`].
+pub const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
 /// Data returned to the client when listing keys.
 ///
 /// A list of these structures are sent in a [`Response::IdentitiesAnswer`] (`SSH_AGENT_IDENTITIES_ANSWER`) message body.
 ///
 /// Described in [draft-miller-ssh-agent-14 § 3.5](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.5)
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Identity {
     /// A standard public-key encoding of an underlying key.
+    #[cfg_attr(feature = "serde", serde(with = "super::serialize::key_data"))]
     pub pubkey: KeyData,
 
-    /// A human-readable comment
-    pub comment: String,
+    /// A human-readable comment.
+    ///
+    /// Comments are usually UTF-8, but agents may return arbitrary bytes,
+    /// e.g. for keys added by other tools. Use [`Identity::comment_lossy`]
+    /// to display them.
+    #[cfg_attr(feature = "serde", serde(with = "super::serialize::comment"))]
+    pub comment: Vec<u8>,
 }
 
 impl Identity {
+    /// The comment, with invalid UTF-8 sequences replaced by `U+FFFD`.
+    pub fn comment_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.comment)
+    }
+
+    /// Format the identity as a single line of an `authorized_keys` file,
+    /// as printed by `ssh-add -L`.
+    ///
+    /// The line holds the key's algorithm, its base64 encoding and the
+    /// comment, which is kept exactly as is, including any spaces. Invalid
+    /// UTF-8 in the comment is replaced as by [`Identity::comment_lossy`].
+    /// Keys with a separate comment can be formatted the same way using
+    /// [`PublicKey::to_openssh`](ssh_key::PublicKey::to_openssh).
+    pub fn to_openssh(&self) -> Result<String> {
+        Ok(PublicKey::new(self.pubkey.clone(), self.comment_lossy()).to_openssh()?)
+    }
+
     fn decode_vec(reader: &mut impl Reader) -> Result<Vec<Self>> {
         let len = u32::decode(reader)?;
-        let mut identities = vec![];
+        // the count is not trusted further than the bytes which follow it,
+        // each identity taking at least two length prefixes
+        let mut identities = Vec::with_capacity((len as usize).min(reader.remaining_len() / 8));
 
         for _ in 0..len {
             identities.push(Self::decode(reader)?);
@@ -46,7 +85,7 @@ impl Decode for Identity {
 
     fn decode(reader: &mut impl Reader) -> Result<Self> {
         let pubkey = reader.read_prefixed(KeyData::decode)?;
-        let comment = String::decode(reader)?;
+        let comment = Vec::decode(reader)?;
 
         Ok(Self { pubkey, comment })
     }
@@ -75,29 +114,169 @@ impl Encode for Identity {
 ///
 /// Described in [draft-miller-ssh-agent-14 § 3.6](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.6)
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SignRequest {
     /// The public key portion of the [`Identity`] in the agent to sign the data with
+    ///
+    /// Requests naming a certificate are decoded to the key it was issued for,
+    /// which agents compare identities by.
+    #[cfg_attr(feature = "serde", serde(with = "super::serialize::key_data"))]
     pub pubkey: KeyData,
 
+    /// The certificate the request names instead of the bare key, if any.
+    ///
+    /// Clients holding a certificate in the agent, like `ssh`
+    /// authenticating with it, send the certificate's blob. It is kept
+    /// here so the request is encoded the same way again, and must have
+    /// been issued for [`pubkey`](Self::pubkey).
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub certificate: Option<Certificate>,
+
     /// Binary data to be signed
-    pub data: Vec<u8>,
+    ///
+    /// Cloning the request, e.g. to retry it, does not copy the data.
+    pub data: Bytes,
 
     /// Signature flags, as described in
     /// [draft-miller-ssh-agent-14 § 3.6.1](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.6.1)
     pub flags: u32,
 }
 
+impl SignRequest {
+    /// Create a new signature request for `data` using the key `pubkey`.
+    ///
+    /// Signature flags are picked based on the key algorithm: RSA keys
+    /// request [`RSA_SHA2_512`] signatures, since OpenSSH servers reject
+    /// the legacy SHA-1 based `ssh-rsa` signatures. Other key types do
+    /// not use any flags.
+    ///
+    /// The `flags` field can be changed afterwards, e.g. to retry with
+    /// [`RSA_SHA2_256`] if the agent does not support SHA-512.
+    ///
+    /// `data` can be anything convertible to [`Bytes`] without
+    /// copying, such as a `Vec<u8>` or a `&'static [u8]`.
+    pub fn new(pubkey: KeyData, data: impl Into<Bytes>) -> Self {
+        let flags = match pubkey.algorithm() {
+            Algorithm::Rsa { .. } => RSA_SHA2_512,
+            _ => 0,
+        };
+
+        Self {
+            pubkey,
+            certificate: None,
+            data: data.into(),
+            flags,
+        }
+    }
+
+    /// Build a signature request with the signature flags set explicitly.
+    ///
+    /// ```
+    /// use ssh_agent_lib::proto::{ProtoError, SignRequest};
+    /// use ssh_key::{private::Ed25519Keypair, PublicKey};
+    ///
+    /// let key = PublicKey::from(Ed25519Keypair::from_seed(&[1; 32]).public);
+    /// let request = SignRequest::builder()
+    ///     .key(&key)
+    ///     .data(b"data".to_vec())
+    ///     .build()?;
+    /// assert_eq!(request.flags, 0);
+    ///
+    /// // SHA-2 signatures are only requested from RSA keys
+    /// let result = SignRequest::builder().key(&key).sha512().build();
+    /// assert!(matches!(
+    ///     result,
+    ///     Err(ProtoError::UnsupportedSignatureFlags { .. })
+    /// ));
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn builder() -> SignRequestBuilder {
+        SignRequestBuilder::default()
+    }
+}
+
+/// Builder for a [`SignRequest`].
+///
+/// As with [`SignRequest::new`], an RSA key without
+/// [`sha256`](Self::sha256) or [`sha512`](Self::sha512) requests an
+/// `rsa-sha2-512` signature. Legacy `ssh-rsa` signatures, which use
+/// SHA-1, can only be requested by clearing the flags of the built
+/// request.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct SignRequestBuilder {
+    pubkey: Option<KeyData>,
+    data: Bytes,
+    flags: u32,
+}
+
+impl SignRequestBuilder {
+    /// Sign with `key`.
+    pub fn key(mut self, key: &PublicKey) -> Self {
+        self.pubkey = Some(key.key_data().clone());
+        self
+    }
+
+    /// Sign `data`, which is empty if not set.
+    pub fn data(mut self, data: impl Into<Bytes>) -> Self {
+        self.data = data.into();
+        self
+    }
+
+    /// Request an `rsa-sha2-256` signature, replacing [`sha512`](Self::sha512).
+    pub fn sha256(mut self) -> Self {
+        self.flags = (self.flags & !RSA_SHA2_512) | RSA_SHA2_256;
+        self
+    }
+
+    /// Request an `rsa-sha2-512` signature, replacing [`sha256`](Self::sha256).
+    pub fn sha512(mut self) -> Self {
+        self.flags = (self.flags & !RSA_SHA2_256) | RSA_SHA2_512;
+        self
+    }
+
+    /// Return the request.
+    ///
+    /// Fails if no key was set, or if SHA-2 signatures were requested for
+    /// a key which is not an RSA key.
+    pub fn build(self) -> Result<SignRequest> {
+        let pubkey = self.pubkey.ok_or(ProtoError::MissingKey)?;
+        let algorithm = pubkey.algorithm();
+        let flags = match algorithm {
+            Algorithm::Rsa { .. } if self.flags == 0 => RSA_SHA2_512,
+            Algorithm::Rsa { .. } => self.flags,
+            _ if self.flags != 0 => {
+                return Err(ProtoError::UnsupportedSignatureFlags {
+                    algorithm,
+                    flags: self.flags,
+                })
+            }
+            _ => 0,
+        };
+
+        Ok(SignRequest {
+            pubkey,
+            certificate: None,
+            data: self.data,
+            flags,
+        })
+    }
+}
+
 impl Decode for SignRequest {
     type Error = ProtoError;
 
     fn decode(reader: &mut impl Reader) -> Result<Self> {
-        let pubkey = reader.read_prefixed(KeyData::decode)?;
+        let (pubkey, certificate) = key_from_blob(&Vec::decode(reader)?)?;
         let data = Vec::decode(reader)?;
         let flags = u32::decode(reader)?;
 
         Ok(Self {
             pubkey,
-            data,
+            certificate,
+            data: data.into(),
             flags,
         })
     }
@@ -106,22 +285,45 @@ impl Decode for SignRequest {
 impl Encode for SignRequest {
     fn encoded_len(&self) -> ssh_encoding::Result<usize> {
         [
-            self.pubkey.encoded_len_prefixed()?,
-            self.data.encoded_len()?,
+            match &self.certificate {
+                Some(certificate) => certificate.encoded_len_prefixed()?,
+                None => self.pubkey.encoded_len_prefixed()?,
+            },
+            self.data[..].encoded_len()?,
             self.flags.encoded_len()?,
         ]
         .checked_sum()
     }
 
     fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
-        self.pubkey.encode_prefixed(writer)?;
-        self.data.encode(writer)?;
+        match &self.certificate {
+            Some(certificate) => certificate.encode_prefixed(writer)?,
+            None => self.pubkey.encode_prefixed(writer)?,
+        }
+        self.data[..].encode(writer)?;
         self.flags.encode(writer)?;
 
         Ok(())
     }
 }
 
+/// Decode the key identifying an identity in a request.
+///
+/// Clients holding a certificate in the agent, like `ssh` authenticating
+/// with it, send the certificate's blob instead of the bare key. Such a
+/// certificate is returned along with the key it was issued for, which
+/// agents compare identities by.
+pub(crate) fn key_from_blob(mut blob: &[u8]) -> Result<(KeyData, Option<Certificate>)> {
+    let algorithm = String::decode(&mut &blob[..])?;
+    let key = if Algorithm::new_certificate(&algorithm).is_ok() {
+        let certificate = Certificate::decode(&mut blob)?;
+        (certificate.public_key().clone(), Some(certificate))
+    } else {
+        (KeyData::decode(&mut blob)?, None)
+    };
+    Ok(blob.finish(key)?)
+}
+
 /// A container for a public / private key pair, or a certificate / private key.
 ///
 /// When adding an identity to an agent, a user can provide either:
@@ -157,6 +359,40 @@ pub enum Credential {
     },
 }
 
+impl Credential {
+    /// Pair `key` with a `certificate` issued for its public key.
+    ///
+    /// The credential is encoded with the certificate's key type, e.g.
+    /// `ssh-ed25519-cert-v01@openssh.com`, followed by the certificate and
+    /// only the private part of `key`. A certificate for a different key
+    /// fails with [`Error::PublicKey`].
+    pub fn certificate(
+        key: &KeypairData,
+        certificate: Certificate,
+        comment: impl Into<String>,
+    ) -> Result<Self> {
+        if KeyData::try_from(key)? != *certificate.public_key() {
+            return Err(Error::PublicKey.into());
+        }
+        Ok(Credential::Cert {
+            algorithm: certificate.algorithm(),
+            privkey: key.try_into()?,
+            certificate,
+            comment: comment.into(),
+        })
+    }
+
+    /// Public key of the credential's private key.
+    ///
+    /// For certificates this is the key the certificate was issued for.
+    pub fn public_key(&self) -> Result<KeyData> {
+        match self {
+            Self::Key { privkey, .. } => Ok(KeyData::try_from(privkey)?),
+            Self::Cert { certificate, .. } => Ok(certificate.public_key().clone()),
+        }
+    }
+}
+
 impl Decode for Credential {
     type Error = ProtoError;
 
@@ -260,6 +496,35 @@ impl Encode for AddIdentity {
     }
 }
 
+impl TryFrom<&PrivateKey> for AddIdentity {
+    type Error = ProtoError;
+
+    /// Add `key` labelled with its own comment.
+    ///
+    /// Encrypted keys have to be [decrypted](PrivateKey::decrypt) first
+    /// and fail with [`Error::Encrypted`]. A key with a certificate is
+    /// added with [`Credential::certificate`] instead.
+    fn try_from(key: &PrivateKey) -> Result<Self> {
+        if key.is_encrypted() {
+            return Err(Error::Encrypted.into());
+        }
+        Ok(Self {
+            credential: Credential::Key {
+                privkey: key.key_data().clone(),
+                comment: key.comment().to_owned(),
+            },
+        })
+    }
+}
+
+impl TryFrom<PrivateKey> for AddIdentity {
+    type Error = ProtoError;
+
+    fn try_from(key: PrivateKey) -> Result<Self> {
+        Self::try_from(&key)
+    }
+}
+
 /// Add a key to an agent, with constraints on it's use.
 ///
 /// This structure is sent in a [`Request::AddIdConstrained`] (`SSH_AGENTC_ADD_ID_CONSTRAINED`) message.
@@ -313,14 +578,36 @@ impl Encode for AddIdentityConstrained {
     }
 }
 
+impl TryFrom<(&PrivateKey, KeyConstraints)> for AddIdentityConstrained {
+    type Error = ProtoError;
+
+    /// Add `key` with `constraints`, see [`AddIdentity::try_from`].
+    fn try_from((key, constraints): (&PrivateKey, KeyConstraints)) -> Result<Self> {
+        Ok(Self {
+            identity: key.try_into()?,
+            constraints: constraints.build(),
+        })
+    }
+}
+
+impl TryFrom<(PrivateKey, KeyConstraints)> for AddIdentityConstrained {
+    type Error = ProtoError;
+
+    fn try_from((key, constraints): (PrivateKey, KeyConstraints)) -> Result<Self> {
+        Self::try_from((&key, constraints))
+    }
+}
+
 /// Remove a key from an agent.
 ///
 /// This structure is sent in a [`Request::RemoveIdentity`] (`SSH_AGENTC_REMOVE_IDENTITY`) message.
 ///
 /// Described in [draft-miller-ssh-agent-14 § 3.4](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.4)
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RemoveIdentity {
     /// The public key portion of the [`Identity`] to be removed
+    #[cfg_attr(feature = "serde", serde(with = "super::serialize::key_data"))]
     pub pubkey: KeyData,
 }
 
@@ -350,6 +637,7 @@ impl Encode for RemoveIdentity {
 ///
 /// Described in [draft-miller-ssh-agent-14 § 3.2](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.2)
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SmartcardKey {
     /// An opaque identifier for the hardware token
     ///
@@ -358,7 +646,31 @@ pub struct SmartcardKey {
     pub id: String,
 
     /// An optional password to unlock the key
-    pub pin: String,
+    ///
+    /// The PIN is wiped from memory when the key is dropped. It is never
+    /// serialized, and keys deserialized with the `serde` feature have an
+    /// empty PIN.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub pin: Zeroizing<String>,
+
+    /// Provider-specific options, e.g. hints for loading a PKCS#11 module.
+    ///
+    /// `SSH_AGENTC_ADD_SMARTCARD_KEY` has no room for options, so they are
+    /// sent as a [`ProviderOptions`] constraint of
+    /// [`Request::AddSmartcardKeyConstrained`] and ignored by all other
+    /// messages. Keys without options are encoded as before.
+    pub options: Vec<(String, String)>,
+}
+
+impl SmartcardKey {
+    /// Create a key reference without provider options.
+    pub fn new(id: impl Into<String>, pin: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            pin: Zeroizing::new(pin.into()),
+            options: vec![],
+        }
+    }
 }
 
 impl Decode for SmartcardKey {
@@ -366,9 +678,13 @@ impl Decode for SmartcardKey {
 
     fn decode(reader: &mut impl Reader) -> Result<Self> {
         let id = String::decode(reader)?;
-        let pin = String::decode(reader)?;
+        let pin = Zeroizing::new(String::decode(reader)?);
 
-        Ok(Self { id, pin })
+        Ok(Self {
+            id,
+            pin,
+            options: vec![],
+        })
     }
 }
 
@@ -393,6 +709,7 @@ impl Encode for SmartcardKey {
 /// - [`Request::AddIdConstrained`]
 /// - [`Request::AddSmartcardKeyConstrained`]
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum KeyConstraint {
     /// Limit the key's lifetime by deleting it after the specified duration (in seconds)
     Lifetime(u32),
@@ -400,6 +717,13 @@ pub enum KeyConstraint {
     /// Require explicit user confirmation for each private key operation using the key.
     Confirm,
 
+    /// Limit the number of signatures made with the key.
+    ///
+    /// This is the OpenSSH `SSH_AGENT_CONSTRAIN_MAXSIGN` constraint,
+    /// set by `ssh-add -x` for XMSS keys, which may only make a limited
+    /// number of signatures.
+    MaxSign(u32),
+
     /// Experimental or private-use constraints
     ///
     /// Contains:
@@ -421,9 +745,17 @@ impl Decode for KeyConstraint {
         Ok(match constraint_type {
             1 => KeyConstraint::Lifetime(u32::decode(reader)?),
             2 => KeyConstraint::Confirm,
+            3 => KeyConstraint::MaxSign(u32::decode(reader)?),
             255 => {
                 let name = String::decode(reader)?;
-                let details: Vec<u8> = Vec::decode(reader)?;
+                let details = if name == AssociatedCerts::NAME {
+                    // OpenSSH writes the fields of this one without a string around them
+                    let mut details = vec![u8::decode(reader)?];
+                    Vec::<u8>::decode(reader)?.encode(&mut details)?;
+                    details
+                } else {
+                    Vec::decode(reader)?
+                };
                 KeyConstraint::Extension(Extension {
                     name,
                     details: Unparsed::from(details),
@@ -443,10 +775,17 @@ impl Encode for KeyConstraint {
                 .checked_add(lifetime.encoded_len()?)
                 .ok_or(EncodingError::Length),
             Self::Confirm => Ok(base),
+            Self::MaxSign(signatures) => base
+                .checked_add(signatures.encoded_len()?)
+                .ok_or(EncodingError::Length),
             Self::Extension(extension) => [
                 base,
                 extension.name.encoded_len()?,
-                extension.details.0.encoded_len()?,
+                if extension.name == AssociatedCerts::NAME {
+                    extension.details.0.len()
+                } else {
+                    extension.details.0.encoded_len()?
+                },
             ]
             .checked_sum(),
         }
@@ -459,15 +798,98 @@ impl Encode for KeyConstraint {
                 lifetime.encode(writer)
             }
             Self::Confirm => 2u8.encode(writer),
+            Self::MaxSign(signatures) => {
+                3u8.encode(writer)?;
+                signatures.encode(writer)
+            }
             Self::Extension(extension) => {
                 255u8.encode(writer)?;
                 extension.name.encode(writer)?;
-                extension.details.0.encode(writer)
+                if extension.name == AssociatedCerts::NAME {
+                    writer.write(&extension.details.0)
+                } else {
+                    extension.details.0.encode(writer)
+                }
             }
         }
     }
 }
 
+/// Builder for a list of [`KeyConstraint`]s.
+///
+/// The resulting constraints can be used in [`AddIdentityConstrained`]
+/// and [`AddSmartcardKeyConstrained`] messages.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use ssh_agent_lib::proto::{KeyConstraint, KeyConstraints};
+///
+/// let constraints = KeyConstraints::new()
+///     .lifetime(Duration::from_secs(30))?
+///     .confirm()
+///     .build();
+///
+/// assert_eq!(
+///     constraints,
+///     vec![KeyConstraint::Lifetime(30), KeyConstraint::Confirm]
+/// );
+/// # Ok::<(), ssh_agent_lib::proto::ProtoError>(())
+/// ```
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct KeyConstraints {
+    constraints: Vec<KeyConstraint>,
+}
+
+impl KeyConstraints {
+    /// Create an empty set of constraints.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the key's lifetime.
+    ///
+    /// The protocol carries the lifetime in seconds, sub-second
+    /// precision is discarded. Returns an error if the lifetime
+    /// does not fit in the 32-bit seconds field.
+    pub fn lifetime(mut self, lifetime: Duration) -> Result<Self> {
+        let seconds = lifetime.as_secs();
+        let seconds =
+            u32::try_from(seconds).map_err(|_| ProtoError::LifetimeOutOfRange { seconds })?;
+        self.constraints.push(KeyConstraint::Lifetime(seconds));
+        Ok(self)
+    }
+
+    /// Require explicit user confirmation for each use of the key.
+    pub fn confirm(mut self) -> Self {
+        self.constraints.push(KeyConstraint::Confirm);
+        self
+    }
+
+    /// Allow at most `signatures` signatures with the key.
+    pub fn maxsign(mut self, signatures: u32) -> Self {
+        self.constraints.push(KeyConstraint::MaxSign(signatures));
+        self
+    }
+
+    /// Add an extension constraint.
+    pub fn extension(mut self, extension: Extension) -> Self {
+        self.constraints.push(KeyConstraint::Extension(extension));
+        self
+    }
+
+    /// Return the list of constraints.
+    pub fn build(self) -> Vec<KeyConstraint> {
+        self.constraints
+    }
+}
+
+impl From<KeyConstraints> for Vec<KeyConstraint> {
+    fn from(constraints: KeyConstraints) -> Self {
+        constraints.build()
+    }
+}
+
 /// Add a key in a hardware token to an agent, with constraints on it's use.
 ///
 /// This structure is sent in a [`Request::AddSmartcardKeyConstrained`] (`SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED`) message.
@@ -484,15 +906,50 @@ pub struct AddSmartcardKeyConstrained {
     pub constraints: Vec<KeyConstraint>,
 }
 
+impl AddSmartcardKeyConstrained {
+    /// Create a request adding the smartcard `key` with the given constraints.
+    ///
+    /// The constraints are encoded exactly as for [`AddIdentityConstrained`],
+    /// so a [`KeyConstraints`] builder can be passed directly.
+    pub fn new(key: SmartcardKey, constraints: impl Into<Vec<KeyConstraint>>) -> Self {
+        Self {
+            key,
+            constraints: constraints.into(),
+        }
+    }
+
+    /// The constraint carrying the options of the key, if it has any.
+    fn options_constraint(&self) -> ssh_encoding::Result<Option<KeyConstraint>> {
+        if self.key.options.is_empty() {
+            return Ok(None);
+        }
+        let mut details = vec![];
+        ProviderOptions {
+            options: self.key.options.clone(),
+        }
+        .encode(&mut details)?;
+        Ok(Some(KeyConstraint::Extension(Extension {
+            name: ProviderOptions::NAME.into(),
+            details: Unparsed(details),
+        })))
+    }
+}
+
 impl Decode for AddSmartcardKeyConstrained {
     type Error = ProtoError;
 
     fn decode(reader: &mut impl Reader) -> Result<Self> {
-        let key = SmartcardKey::decode(reader)?;
+        let mut key = SmartcardKey::decode(reader)?;
         let mut constraints = vec![];
 
         while !reader.is_finished() {
-            constraints.push(KeyConstraint::decode(reader)?);
+            match KeyConstraint::decode(reader)? {
+                KeyConstraint::Extension(extension) if extension.name == ProviderOptions::NAME => {
+                    let options: ProviderOptions = extension.details.parse()?;
+                    key.options.extend(options.options);
+                }
+                constraint => constraints.push(constraint),
+            }
         }
         Ok(Self { key, constraints })
     }
@@ -502,6 +959,7 @@ impl Encode for AddSmartcardKeyConstrained {
     fn encoded_len(&self) -> ssh_encoding::Result<usize> {
         self.constraints
             .iter()
+            .chain(&self.options_constraint()?)
             .try_fold(self.key.encoded_len()?, |acc, e| {
                 let constraint_len = e.encoded_len()?;
                 usize::checked_add(acc, constraint_len).ok_or(EncodingError::Length)
@@ -510,7 +968,7 @@ impl Encode for AddSmartcardKeyConstrained {
 
     fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
         self.key.encode(writer)?;
-        for constraint in &self.constraints {
+        for constraint in self.constraints.iter().chain(&self.options_constraint()?) {
             constraint.encode(writer)?;
         }
         Ok(())
@@ -523,6 +981,7 @@ impl Encode for AddSmartcardKeyConstrained {
 ///
 /// Described in [draft-miller-ssh-agent-14 § 3.8](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-3.8).
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Extension {
     /// Indicates the type of the extension message (as a UTF-8 string)
     ///
@@ -568,6 +1027,21 @@ impl Extension {
         }
     }
 
+    /// The extension-specific content, as sent.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.details.0
+    }
+
+    /// The extension-specific content as text, with invalid UTF-8
+    /// sequences replaced by `U+FFFD`.
+    ///
+    /// This suits extensions answering with bare text. Content encoded
+    /// as an SSH `string` starts with its length, which is skipped by
+    /// parsing it with `details.parse::<String>()` instead.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.details.0)
+    }
+
     /// Create a new [`Extension`] from a [`KeyConstraintExtension`]
     /// structure implementing [`ssh_encoding::Encode`]
     pub fn new_key_constraint<T>(extension: T) -> Result<Self>
@@ -617,7 +1091,7 @@ impl Decode for Extension {
 
 impl Encode for Extension {
     fn encoded_len(&self) -> ssh_encoding::Result<usize> {
-        [self.name.encoded_len()?, self.details.0.encoded_len()?].checked_sum()
+        [self.name.encoded_len()?, self.details.0.len()].checked_sum()
     }
 
     fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
@@ -632,6 +1106,7 @@ impl Encode for Extension {
 
 /// Generic container for [`Extension`]-specific content
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Unparsed(pub Vec<u8>);
 
 impl Unparsed {
@@ -693,10 +1168,14 @@ pub enum Request {
     RemoveSmartcardKey(SmartcardKey),
 
     /// Temporarily lock an agent with a pass-phrase
-    Lock(String),
+    ///
+    /// The pass-phrase is wiped from memory when the request is dropped.
+    Lock(Zeroizing<String>),
 
     /// Unlock a locked agaent with a pass-phrase
-    Unlock(String),
+    ///
+    /// The pass-phrase is wiped from memory when the request is dropped.
+    Unlock(Zeroizing<String>),
 
     /// Add an identity (private key/certificate & comment) to an agent,
     /// with constraints on it's usage
@@ -725,6 +1204,15 @@ pub enum Response {
     /// Indicates generic agent success
     Success,
 
+    /// Indicates generic agent success, followed by contents
+    ///
+    /// The protocol defines no contents for `SSH_AGENT_SUCCESS`, but
+    /// Pageant answers the [`query`](super::extension::Query) extension
+    /// this way, listing its extensions after the message type. Only
+    /// [`Response::decode_with_data`] decodes these, a success message
+    /// without any contents is decoded as [`Response::Success`].
+    SuccessWithData(Unparsed),
+
     /// A list of identities, sent in response to
     /// a [`Request::RequestIdentities`] message.
     IdentitiesAnswer(Vec<Identity>),
@@ -742,6 +1230,16 @@ pub enum Response {
 }
 
 impl Request {
+    /// Lock an agent with `passphrase`, which may be a plain `String`.
+    pub fn lock(passphrase: impl Into<Zeroizing<String>>) -> Self {
+        Self::Lock(passphrase.into())
+    }
+
+    /// Unlock an agent with `passphrase`, which may be a plain `String`.
+    pub fn unlock(passphrase: impl Into<Zeroizing<String>>) -> Self {
+        Self::Unlock(passphrase.into())
+    }
+
     /// The protocol message identifier for a given [`Request`] message type.
     ///
     /// Described in [draft-miller-ssh-agent-14 § 6.1](https://www.ietf.org/archive/id/draft-miller-ssh-agent-14.html#section-6.1).
@@ -770,7 +1268,7 @@ impl Response {
     pub fn message_id(&self) -> u8 {
         match self {
             Self::Failure => 5,
-            Self::Success => 6,
+            Self::Success | Self::SuccessWithData(_) => 6,
             Self::IdentitiesAnswer(_) => 12,
             Self::SignResponse(_) => 14,
             Self::ExtensionFailure => 28,
@@ -793,8 +1291,8 @@ impl Decode for Request {
             19 => Ok(Self::RemoveAllIdentities),
             20 => SmartcardKey::decode(reader).map(Self::AddSmartcardKey),
             21 => SmartcardKey::decode(reader).map(Self::RemoveSmartcardKey),
-            22 => Ok(String::decode(reader).map(Self::Lock)?),
-            23 => Ok(String::decode(reader).map(Self::Unlock)?),
+            22 => Ok(Self::Lock(Zeroizing::new(String::decode(reader)?))),
+            23 => Ok(Self::Unlock(Zeroizing::new(String::decode(reader)?))),
             25 => AddIdentityConstrained::decode(reader).map(Self::AddIdConstrained),
             26 => AddSmartcardKeyConstrained::decode(reader).map(Self::AddSmartcardKeyConstrained),
             27 => Extension::decode(reader).map(Self::Extension),
@@ -806,16 +1304,47 @@ impl Decode for Request {
 impl Decode for Response {
     type Error = ProtoError;
 
+    /// Decode a response.
+    ///
+    /// Contents following `SSH_AGENT_SUCCESS` are left unread, so
+    /// [`decode_frame`](super::decode_frame) rejects them. Use
+    /// [`Response::decode_with_data`] where Pageant's answers are expected.
     fn decode(reader: &mut impl Reader) -> Result<Self> {
         let message_type = u8::decode(reader)?;
+        Self::decode_message(message_type, reader)
+    }
+}
 
+impl Response {
+    /// Decode a response, keeping contents following `SSH_AGENT_SUCCESS`
+    /// as [`Response::SuccessWithData`].
+    ///
+    /// Pageant answers the [`query`](super::extension::Query) extension
+    /// this way. Other responses are decoded as by [`Response::decode`].
+    pub fn decode_with_data(reader: &mut impl Reader) -> Result<Self> {
+        let message_type = u8::decode(reader)?;
+        if message_type == 6 && !reader.is_finished() {
+            let mut data = vec![0; reader.remaining_len()];
+            reader.read(&mut data)?;
+            return Ok(Self::SuccessWithData(data.into()));
+        }
+        Self::decode_message(message_type, reader)
+    }
+
+    /// Decode the contents of a response of `message_type`.
+    fn decode_message(message_type: u8, reader: &mut impl Reader) -> Result<Self> {
         match message_type {
             5 => Ok(Self::Failure),
             6 => Ok(Self::Success),
             12 => Identity::decode_vec(reader).map(Self::IdentitiesAnswer),
             14 => {
-                Ok(reader
-                    .read_prefixed(|reader| Signature::decode(reader).map(Self::SignResponse))?)
+                let blob = Vec::decode(reader)?;
+                if Algorithm::decode(&mut &blob[..])? == (Algorithm::Rsa { hash: None }) {
+                    return Err(ProtoError::LegacySha1Signature);
+                }
+                let mut blob = &blob[..];
+                let signature = Signature::decode(&mut blob)?;
+                Ok(blob.finish(Self::SignResponse(signature))?)
             }
             28 => Ok(Self::ExtensionFailure),
             29 => Extension::decode(reader).map(Self::ExtensionResponse),
@@ -868,22 +1397,46 @@ impl Encode for Request {
     }
 }
 
+/// Length of the `flags` and `counter` fields of security key signatures.
+const SK_SIGNATURE_TRAILER_LEN: usize = 5;
+
+/// Encode a signature with its length prefix.
+///
+/// Security key signatures are followed by `flags` and `counter` fields
+/// outside of the signature blob, which `ssh-key` only writes that way for
+/// Ed25519 keys, so ECDSA ones are split here.
+fn encode_signature_prefixed(
+    signature: &Signature,
+    writer: &mut impl Writer,
+) -> ssh_encoding::Result<()> {
+    if signature.algorithm() != Algorithm::SkEcdsaSha2NistP256 {
+        return signature.encode_prefixed(writer);
+    }
+
+    let bytes = signature.as_bytes();
+    let blob_len = bytes
+        .len()
+        .checked_sub(SK_SIGNATURE_TRAILER_LEN)
+        .ok_or(EncodingError::Length)?;
+    signature.encoded_len()?.encode(writer)?;
+    signature.algorithm().encode(writer)?;
+    bytes[..blob_len].encode(writer)?;
+    writer.write(&bytes[blob_len..])
+}
+
 impl Encode for Response {
     fn encoded_len(&self) -> ssh_encoding::Result<usize> {
         let message_id_len = 1;
         let payload_len = match self {
             Self::Failure => 0,
             Self::Success => 0,
+            Self::SuccessWithData(data) => data.0.len(),
             Self::IdentitiesAnswer(ids) => {
-                let mut lengths = Vec::with_capacity(1 + ids.len());
-                // Prefixed length
-                lengths.push(4);
-
-                for id in ids {
-                    lengths.push(id.encoded_len()?);
-                }
-
-                lengths.checked_sum()?
+                // prefixed count, summed without collecting the lengths
+                ids.iter().try_fold(4usize, |len, id| {
+                    len.checked_add(id.encoded_len()?)
+                        .ok_or(EncodingError::Length)
+                })?
             }
             Self::SignResponse(response) => response.encoded_len_prefixed()?,
             Self::ExtensionFailure => 0,
@@ -900,13 +1453,14 @@ impl Encode for Response {
         match self {
             Self::Failure => {}
             Self::Success => {}
+            Self::SuccessWithData(data) => writer.write(&data.0)?,
             Self::IdentitiesAnswer(ids) => {
                 (ids.len() as u32).encode(writer)?;
                 for id in ids {
                     id.encode(writer)?;
                 }
             }
-            Self::SignResponse(response) => response.encode_prefixed(writer)?,
+            Self::SignResponse(response) => encode_signature_prefixed(response, writer)?,
             Self::ExtensionFailure => {}
             Self::ExtensionResponse(extension) => extension.encode(writer)?,
         };
@@ -927,10 +1481,12 @@ mod tests {
     };
     use ssh_key::{
         private::{EcdsaKeypair, EcdsaPrivateKey, KeypairData, RsaPrivateKey},
+        public::RsaPublicKey,
         Mpint,
     };
 
     use super::*;
+    use crate::proto::extension::RestrictDestination;
 
     fn demo_key() -> EcdsaKeypair {
         EcdsaKeypair::NistP256 {
@@ -1107,6 +1663,33 @@ mod tests {
         let mut buf = vec![];
         expected.encode(&mut buf).expect("serialize message");
         assert_eq!(buf, msg);
+
+        // the agent side reads the typed destination constraint back out
+        let KeyConstraint::Extension(extension) = &out.constraints[0] else {
+            panic!("expected an extension constraint");
+        };
+        let destination = extension
+            .parse_key_constraint::<RestrictDestination>()
+            .expect("parse destination constraint")
+            .expect("restrict-destination constraint");
+        assert_eq!(destination.constraints.len(), 1);
+        let hop = &destination.constraints[0];
+        assert_eq!(hop.from.hostname, "");
+        assert!(hop.from.keys.is_empty());
+        assert_eq!(hop.to.hostname, "github.com");
+        assert_eq!(hop.to.keys.len(), 3);
+        assert!(hop.to.keys.iter().all(|key| !key.is_ca));
+
+        // and the client side can carry it in an `AddIdentityConstrained`
+        let constrained = AddIdentityConstrained {
+            identity: out.identity.clone(),
+            constraints: vec![KeyConstraint::Extension(
+                Extension::new_key_constraint(destination).expect("encode constraint"),
+            )],
+        };
+        let mut buf = vec![];
+        constrained.encode(&mut buf).expect("serialize message");
+        assert_eq!(buf, msg);
     }
 
     #[test]
@@ -1143,6 +1726,328 @@ mod tests {
         assert_eq!(buf, msg);
     }
 
+    #[test]
+    fn test_success_with_data() {
+        let mut msg: &[u8] = &hex!("06 00000005 7175657279");
+        let out = Response::decode_with_data(&mut msg).expect("parse message");
+        assert_eq!(
+            out,
+            Response::SuccessWithData(hex!("00000005 7175657279").to_vec().into())
+        );
+
+        let mut buf = vec![];
+        out.encode(&mut buf).expect("serialize message");
+        assert_eq!(buf, hex!("06 00000005 7175657279"));
+
+        let out = Response::decode_with_data(&mut &[6][..]).expect("parse message");
+        assert_eq!(out, Response::Success);
+
+        // without data the contents are left for the frame to reject
+        let mut msg: &[u8] = &hex!("06 00000005 7175657279");
+        let out = Response::decode(&mut msg).expect("parse message");
+        assert_eq!(out, Response::Success);
+        assert_eq!(msg.len(), 9);
+    }
+
+    #[test]
+    fn test_key_constraints_builder() {
+        let constraints = KeyConstraints::new()
+            .lifetime(Duration::from_millis(2500))
+            .expect("valid lifetime")
+            .confirm()
+            .maxsign(1000)
+            .build();
+        assert_eq!(
+            constraints,
+            vec![
+                KeyConstraint::Lifetime(2),
+                KeyConstraint::Confirm,
+                KeyConstraint::MaxSign(1000)
+            ]
+        );
+
+        // as written by `encode_constraints` in OpenSSH's authfd.c
+        let mut buf = vec![];
+        for constraint in &constraints {
+            constraint.encode(&mut buf).expect("serialize constraint");
+        }
+        assert_eq!(buf, hex!("01 00000002 02 03 000003e8"));
+
+        let mut reader = &buf[..];
+        let mut decoded = vec![];
+        while !reader.is_empty() {
+            decoded.push(KeyConstraint::decode(&mut reader).expect("parse constraint"));
+        }
+        assert_eq!(decoded, constraints);
+
+        let too_long = Duration::from_secs(u64::from(u32::MAX) + 1);
+        assert!(matches!(
+            KeyConstraints::new().lifetime(too_long),
+            Err(ProtoError::LifetimeOutOfRange { seconds }) if seconds == u64::from(u32::MAX) + 1
+        ));
+    }
+
+    #[test]
+    fn test_smartcard_key_options() {
+        let key = SmartcardKey {
+            id: "token".into(),
+            pin: "".to_owned().into(),
+            options: vec![("module_path".into(), "/usr/lib/p11.so".into())],
+        };
+
+        // options are dropped where the message has no room for them
+        let mut buf = vec![];
+        Request::AddSmartcardKey(key.clone())
+            .encode(&mut buf)
+            .expect("serialize message");
+        assert_eq!(buf, hex!("14 00000005 746f6b656e 00000000"));
+
+        let request =
+            Request::AddSmartcardKeyConstrained(AddSmartcardKeyConstrained::new(key, vec![]));
+        let mut expected = hex!("1a 00000005 746f6b656e 00000000 ff").to_vec();
+        expected.extend_from_slice(&hex!("0000001d"));
+        expected.extend_from_slice(b"provider-options@metacode.biz");
+        expected.extend_from_slice(&hex!("00000022 0000000b"));
+        expected.extend_from_slice(b"module_path");
+        expected.extend_from_slice(&hex!("0000000f"));
+        expected.extend_from_slice(b"/usr/lib/p11.so");
+
+        let mut buf = vec![];
+        request.encode(&mut buf).expect("serialize message");
+        assert_eq!(buf, expected);
+        assert_eq!(request.encoded_len().expect("length"), expected.len());
+
+        let decoded = Request::decode(&mut &buf[..]).expect("parse message");
+        assert_eq!(decoded, request);
+    }
+
+    #[test]
+    fn test_add_smartcard_key_constrained() {
+        let request = Request::AddSmartcardKeyConstrained(AddSmartcardKeyConstrained::new(
+            SmartcardKey::new("/usr/lib/opensc-pkcs11.so", "123456"),
+            KeyConstraints::new()
+                .lifetime(Duration::from_secs(30))
+                .expect("valid lifetime"),
+        ));
+
+        // as sent by `ssh-add -s /usr/lib/opensc-pkcs11.so -t 30`
+        let mut expected = vec![26];
+        expected.extend_from_slice(&hex!("00000019"));
+        expected.extend_from_slice(b"/usr/lib/opensc-pkcs11.so");
+        expected.extend_from_slice(&hex!("00000006"));
+        expected.extend_from_slice(b"123456");
+        expected.extend_from_slice(&hex!("01 0000001e"));
+
+        let mut buf = vec![];
+        request.encode(&mut buf).expect("serialize message");
+        assert_eq!(buf, expected);
+        assert_eq!(request.encoded_len().expect("length"), expected.len());
+
+        let decoded = Request::decode(&mut &buf[..]).expect("parse message");
+        assert_eq!(decoded, request);
+    }
+
+    #[test]
+    fn test_sign_request_default_flags() {
+        let ecdsa = SignRequest::new(KeyData::Ecdsa(demo_key().into()), b"data".to_vec());
+        assert_eq!(ecdsa.flags, 0);
+
+        let rsa = SignRequest::new(
+            KeyData::Rsa(RsaPublicKey {
+                e: Mpint::from_bytes(&hex!("010001")).unwrap(),
+                n: Mpint::from_bytes(&hex!("00c5a3b2f1")).unwrap(),
+            }),
+            b"data".to_vec(),
+        );
+        assert_eq!(rsa.flags, RSA_SHA2_512);
+    }
+
+    #[test]
+    fn test_add_identity_from_private_key() {
+        let key = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519).unwrap();
+        let key = PrivateKey::new(key.key_data().clone(), "me@work").unwrap();
+
+        let identity = AddIdentity::try_from(&key).unwrap();
+        assert_eq!(
+            identity.credential,
+            Credential::Key {
+                privkey: key.key_data().clone(),
+                comment: "me@work".into(),
+            }
+        );
+
+        let constrained =
+            AddIdentityConstrained::try_from((&key, KeyConstraints::new().confirm())).unwrap();
+        assert_eq!(constrained.identity, identity);
+        assert_eq!(constrained.constraints, [KeyConstraint::Confirm]);
+
+        // encryption is only enabled by the agent feature
+        #[cfg(feature = "agent")]
+        {
+            let key = key.encrypt(&mut rand::thread_rng(), "passphrase").unwrap();
+            assert!(matches!(
+                AddIdentity::try_from(key),
+                Err(ProtoError::SshKey(Error::Encrypted))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_add_sk_identity() -> testresult::TestResult {
+        use ssh_key::{
+            certificate::Builder,
+            private::{Ed25519Keypair, SkEd25519},
+            public::{self, Ed25519PublicKey},
+        };
+
+        let public = public::SkEd25519::new(Ed25519PublicKey([7; 32]), "ssh:git-signing");
+        // user presence and verification required
+        let keypair = KeypairData::SkEd25519(SkEd25519::new(public, 0x05, vec![9; 64])?);
+        let read_back = |constraints: Vec<KeyConstraint>, credential: Credential| {
+            let request = Request::AddIdConstrained(AddIdentityConstrained {
+                identity: AddIdentity { credential },
+                constraints,
+            });
+            let mut encoded = vec![];
+            request.encode(&mut encoded)?;
+            let decoded = Request::decode(&mut &encoded[..])?;
+            assert_eq!(decoded, request);
+            let Request::AddIdConstrained(decoded) = decoded else {
+                unreachable!("decoded as {decoded:?}");
+            };
+            Ok::<_, ProtoError>(decoded.identity.credential)
+        };
+
+        let credential = Credential::Key {
+            privkey: keypair.clone(),
+            comment: "security key".into(),
+        };
+        let Credential::Key { privkey, .. } = read_back(vec![KeyConstraint::Confirm], credential)?
+        else {
+            panic!("expected a key");
+        };
+        let sk = privkey.sk_ed25519().expect("an sk-ssh-ed25519 key");
+        assert_eq!(sk.public().application(), "ssh:git-signing");
+        assert_eq!(sk.flags(), 0x05);
+        assert_eq!(sk.key_handle(), [9; 64]);
+
+        // also with a certificate
+        let ca = PrivateKey::new(
+            KeypairData::Ed25519(Ed25519Keypair::from_seed(&[1; 32])),
+            "ca",
+        )?;
+        let mut builder = Builder::new([0; 16], KeyData::try_from(&keypair)?, 0, u64::MAX >> 1)?;
+        builder.all_principals_valid()?;
+        let credential = Credential::certificate(&keypair, builder.sign(&ca)?, "certified")?;
+        let Credential::Cert { privkey, .. } = read_back(vec![], credential)? else {
+            panic!("expected a certificate");
+        };
+        let PrivateKeyData::SkEd25519(sk) = privkey else {
+            panic!("expected an sk-ssh-ed25519 key");
+        };
+        assert_eq!(sk.public().application(), "ssh:git-signing");
+        assert_eq!(sk.flags(), 0x05);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_request_builder() {
+        let rsa = PublicKey::from(KeyData::Rsa(RsaPublicKey {
+            e: Mpint::from_bytes(&hex!("010001")).unwrap(),
+            n: Mpint::from_bytes(&hex!("00c5a3b2f1")).unwrap(),
+        }));
+        let request = SignRequest::builder()
+            .key(&rsa)
+            .data(b"data".to_vec())
+            .sha512()
+            .sha256()
+            .build()
+            .unwrap();
+        assert_eq!(request.pubkey, *rsa.key_data());
+        assert_eq!(&request.data[..], b"data");
+        assert_eq!(request.flags, RSA_SHA2_256);
+
+        let default = SignRequest::builder().key(&rsa).build().unwrap();
+        assert_eq!(default.flags, RSA_SHA2_512);
+
+        let ecdsa = PublicKey::from(KeyData::Ecdsa(demo_key().into()));
+        assert!(matches!(
+            SignRequest::builder().key(&ecdsa).sha256().build(),
+            Err(ProtoError::UnsupportedSignatureFlags {
+                flags: RSA_SHA2_256,
+                ..
+            })
+        ));
+        assert!(matches!(
+            SignRequest::builder().sha512().build(),
+            Err(ProtoError::MissingKey)
+        ));
+    }
+
+    #[test]
+    fn test_sk_sign_response() {
+        for (msg, algorithm, trailer) in [
+            (
+                &include_bytes!("../../tests/messages/resp-sign-response-sk-ed25519.bin")[..],
+                Algorithm::SkEd25519,
+                hex!("01 0000002a"),
+            ),
+            (
+                &include_bytes!("../../tests/messages/resp-sign-response-sk-ecdsa.bin")[..],
+                Algorithm::SkEcdsaSha2NistP256,
+                hex!("05 00001337"),
+            ),
+        ] {
+            let Response::SignResponse(signature) = Response::decode(&mut &msg[..]).unwrap() else {
+                panic!("expected a sign response");
+            };
+            assert_eq!(signature.algorithm(), algorithm);
+            // flags and counter are kept at the end of the signature data
+            assert!(signature.as_bytes().ends_with(&trailer));
+
+            let response = Response::SignResponse(signature);
+            let mut encoded = vec![];
+            response.encode(&mut encoded).unwrap();
+            assert_eq!(encoded, msg);
+            assert_eq!(response.encoded_len().unwrap(), msg.len());
+        }
+    }
+
+    #[test]
+    fn test_legacy_sha1_sign_response() {
+        let msg = hex!(
+            "
+            0e00000013000000077373682d727361
+            00000004deadbeef
+            "
+        );
+        assert!(matches!(
+            Response::decode(&mut &msg[..]),
+            Err(ProtoError::LegacySha1Signature)
+        ));
+    }
+
+    #[test]
+    fn test_identity_to_openssh() -> testresult::TestResult {
+        let identity = Identity {
+            pubkey: KeyData::Ecdsa(demo_key().into()),
+            comment: b"  two  spaces ".to_vec(),
+        };
+        let line = identity.to_openssh()?;
+        assert!(line.starts_with("ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYA"));
+        assert!(line.ends_with("=   two  spaces "));
+        assert_eq!(PublicKey::from_openssh(&line)?.key_data(), &identity.pubkey);
+
+        // without a comment there is no trailing separator
+        let identity = Identity {
+            comment: vec![],
+            ..identity
+        };
+        assert!(identity.to_openssh()?.ends_with('='));
+        Ok(())
+    }
+
     #[test]
     fn test_parse_identities() {
         let msg: &[u8] = &hex!(
@@ -1163,7 +2068,7 @@ mod tests {
 
         let expected = Response::IdentitiesAnswer(vec![Identity {
             pubkey: KeyData::Ecdsa(demo_key().into()),
-            comment: "baloo@angela".to_string(),
+            comment: "baloo@angela".into(),
         }]);
         assert_eq!(out, expected);
 
@@ -1172,6 +2077,84 @@ mod tests {
         assert_eq!(buf, msg);
     }
 
+    #[test]
+    fn test_parse_identity_with_invalid_utf8_comment() {
+        let msg: &[u8] = &hex!(
+            "0c 00000001"
+            "00000033 0000000b 7373682d65643235353139"
+            "00000020 0101010101010101010101010101010101010101010101010101010101010101"
+            "00000005 6b6579ff21"
+        );
+
+        let Response::IdentitiesAnswer(identities) = Response::decode(&mut &msg[..]).unwrap()
+        else {
+            panic!("expected identities");
+        };
+        assert_eq!(identities[0].comment, b"key\xff!");
+        assert_eq!(identities[0].comment_lossy(), "key\u{fffd}!");
+
+        let mut buf = vec![];
+        Response::IdentitiesAnswer(identities)
+            .encode(&mut buf)
+            .unwrap();
+        assert_eq!(buf, msg);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() -> testresult::TestResult {
+        let pubkey = KeyData::Ecdsa(demo_key().into());
+        let line = PublicKey::from(pubkey.clone()).to_openssh()?;
+
+        let identity = Identity {
+            pubkey: pubkey.clone(),
+            comment: b"baloo@angela".to_vec(),
+        };
+        let json = serde_json::to_value(&identity)?;
+        assert_eq!(
+            json,
+            serde_json::json!({ "pubkey": line, "comment": "baloo@angela" })
+        );
+        assert_eq!(serde_json::from_value::<Identity>(json)?, identity);
+
+        // comments which are not UTF-8 are written as bytes
+        let identity = Identity {
+            comment: b"key\xff!".to_vec(),
+            ..identity
+        };
+        let json = serde_json::to_value(&identity)?;
+        assert_eq!(json["comment"], serde_json::json!([107, 101, 121, 255, 33]));
+        assert_eq!(serde_json::from_value::<Identity>(json)?, identity);
+
+        let request = SignRequest::new(pubkey, Bytes::from_static(b"data"));
+        let json = serde_json::to_string(&request)?;
+        assert_eq!(serde_json::from_str::<SignRequest>(&json)?, request);
+
+        let constraint = KeyConstraint::Extension(Extension {
+            name: "ext@example.com".into(),
+            details: Unparsed(vec![1, 2]),
+        });
+        let json = serde_json::to_value(&constraint)?;
+        assert_eq!(
+            json,
+            serde_json::json!({ "Extension": { "name": "ext@example.com", "details": [1, 2] } })
+        );
+        assert_eq!(serde_json::from_value::<KeyConstraint>(json)?, constraint);
+
+        // the PIN is never written
+        let key = SmartcardKey::new("/usr/lib/opensc-pkcs11.so", "123456");
+        let json = serde_json::to_value(&key)?;
+        assert_eq!(
+            json,
+            serde_json::json!({ "id": "/usr/lib/opensc-pkcs11.so", "options": [] })
+        );
+        assert_eq!(
+            serde_json::from_value::<SmartcardKey>(json)?,
+            SmartcardKey::new("/usr/lib/opensc-pkcs11.so", "")
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_parse_certificates() {
         let msg: &[u8] = &hex!(
@@ -4,7 +4,7 @@ use core::fmt;
 
 use ssh_encoding::{Decode, Encode, Reader, Writer};
 use ssh_key::{
-    private::{self, DsaPrivateKey, Ed25519Keypair, RsaPrivateKey},
+    private::{self, DsaPrivateKey, EcdsaKeypair, Ed25519Keypair, KeypairData, RsaPrivateKey},
     Algorithm, EcdsaCurve, Error, Result,
 };
 use subtle::{Choice, ConstantTimeEq};
@@ -99,6 +99,12 @@ pub enum PrivateKeyData {
 
     /// RSA private key.
     Rsa(RsaPrivateKey),
+
+    /// FIDO/U2F Ed25519 key, sent with the public key as OpenSSH does.
+    ///
+    /// Its application and flags, e.g. whether user verification is
+    /// required, are kept as they are sent.
+    SkEd25519(private::SkEd25519),
 }
 
 impl PrivateKeyData {
@@ -111,12 +117,40 @@ impl PrivateKeyData {
             }
             Algorithm::Ed25519 => Ed25519Keypair::decode(reader).map(Self::Ed25519),
             Algorithm::Rsa { .. } => RsaPrivateKey::decode(reader).map(Self::Rsa),
+            Algorithm::SkEd25519 => private::SkEd25519::decode(reader).map(Self::SkEd25519),
             #[allow(unreachable_patterns)]
             _ => Err(Error::AlgorithmUnknown),
         }
     }
 }
 
+impl TryFrom<&KeypairData> for PrivateKeyData {
+    type Error = Error;
+
+    /// Take the private part of a key pair, as sent along a certificate.
+    ///
+    /// Only keys of algorithms which can be certified are supported.
+    fn try_from(keypair: &KeypairData) -> Result<Self> {
+        Ok(match keypair {
+            KeypairData::Dsa(keypair) => Self::Dsa(keypair.private.clone()),
+            KeypairData::Ecdsa(EcdsaKeypair::NistP256 { private, .. }) => {
+                Self::Ecdsa(EcdsaPrivateKey::NistP256(private.clone()))
+            }
+            KeypairData::Ecdsa(EcdsaKeypair::NistP384 { private, .. }) => {
+                Self::Ecdsa(EcdsaPrivateKey::NistP384(private.clone()))
+            }
+            KeypairData::Ecdsa(EcdsaKeypair::NistP521 { private, .. }) => {
+                Self::Ecdsa(EcdsaPrivateKey::NistP521(private.clone()))
+            }
+            KeypairData::Ed25519(keypair) => Self::Ed25519(keypair.clone()),
+            KeypairData::Rsa(keypair) => Self::Rsa(keypair.private.clone()),
+            KeypairData::SkEd25519(keypair) => Self::SkEd25519(keypair.clone()),
+            KeypairData::Encrypted(_) => return Err(Error::Encrypted),
+            _ => return Err(Error::AlgorithmUnknown),
+        })
+    }
+}
+
 impl fmt::Debug for PrivateKeyData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -124,6 +158,7 @@ impl fmt::Debug for PrivateKeyData {
             Self::Ecdsa(_) => write!(f, "PrivateKeyData::Ecdsa"),
             Self::Ed25519(_) => write!(f, "PrivateKeyData::Ed25519"),
             Self::Rsa(_) => write!(f, "PrivateKeyData::Rsa"),
+            Self::SkEd25519(_) => write!(f, "PrivateKeyData::SkEd25519"),
         }
     }
 }
@@ -135,6 +170,7 @@ impl Encode for PrivateKeyData {
             Self::Ecdsa(key) => key.encoded_len(),
             Self::Ed25519(key) => key.encoded_len(),
             Self::Rsa(key) => key.encoded_len(),
+            Self::SkEd25519(key) => key.encoded_len(),
         }
     }
 
@@ -144,6 +180,7 @@ impl Encode for PrivateKeyData {
             Self::Ecdsa(key) => key.encode(writer)?,
             Self::Ed25519(key) => key.encode(writer)?,
             Self::Rsa(key) => key.encode(writer)?,
+            Self::SkEd25519(key) => key.encode(writer)?,
         }
 
         Ok(())
@@ -158,6 +195,12 @@ impl ConstantTimeEq for PrivateKeyData {
             (Self::Ecdsa(a), Self::Ecdsa(b)) => a.ct_eq(b),
             (Self::Ed25519(a), Self::Ed25519(b)) => a.ct_eq(b),
             (Self::Rsa(a), Self::Rsa(b)) => a.ct_eq(b),
+            // the key handle, passed to the authenticator, is the only
+            // secret part
+            (Self::SkEd25519(a), Self::SkEd25519(b)) => {
+                let public = a.public() == b.public() && a.flags() == b.flags();
+                a.key_handle().ct_eq(b.key_handle()) & Choice::from(u8::from(public))
+            }
             #[allow(unreachable_patterns)]
             _ => Choice::from(0),
         }
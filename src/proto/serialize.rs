@@ -0,0 +1,109 @@
+//! Serde support for fields without their own implementation.
+//!
+//! Used with `#[serde(with = "...")]` by the protocol types deriving
+//! `Serialize` and `Deserialize` when the `serde` feature is enabled.
+
+use core::fmt;
+
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use ssh_encoding::{Decode, Encode};
+use ssh_key::{public::KeyData, PublicKey};
+
+/// Public keys, as an OpenSSH `ssh-ed25519 AAAA...` line in
+/// human-readable formats and as the wire encoding otherwise.
+pub(crate) mod key_data {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        key: &KeyData,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            PublicKey::from(key.clone())
+                .to_openssh()
+                .map_err(ser::Error::custom)?
+                .serialize(serializer)
+        } else {
+            let mut bytes = vec![];
+            key.encode(&mut bytes).map_err(ser::Error::custom)?;
+            serializer.serialize_bytes(&bytes)
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<KeyData, D::Error> {
+        if deserializer.is_human_readable() {
+            let line = String::deserialize(deserializer)?;
+            let key = PublicKey::from_openssh(&line).map_err(de::Error::custom)?;
+            Ok(key.key_data().clone())
+        } else {
+            let bytes = bytes(deserializer)?;
+            KeyData::decode(&mut &bytes[..]).map_err(de::Error::custom)
+        }
+    }
+}
+
+/// Comments, as a string in human-readable formats if they are valid
+/// UTF-8 and as bytes otherwise.
+pub(crate) mod comment {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        comment: &[u8],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match core::str::from_utf8(comment) {
+            Ok(comment) if serializer.is_human_readable() => serializer.serialize_str(comment),
+            _ => serializer.serialize_bytes(comment),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        bytes(deserializer)
+    }
+}
+
+/// Deserialize bytes written by [`comment::serialize`], or by formats
+/// without a bytes type.
+fn bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(BytesVisitor)
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// Accepts strings, bytes and sequences of bytes, as formats without
+/// a bytes type write them as sequences.
+struct BytesVisitor;
+
+impl<'de> de::Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(value.as_bytes().to_vec())
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+        Ok(value.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(value)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,88 @@
+//! Helpers shared by the unit tests.
+
+use std::io;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(feature = "agent")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "agent")]
+use std::time::Duration;
+
+#[cfg(feature = "agent")]
+use crate::{agent::Session, error::AgentError, proto::AddIdentityConstrained};
+
+/// Writer keeping what was written for the test to look at.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// Everything written so far.
+    pub(crate) fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Create a socket at `path` which nothing listens on, like one left
+/// behind by an agent which exited.
+#[cfg(unix)]
+pub(crate) fn stale_socket(path: &Path) -> io::Result<()> {
+    drop(std::os::unix::net::UnixListener::bind(path)?);
+    Ok(())
+}
+
+/// Session counting pings and recording the keys added to it, failing
+/// both once `fail` is set.
+#[cfg(feature = "agent")]
+#[derive(Clone, Default)]
+pub(crate) struct CountingSession {
+    pub(crate) pings: Arc<AtomicUsize>,
+    pub(crate) adds: Arc<Mutex<Vec<AddIdentityConstrained>>>,
+    pub(crate) fail: bool,
+}
+
+#[cfg(feature = "agent")]
+impl CountingSession {
+    fn result(&self) -> Result<(), AgentError> {
+        if self.fail {
+            Err(AgentError::Failure)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "agent")]
+#[async_trait::async_trait]
+impl Session for CountingSession {
+    async fn ping(&mut self) -> Result<(), AgentError> {
+        self.pings.fetch_add(1, Ordering::SeqCst);
+        self.result()
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.adds.lock().unwrap().push(identity);
+        self.result()
+    }
+}
+
+/// Let spawned tasks run for `duration` of paused time.
+#[cfg(feature = "agent")]
+pub(crate) async fn advance(duration: Duration) {
+    tokio::time::advance(duration).await;
+    tokio::task::yield_now().await;
+}